@@ -195,6 +195,11 @@ export async function shareGame(
   return parseApiResponse<ShareGameResponse>(response);
 }
 
+export interface UpdateTemplateResponse {
+  template: MovieTemplate;
+  warnings?: { code: string; message: string }[];
+}
+
 /**
  * 更新指定 requestId 对应的剧情模板（写回数据库）。
  * @param id 生成记录 ID（requestId）
@@ -213,9 +218,13 @@ export async function updateGameTemplate(
     body: JSON.stringify({ id, template, source }),
   });
 
-  const data = await parseApiResponse<MovieTemplate>(response);
-  if (data && !data.requestId) data.requestId = id;
-  return data;
+  const data = await parseApiResponse<UpdateTemplateResponse>(response);
+  for (const warning of data.warnings ?? []) {
+    console.warn(`[${warning.code}] ${warning.message}`);
+  }
+  const saved = data.template;
+  if (saved && !saved.requestId) saved.requestId = id;
+  return saved;
 }
 
 export interface DeleteTemplateResponse {
@@ -258,6 +267,8 @@ export interface RecordsListItem {
   genre: string;
   language: string;
   playCount: number;
+  /** 最近一次写入模板的来源（如 manual-edit、/generate、/import） */
+  source: string;
 }
 
 /**
@@ -25,6 +25,9 @@ export interface MovieTemplate {
   /** 元信息（梗概、摘要、时长、类型、语言） */
   meta: MetaInfo;
 
+  /** 生成时选择的剧情类型标签（数组形式，meta.genre 为其拼接字符串） */
+  genreTags?: string[];
+
   backgroundImageBase64?: string;
 
   /** 以节点 ID 为 key 的故事节点集合（严格来自 JSON 的 nodes 对象） */
@@ -84,6 +87,12 @@ export interface Character {
 
   /** 角色头像/形象资源路径 */
   avatarPath?: string;
+
+  /** TTS 音色 id/标签（供配音流程使用） */
+  voice?: string;
+
+  /** 音色文字描述 */
+  voiceDescription?: string;
 }
 
 /**
@@ -104,6 +113,12 @@ export interface StoryNode {
 
   /** 玩家在该节点可做出的所有选择 */
   choices: Choice[];
+
+  /** 节点专属场景背景（缺省时使用全局 backgroundImageBase64） */
+  backgroundImageBase64?: string;
+
+  /** 节点背景对应的场景描述 */
+  backgroundAlt?: string;
 }
 
 /**
@@ -153,4 +168,7 @@ export interface Provenance {
 
   /** 创建时间 ISO 字符串 */
   createdAt: string;
+
+  /** 以 allowCycles 生成：后续编辑、拆分、续写时保留循环 */
+  allowCycles?: boolean;
 }
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS feedback (
+    id UUID PRIMARY KEY,
+    request_id UUID NOT NULL REFERENCES glm_requests(id),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    rating SMALLINT NOT NULL CHECK (rating BETWEEN 1 AND 5),
+    comment TEXT,
+    client_ip TEXT NOT NULL,
+    user_agent TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_feedback_request_id ON feedback(request_id);
@@ -0,0 +1,2 @@
+ALTER TABLE glm_requests
+    ADD COLUMN IF NOT EXISTS prompt_tokens_estimated INTEGER;
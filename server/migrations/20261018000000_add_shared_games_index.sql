@@ -0,0 +1,3 @@
+CREATE INDEX IF NOT EXISTS idx_glm_requests_playable_shared
+    ON glm_requests(id)
+    WHERE shared AND status = 'success' AND processed_response IS NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE glm_requests
+    ADD COLUMN IF NOT EXISTS glm_ms BIGINT,
+    ADD COLUMN IF NOT EXISTS images_ms BIGINT,
+    ADD COLUMN IF NOT EXISTS process_ms BIGINT;
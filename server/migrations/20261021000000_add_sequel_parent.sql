@@ -0,0 +1,3 @@
+ALTER TABLE glm_requests
+    ADD COLUMN IF NOT EXISTS parent_id UUID,
+    ADD COLUMN IF NOT EXISTS parent_ending TEXT;
@@ -0,0 +1,2 @@
+ALTER TABLE glm_requests
+    ADD COLUMN IF NOT EXISTS finished_at TIMESTAMPTZ;
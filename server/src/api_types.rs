@@ -1,12 +1,207 @@
 use crate::types::MovieTemplate;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct GenerateResponse {
     pub(crate) id: Uuid,
     pub(crate) template: MovieTemplate,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warnings: Vec<GenerationWarning>,
+}
+
+/// A finished `/generate` handed to identical requests that were in flight,
+/// with the client owning its `glm_requests` row.
+#[derive(Clone)]
+pub(crate) struct SharedGeneration {
+    pub(crate) owner_ip: String,
+    pub(crate) generated: GenerateResponse,
+}
+
+/// Non-fatal issue noticed while building or post-processing a template.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GenerationWarning {
+    pub(crate) code: String,
+    pub(crate) message: String,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FeedbackRequest {
+    pub(crate) id: Uuid,
+    pub(crate) rating: i64,
+    #[serde(default)]
+    pub(crate) comment: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FeedbackStat {
+    pub(crate) route: String,
+    pub(crate) model: String,
+    pub(crate) count: i64,
+    /// Mean rating rounded to two decimals.
+    pub(crate) average_rating: f64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PromptSizeStat {
+    pub(crate) route: String,
+    pub(crate) status: String,
+    pub(crate) count: i64,
+    /// Mean estimated prompt tokens, rounded to a whole token.
+    pub(crate) average_prompt_tokens: f64,
+    pub(crate) max_prompt_tokens: i32,
+}
+
+/// Lifecycle of one logged request, as returned by `/request/:id/timeline`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RequestTimeline {
+    pub(crate) status: String,
+    pub(crate) created_at: String,
+    /// When the final status was written; `None` while still running and
+    /// for rows logged before finish times were recorded.
+    pub(crate) finished_at: Option<String>,
+    /// Time spent waiting on GLM.
+    pub(crate) glm_latency_ms: Option<i64>,
+    /// Image generation and processing stages, recorded for successful
+    /// `/generate` calls only.
+    pub(crate) images_ms: Option<i64>,
+    pub(crate) process_ms: Option<i64>,
+    /// Server time from logging the request to writing its final status.
+    pub(crate) total_ms: Option<i64>,
+    /// `total_ms` minus GLM latency: parsing, repair, images and DB writes.
+    pub(crate) other_ms: Option<i64>,
+}
+
+/// Query string of `/admin/backfill/processed`.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct BackfillQuery {
+    pub(crate) limit: Option<i64>,
+    /// `lastId` of the previous batch; omitted to start from the beginning.
+    pub(crate) after: Option<Uuid>,
+}
+
+/// One `/admin/backfill/processed` batch.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BackfillResult {
+    /// Rows picked up in this batch.
+    pub(crate) scanned: usize,
+    pub(crate) backfilled: usize,
+    /// Rows whose stored content could not be rebuilt, with the reason.
+    pub(crate) failed: Vec<BackfillFailure>,
+    /// Pass as `after` to continue; `None` once nothing was scanned.
+    pub(crate) last_id: Option<Uuid>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BackfillFailure {
+    pub(crate) id: Uuid,
+    pub(crate) error: String,
+}
+
+/// Query string of `/export/json/:id`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ExportJsonQuery {
+    #[serde(default)]
+    pub(crate) ordered_endings: bool,
+}
+
+/// Optional overrides for `/ping/glm`; anything omitted uses the server config.
+#[derive(Deserialize, Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GlmPingRequest {
+    #[serde(default)]
+    pub(crate) api_key: Option<String>,
+    #[serde(default)]
+    pub(crate) base_url: Option<String>,
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+}
+
+/// Schema state reported by `/admin/db/version`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DbVersionInfo {
+    /// Highest applied migration version, if any.
+    pub(crate) current: Option<i64>,
+    /// Highest migration version bundled with this build.
+    pub(crate) latest: Option<i64>,
+    pub(crate) pending: Vec<i64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NodeLayout {
+    pub(crate) id: String,
+    pub(crate) level: u32,
+    pub(crate) column: u32,
+    pub(crate) siblings: Vec<String>,
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+}
+
+/// Query string of `/relationships/:id`.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct RelationshipQuery {
+    /// `json` (default) or `mermaid`.
+    pub(crate) format: Option<String>,
+}
+
+/// Social graph of a template's cast, see `build_relationship_graph`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RelationshipGraph {
+    pub(crate) characters: Vec<CastMember>,
+    pub(crate) edges: Vec<RelationshipEdge>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CastMember {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) role: String,
+    /// Story nodes the character is listed in.
+    pub(crate) appearances: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RelationshipEdge {
+    pub(crate) source: String,
+    pub(crate) target: String,
+    pub(crate) relation: String,
+    /// Story nodes both characters are listed in.
+    pub(crate) count: usize,
+}
+
+/// Structural health of a story graph, see `analyze_graph`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GraphMetrics {
+    pub(crate) node_count: usize,
+    /// Nodes the start node cannot reach (all nodes when there is no start).
+    pub(crate) unreachable_nodes: usize,
+    /// Choices pointing at neither a node nor an ending.
+    pub(crate) dangling_targets: usize,
+    /// Choices that close a cycle (back edges, including self references).
+    pub(crate) cycles: usize,
+    /// Nodes with no choices and no valid ending.
+    pub(crate) dead_ends: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CompileRequest {
+    pub(crate) id: Uuid,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -31,12 +226,108 @@ pub(crate) struct UpdateTemplateRequest {
     pub(crate) source: Option<String>,
 }
 
+/// Saved template plus anything dropped on the way in (oversized images).
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpdateTemplateResponse {
+    pub(crate) template: serde_json::Value,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warnings: Vec<GenerationWarning>,
+}
+
+/// Either a full `MovieTemplate` or a loose GLM-style template to repair.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SanitizeTemplateRequest {
+    pub(crate) template: serde_json::Value,
+    #[serde(default)]
+    pub(crate) language: Option<String>,
+    /// `link`, `prune` or `report` (the default): unreachable endings are
+    /// only reported unless the caller asks for a fix.
+    #[serde(default)]
+    pub(crate) orphan_endings: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SanitizeTemplateResponse {
+    pub(crate) template: MovieTemplate,
+    pub(crate) warnings: Vec<GenerationWarning>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AutofixTemplateResponse {
+    pub(crate) fixed_template: MovieTemplate,
+    pub(crate) before: GraphMetrics,
+    pub(crate) after: GraphMetrics,
+    pub(crate) warnings: Vec<GenerationWarning>,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct DeleteTemplateRequest {
     pub(crate) id: Uuid,
 }
 
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RenumberTemplateRequest {
+    pub(crate) id: Uuid,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ContinueGenerationRequest {
+    /// When set, the result is also persisted to this generation record.
+    #[serde(default)]
+    pub(crate) id: Option<Uuid>,
+    pub(crate) template: MovieTemplate,
+    pub(crate) from_node_id: String,
+    pub(crate) instruction: String,
+    #[serde(default)]
+    pub(crate) max_nodes: Option<usize>,
+    pub(crate) api_key: Option<String>,
+    #[serde(default)]
+    pub(crate) base_url: Option<String>,
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+}
+
+/// `/generate/sequel`: the story fields of the new generation come from the
+/// parent game; only the model options are the caller's.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SequelRequest {
+    /// Stored game the sequel continues.
+    pub(crate) id: Uuid,
+    /// Ending of that game the sequel starts from.
+    pub(crate) ending_key: String,
+    #[serde(default)]
+    pub(crate) api_key: Option<String>,
+    #[serde(default)]
+    pub(crate) base_url: Option<String>,
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+    #[serde(default)]
+    pub(crate) quality: Option<String>,
+    #[serde(default)]
+    pub(crate) max_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SplitNodeRequest {
+    pub(crate) id: Uuid,
+    pub(crate) node_id: String,
+    pub(crate) parts: usize,
+    pub(crate) api_key: Option<String>,
+    #[serde(default)]
+    pub(crate) base_url: Option<String>,
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ImportTemplateRequest {
@@ -51,15 +342,27 @@ pub(crate) struct ImportTemplateRequest {
     pub(crate) characters: Option<Vec<CharacterInput>>,
     #[serde(default)]
     pub(crate) language: Option<String>,
+    #[serde(default)]
+    pub(crate) source: Option<String>,
+    /// Run the full import pipeline but skip the DB write.
+    #[serde(default)]
+    pub(crate) dry_run: bool,
 }
 
-#[derive(Deserialize, Debug, Serialize, Clone)]
+#[derive(Deserialize, Debug, Serialize, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct GenerateRequest {
     pub(crate) mode: String,
     pub(crate) theme: Option<String>,
     pub(crate) synopsis: Option<String>,
+    /// Text returned by `/expand/worldview`; when set it is the authoritative
+    /// synopsis and `synopsis` is ignored.
+    #[serde(default)]
+    pub(crate) worldview: Option<String>,
     pub(crate) genre: Option<Vec<String>>,
+    /// Accepts `existingCharacters` too, so the `/expand/character` cast can
+    /// be passed through unchanged.
+    #[serde(alias = "existingCharacters")]
     pub(crate) characters: Option<Vec<CharacterInput>>,
     #[serde(default)]
     pub(crate) min_nodes: Option<u32>,
@@ -69,6 +372,17 @@ pub(crate) struct GenerateRequest {
     pub(crate) min_endings: Option<u32>,
     #[serde(default)]
     pub(crate) max_endings: Option<u32>,
+    #[serde(default)]
+    pub(crate) exact_endings: Option<u32>,
+    #[serde(default)]
+    pub(crate) quick_ending_level: Option<u32>,
+    /// Fewest story nodes any route to an ending may pass; defaults to 12.
+    #[serde(default)]
+    pub(crate) min_path_depth: Option<u32>,
+    /// Opt-in: lengthen too-short routes with connector nodes instead of
+    /// only warning about them.
+    #[serde(default)]
+    pub(crate) pad_short_paths: Option<bool>,
     pub(crate) free_input: Option<String>,
     pub(crate) language: Option<String>,
     #[serde(default)]
@@ -79,6 +393,82 @@ pub(crate) struct GenerateRequest {
     pub(crate) base_url: Option<String>,
     #[serde(default)]
     pub(crate) model: Option<String>,
+    #[serde(default)]
+    pub(crate) image_model: Option<String>,
+    #[serde(default)]
+    pub(crate) image_quality: Option<String>,
+    /// Language for image prompts; defaults to `language`.
+    #[serde(default)]
+    pub(crate) image_prompt_language: Option<String>,
+    #[serde(default)]
+    pub(crate) strip_markdown: Option<bool>,
+    /// Post-processing toggles; each defaults to on when omitted.
+    #[serde(default)]
+    pub(crate) normalize_ids: Option<bool>,
+    #[serde(default)]
+    pub(crate) break_cycles: Option<bool>,
+    /// Drops the DAG rules from the prompt for loop-based stories; makes
+    /// `break_cycles` default to off.
+    #[serde(default)]
+    pub(crate) allow_cycles: Option<bool>,
+    #[serde(default)]
+    pub(crate) dedup_nodes: Option<bool>,
+    #[serde(default)]
+    pub(crate) enforce_endings: Option<bool>,
+    /// Opt-in near-duplicate node merging at this similarity (0, 1].
+    #[serde(default)]
+    pub(crate) near_duplicate_threshold: Option<f64>,
+    /// Opt-in: keep only the first of several choices leading to the same node.
+    #[serde(default)]
+    pub(crate) merge_same_target_choices: Option<bool>,
+    /// `link` (default) or `prune`: what to do with endings no route reaches.
+    #[serde(default)]
+    pub(crate) orphan_endings: Option<String>,
+    /// `fast` / `balanced` / `strict` preset; individual flags take precedence.
+    #[serde(default)]
+    pub(crate) quality: Option<String>,
+    /// Completion budget; defaults per model and is clamped to its ceiling.
+    #[serde(default)]
+    pub(crate) max_tokens: Option<u32>,
+    /// Opt-in scene backgrounds per location; requires an own `apiKey`.
+    #[serde(default)]
+    pub(crate) per_node_backgrounds: Option<bool>,
+    /// Set by the server for sequels: the `characters` avatars come from a
+    /// stored game, so the upload size cap does not apply to them.
+    #[serde(skip)]
+    pub(crate) stored_avatars: bool,
+}
+
+// GLM 偶尔把 isMain 写成 "true"/"是"/1，这里统一宽松解析为 bool
+fn deserialize_bool_lenient<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrStringOrNumber {
+        Bool(bool),
+        Number(i64),
+        String(String),
+    }
+
+    match BoolOrStringOrNumber::deserialize(deserializer)? {
+        BoolOrStringOrNumber::Bool(b) => Ok(b),
+        BoolOrStringOrNumber::Number(1) => Ok(true),
+        BoolOrStringOrNumber::Number(0) => Ok(false),
+        BoolOrStringOrNumber::Number(n) => Err(serde::de::Error::custom(format!(
+            "invalid boolean number: {}",
+            n
+        ))),
+        BoolOrStringOrNumber::String(s) => match s.trim().to_lowercase().as_str() {
+            "true" | "是" | "1" => Ok(true),
+            "false" | "否" | "0" => Ok(false),
+            other => Err(serde::de::Error::custom(format!(
+                "invalid boolean string: {}",
+                other
+            ))),
+        },
+    }
 }
 
 #[derive(Deserialize, Debug, Serialize, Clone)]
@@ -86,8 +476,21 @@ pub(crate) struct CharacterInput {
     pub(crate) name: String,
     pub(crate) description: String,
     pub(crate) gender: String,
-    #[serde(rename = "isMain")]
+    #[serde(rename = "isMain", deserialize_with = "deserialize_bool_lenient")]
     pub(crate) is_main: bool,
+    /// Portrait supplied by the user as a base64 image data URI; used as-is
+    /// instead of generating one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) avatar: Option<String>,
+    /// TTS voice id or label, copied onto the generated character.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) voice: Option<String>,
+    #[serde(
+        rename = "voiceDescription",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) voice_description: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
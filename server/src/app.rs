@@ -7,9 +7,15 @@ use tower_http::cors::{Any, CorsLayer};
 
 use crate::db::AppState;
 use crate::handlers::{
-    delete_template, expand_character, expand_character_prompt, expand_worldview,
-    expand_worldview_prompt, generate, generate_prompt, get_shared_game, get_shared_record_meta,
-    hello, import_template, list_records, share_game, update_template,
+    autofix_template, backfill_processed_responses, cancel_generation, compile_template,
+    continue_generation, delete_template, expand_character, expand_character_prompt,
+    expand_worldview, expand_worldview_prompt, export_template_json, generate, generate_prompt,
+    generate_sequel, generate_stream, get_characters, get_db_version, get_features,
+    get_feedback_stats, get_layout, get_models, get_preview_image, get_prompt_size_stats,
+    get_random_game, get_raw_template, get_relationships, get_request_prompt, get_request_timeline,
+    get_shared_game, get_shared_record_meta, hello, import_template, list_records, ping_glm,
+    renumber_template, sanitize_template, share_game, split_template_node, submit_feedback,
+    update_template,
 };
 
 pub(crate) fn build_app(state: AppState) -> Router {
@@ -22,7 +28,13 @@ pub(crate) fn build_app(state: AppState) -> Router {
         .route("/", get(hello))
         .route("/generate", post(generate))
         .route("/generate/prompt", post(generate_prompt))
+        .route("/generate/continue", post(continue_generation))
+        .route("/generate/cancel/:id", post(cancel_generation))
+        .route("/generate/sequel", post(generate_sequel))
+        .route("/generate/stream", post(generate_stream))
         .route("/import", post(import_template))
+        .route("/sanitize", post(sanitize_template))
+        .route("/template/autofix", post(autofix_template))
         .route("/expand/worldview", post(expand_worldview))
         .route("/expand/worldview/prompt", post(expand_worldview_prompt))
         .route("/expand/character", post(expand_character))
@@ -30,9 +42,32 @@ pub(crate) fn build_app(state: AppState) -> Router {
         .route("/share", post(share_game))
         .route("/template/update", post(update_template))
         .route("/template/delete", post(delete_template))
+        .route("/template/renumber", post(renumber_template))
+        .route("/node/split", post(split_template_node))
+        .route("/template/:id/raw", get(get_raw_template))
+        .route("/request/:id/prompt", get(get_request_prompt))
+        .route("/request/:id/timeline", get(get_request_timeline))
         .route("/play/:id", get(get_shared_game))
+        .route("/preview/:id", get(get_preview_image))
+        .route("/random", get(get_random_game))
+        .route("/layout/:id", get(get_layout))
+        .route("/characters/:id", get(get_characters))
+        .route("/relationships/:id", get(get_relationships))
+        .route("/export/json/:id", get(export_template_json))
+        .route("/compile", post(compile_template))
         .route("/records", post(list_records))
         .route("/records/meta/:id", get(get_shared_record_meta))
+        .route("/feedback", post(submit_feedback))
+        .route("/stats/feedback", get(get_feedback_stats))
+        .route("/admin/db/version", get(get_db_version))
+        .route("/admin/stats/prompt-size", get(get_prompt_size_stats))
+        .route(
+            "/admin/backfill/processed",
+            post(backfill_processed_responses),
+        )
+        .route("/ping/glm", post(ping_glm))
+        .route("/models", get(get_models))
+        .route("/features", get(get_features))
         .with_state(state)
         .layer(cors)
 }
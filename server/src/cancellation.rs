@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+const RUNNING: u8 = 0;
+const CANCELLED: u8 = 1;
+const FINISHING: u8 = 2;
+
+type Jobs = Arc<Mutex<HashMap<Uuid, Arc<AtomicU8>>>>;
+
+/// Cancellation flags of the `/generate` tasks running in this process, keyed
+/// by request id. Each task checks its flag between stages; cancelling and
+/// finishing race on one atomic, so exactly one of them wins.
+pub(crate) struct Cancellations {
+    jobs: Jobs,
+}
+
+/// Held by a running task; dropping it forgets the job.
+pub(crate) struct CancelToken {
+    id: Uuid,
+    state: Arc<AtomicU8>,
+    jobs: Jobs,
+}
+
+impl Cancellations {
+    pub(crate) fn new() -> Self {
+        Self {
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn register(&self, id: Uuid) -> CancelToken {
+        let state = Arc::new(AtomicU8::new(RUNNING));
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, state.clone());
+        CancelToken {
+            id,
+            state,
+            jobs: self.jobs.clone(),
+        }
+    }
+
+    /// Asks the task to stop at its next stage boundary. `false` when no such
+    /// task runs here or it has already committed to finishing.
+    pub(crate) fn cancel(&self, id: Uuid) -> bool {
+        let jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.get(&id).is_some_and(|state| {
+            state
+                .compare_exchange(RUNNING, CANCELLED, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        })
+    }
+}
+
+impl CancelToken {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::Acquire) == CANCELLED
+    }
+
+    /// Commits the task to saving its result. `false` when a cancel got in
+    /// first, in which case the task must stop instead.
+    pub(crate) fn complete(&self) -> bool {
+        self.state
+            .compare_exchange(RUNNING, FINISHING, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
+impl Drop for CancelToken {
+    fn drop(&mut self) {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
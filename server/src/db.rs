@@ -3,12 +3,27 @@ use sqlx::PgPool;
 use std::sync::Arc;
 use uuid::Uuid;
 
+use crate::api_types::SharedGeneration;
+use crate::cancellation::Cancellations;
+use crate::features::FeatureFlags;
+use crate::prompt::estimate_prompt_tokens;
+use crate::rate_limit::{BurstLimiter, TrustedClients};
+use crate::request_summary::StageTimings;
 use crate::sensitive::SensitiveFilter;
+use crate::single_flight::SingleFlight;
 
 #[derive(Clone)]
 pub(crate) struct AppState {
     pub(crate) db: PgPool,
     pub(crate) sensitive: Arc<SensitiveFilter>,
+    pub(crate) burst_limiter: Arc<BurstLimiter>,
+    pub(crate) trusted: Arc<TrustedClients>,
+    /// Free-tier `/generate` calls in flight, keyed by request hash.
+    pub(crate) generations: Arc<SingleFlight<SharedGeneration>>,
+    /// Running `/generate` tasks that `/generate/cancel/:id` can stop.
+    pub(crate) cancellations: Arc<Cancellations>,
+    /// Capabilities reported by `/features` and honoured by `/generate`.
+    pub(crate) features: FeatureFlags,
 }
 
 pub(crate) async fn init_pool() -> Result<PgPool, sqlx::Error> {
@@ -39,12 +54,81 @@ pub(crate) async fn init_db(db: &PgPool) -> Result<(), sqlx::Error> {
     }
 }
 
+pub(crate) async fn insert_feedback(
+    db: &PgPool,
+    request_id: Uuid,
+    rating: i16,
+    comment: Option<&str>,
+    client_ip: &str,
+    user_agent: Option<&str>,
+) -> Result<Uuid, sqlx::Error> {
+    let id = Uuid::new_v4();
+    sqlx::query(
+        "insert into feedback (id, request_id, rating, comment, client_ip, user_agent) values ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(id)
+    .bind(request_id)
+    .bind(rating)
+    .bind(comment)
+    .bind(client_ip)
+    .bind(user_agent)
+    .execute(db)
+    .await?;
+    Ok(id)
+}
+
+/// Rating count and sum per (route, model). Requests without an explicit
+/// model used the route default and are grouped under `default`.
+pub(crate) async fn get_feedback_totals(
+    db: &PgPool,
+) -> Result<Vec<(String, String, i64, i64)>, sqlx::Error> {
+    sqlx::query_as(
+        "select g.route, coalesce(nullif(g.request_payload->>'model', ''), 'default') as model, count(*), sum(f.rating)::bigint \
+         from feedback f join glm_requests g on g.id = f.request_id \
+         group by 1, 2 order by 1, 2",
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Per route and status: request count, mean and max estimated prompt tokens.
+pub(crate) async fn get_prompt_size_totals(
+    db: &PgPool,
+) -> Result<Vec<(String, String, i64, f64, i32)>, sqlx::Error> {
+    sqlx::query_as(
+        "select route, status, count(*), avg(prompt_tokens_estimated)::float8, max(prompt_tokens_estimated) \
+         from glm_requests where prompt_tokens_estimated is not null \
+         group by 1, 2 order by 1, 2",
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// Versions of the migrations embedded in the binary, ascending.
+pub(crate) fn known_migration_versions() -> Vec<i64> {
+    let mut versions: Vec<i64> = sqlx::migrate!("./migrations")
+        .iter()
+        .map(|m| m.version)
+        .collect();
+    versions.sort();
+    versions
+}
+
+/// Versions recorded as successfully applied in `_sqlx_migrations`.
+pub(crate) async fn get_applied_migrations(db: &PgPool) -> Result<Vec<i64>, sqlx::Error> {
+    sqlx::query_scalar("select version from _sqlx_migrations where success order by version")
+        .fetch_all(db)
+        .await
+}
+
 // 数据库错误类型 - 用于与 handlers.rs 中的 ApiResponse 兼容
 #[derive(Debug)]
 pub(crate) enum DbError {
     DailyLimitExceeded,
     TooManyRequests,
     ServiceBusy,
+    // 连接池耗尽/数据库暂不可达，客户端应稍后重试
+    Unavailable,
     // InvalidBaseUrl, // Unused
     InternalError,
 }
@@ -55,6 +139,7 @@ impl DbError {
             DbError::DailyLimitExceeded => "API_KEY_REQUIRED_DAILY_LIMIT",
             DbError::TooManyRequests => "API_KEY_REQUIRED",
             DbError::ServiceBusy => "SERVICE_BUSY",
+            DbError::Unavailable => "SERVICE_UNAVAILABLE",
             // DbError::InvalidBaseUrl => "INVALID_BASE_URL",
             DbError::InternalError => "INTERNAL_ERROR",
         }
@@ -62,13 +147,149 @@ impl DbError {
 
     pub(crate) fn message(&self) -> &'static str {
         match self {
-            DbError::DailyLimitExceeded => "今日免费额度已用完 (30次/天)，请填写 API Key 继续使用",
+            DbError::DailyLimitExceeded => "今日免费额度已用完，请填写 API Key 继续使用",
             DbError::TooManyRequests => "当前并发较高，请填写 API Key 后重试",
             DbError::ServiceBusy => "服务繁忙",
+            DbError::Unavailable => "数据库繁忙，请稍后重试",
             // DbError::InvalidBaseUrl => "Invalid baseUrl",
             DbError::InternalError => "DB Error",
         }
     }
+
+    pub(crate) fn from_sqlx(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                DbError::Unavailable
+            }
+            _ => DbError::InternalError,
+        }
+    }
+}
+
+/// Advisory-lock key for the quota check of one client IP on one route, so
+/// unrelated clients never contend on a single global lock.
+pub(crate) fn quota_lock_key(client_ip: &str, route: &str) -> i64 {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(format!("{}\0{}", route, client_ip).as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(bytes)
+}
+
+/// `QUOTA_ADVISORY_LOCK=0` (or `false`/`off`) skips the lock entirely, trading
+/// exact quota accounting under bursts for throughput.
+fn quota_lock_enabled() -> bool {
+    parse_quota_lock_flag(std::env::var("QUOTA_ADVISORY_LOCK").ok().as_deref())
+}
+
+pub(crate) fn parse_quota_lock_flag(value: Option<&str>) -> bool {
+    !matches!(
+        value.map(|v| v.trim().to_ascii_lowercase()).as_deref(),
+        Some("0" | "false" | "off" | "no")
+    )
+}
+
+/// Free requests per IP per route per day when neither the route's own
+/// variable nor `QUOTA_DAILY` is set.
+pub(crate) const DEFAULT_DAILY_QUOTA: i64 = 30;
+
+/// Env var holding the daily quota of `route`: full generation is priced
+/// separately from the cheap expansions; other routes use the global limit.
+pub(crate) fn route_quota_env(route: &str) -> Option<&'static str> {
+    match route {
+        "/generate" => Some("QUOTA_GENERATE"),
+        "/expand/worldview" | "/expand/character" => Some("QUOTA_EXPAND"),
+        _ => None,
+    }
+}
+
+/// Daily per-IP quota of `route`: its route variable, else `QUOTA_DAILY`,
+/// else `DEFAULT_DAILY_QUOTA`. Unset, blank or non-positive values are skipped.
+pub(crate) fn daily_quota_for_route(route: &str, env: impl Fn(&str) -> Option<String>) -> i64 {
+    let parse = |name: &str| {
+        env(name)
+            .and_then(|v| v.trim().parse::<i64>().ok())
+            .filter(|n| *n > 0)
+    };
+    route_quota_env(route)
+        .and_then(parse)
+        .or_else(|| parse("QUOTA_DAILY"))
+        .unwrap_or(DEFAULT_DAILY_QUOTA)
+}
+
+/// Per-IP quota: `daily_limit` requests per route per day and 2 per 5
+/// minutes. Callers with their own API key or on the trusted allowlist are exempt.
+pub(crate) fn check_ip_quota(
+    daily_count: i64,
+    active: i64,
+    exempt: bool,
+    daily_limit: i64,
+) -> Result<(), DbError> {
+    if exempt {
+        return Ok(());
+    }
+    if daily_count >= daily_limit {
+        return Err(DbError::DailyLimitExceeded);
+    }
+    if active >= 2 {
+        return Err(DbError::TooManyRequests);
+    }
+    Ok(())
+}
+
+/// How a new `glm_requests` row is held to the quotas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QuotaCheck {
+    /// Global cap and per-IP limits apply.
+    Enforced,
+    /// Trusted clients: only the global cap applies.
+    Exempt,
+    /// The caller pays with their own API key: nothing is counted.
+    OwnKey,
+    /// A follow-up call the server makes on its own, such as a length retry:
+    /// logged but never checked.
+    Internal,
+}
+
+impl QuotaCheck {
+    pub(crate) fn for_request(own_api_key: bool, trusted: bool) -> Self {
+        if own_api_key {
+            QuotaCheck::OwnKey
+        } else if trusted {
+            QuotaCheck::Exempt
+        } else {
+            QuotaCheck::Enforced
+        }
+    }
+}
+
+/// Statements `begin_glm_request_log` runs before inserting the row.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct QuotaPlan {
+    pub(crate) advisory_lock: bool,
+    /// Requests on the route today across all clients (`/generate` only).
+    pub(crate) global_count: bool,
+    /// The client's requests today and in the last five minutes.
+    pub(crate) client_counts: bool,
+}
+
+impl QuotaPlan {
+    pub(crate) fn count_queries(&self) -> usize {
+        usize::from(self.global_count) + 2 * usize::from(self.client_counts)
+    }
+}
+
+pub(crate) fn plan_quota_checks(route: &str, quota: QuotaCheck, lock_enabled: bool) -> QuotaPlan {
+    // Own-key and internal requests skip every limit, so the lock and counts
+    // would only add contention for the heaviest users.
+    if matches!(quota, QuotaCheck::OwnKey | QuotaCheck::Internal) {
+        return QuotaPlan::default();
+    }
+    QuotaPlan {
+        advisory_lock: lock_enabled,
+        global_count: route == "/generate",
+        client_counts: true,
+    }
 }
 
 pub(crate) async fn begin_glm_request_log(
@@ -78,74 +299,85 @@ pub(crate) async fn begin_glm_request_log(
     route: &str,
     request_payload: serde_json::Value,
     glm_prompt: &str,
-    using_override_key: bool,
+    quota: QuotaCheck,
 ) -> Result<Uuid, DbError> {
-    let mut tx = db.begin().await.map_err(|_| DbError::InternalError)?;
+    let plan = plan_quota_checks(route, quota, quota_lock_enabled());
+    let id = Uuid::new_v4();
+    let insert = sqlx::query(
+        "insert into glm_requests (id, client_ip, user_agent, route, status, request_payload, glm_prompt, prompt_tokens_estimated) values ($1, $2, $3, $4, 'running', $5, $6, $7)",
+    )
+    .bind(id)
+    .bind(client_ip)
+    .bind(user_agent)
+    .bind(route)
+    .bind(request_payload)
+    .bind(glm_prompt)
+    .bind(estimate_prompt_tokens(glm_prompt));
 
-    let _ = sqlx::query("select pg_advisory_xact_lock($1)")
-        .bind(9001i64)
-        .execute(&mut *tx)
-        .await
-        .map_err(|_| DbError::InternalError)?;
+    if plan == QuotaPlan::default() {
+        insert.execute(db).await.map_err(DbError::from_sqlx)?;
+        return Ok(id);
+    }
+
+    let mut tx = db.begin().await.map_err(DbError::from_sqlx)?;
+
+    // Serializes concurrent quota checks of the same IP/route only; other
+    // clients take different keys and never wait on each other.
+    if plan.advisory_lock {
+        let _ = sqlx::query("select pg_advisory_xact_lock($1)")
+            .bind(quota_lock_key(client_ip, route))
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from_sqlx)?;
+    }
 
-    if route == "/generate" {
+    if plan.global_count {
         let daily_total: i64 = sqlx::query_scalar(
             "select count(*) from glm_requests where route = $1 and created_at > current_date",
         )
         .bind(route)
         .fetch_one(&mut *tx)
         .await
-        .map_err(|_| DbError::InternalError)?;
+        .map_err(DbError::from_sqlx)?;
 
         if daily_total >= 60 {
             return Err(DbError::ServiceBusy);
         }
     }
 
-    // Check daily limit (30 requests per IP per day) - only applies if not using own API Key
-    let daily_count: i64 = sqlx::query_scalar(
-        "select count(*) from glm_requests where client_ip = $1 and route = $2 and created_at > current_date",
-    )
-    .bind(client_ip)
-    .bind(route)
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|_| DbError::InternalError)?;
-
-    if daily_count >= 30 && !using_override_key {
-        return Err(DbError::DailyLimitExceeded);
-    }
-
-    // Check recent request frequency (2 requests per 5 minutes per IP)
-    // Only applies if not using own API Key
-    let active: i64 = sqlx::query_scalar(
-        "select count(*) from glm_requests where client_ip = $1 and route = $2 and created_at > now() - interval '5 minutes'",
-    )
-    .bind(client_ip)
-    .bind(route)
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|_| DbError::InternalError)?;
+    if plan.client_counts {
+        // Check the route's daily limit per IP - skipped for trusted clients
+        let daily_count: i64 = sqlx::query_scalar(
+            "select count(*) from glm_requests where client_ip = $1 and route = $2 and created_at > current_date",
+        )
+        .bind(client_ip)
+        .bind(route)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(DbError::from_sqlx)?;
 
-    if active >= 2 && !using_override_key {
-        return Err(DbError::TooManyRequests);
+        // Check recent request frequency (2 requests per 5 minutes per IP)
+        let active: i64 = sqlx::query_scalar(
+            "select count(*) from glm_requests where client_ip = $1 and route = $2 and created_at > now() - interval '5 minutes'",
+        )
+        .bind(client_ip)
+        .bind(route)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(DbError::from_sqlx)?;
+
+        let daily_limit = daily_quota_for_route(route, |name| std::env::var(name).ok());
+        check_ip_quota(
+            daily_count,
+            active,
+            quota != QuotaCheck::Enforced,
+            daily_limit,
+        )?;
     }
 
-    let id = Uuid::new_v4();
-    sqlx::query(
-        "insert into glm_requests (id, client_ip, user_agent, route, status, request_payload, glm_prompt) values ($1, $2, $3, $4, 'running', $5, $6)",
-    )
-    .bind(id)
-    .bind(client_ip)
-    .bind(user_agent)
-    .bind(route)
-    .bind(request_payload)
-    .bind(glm_prompt)
-    .execute(&mut *tx)
-    .await
-    .map_err(|_| DbError::InternalError)?;
+    insert.execute(&mut *tx).await.map_err(DbError::from_sqlx)?;
 
-    tx.commit().await.map_err(|_| DbError::InternalError)?;
+    tx.commit().await.map_err(DbError::from_sqlx)?;
 
     Ok(id)
 }
@@ -159,7 +391,7 @@ pub(crate) async fn finish_glm_request_log(
     response_time_ms: Option<i64>,
 ) {
     let result = sqlx::query(
-        "update glm_requests set status = $1, glm_response = $2, error_text = $3, response_time_ms = $4, updated_at = now() where id = $5",
+        "update glm_requests set status = $1, glm_response = $2, error_text = $3, response_time_ms = $4, updated_at = now(), finished_at = now() where id = $5",
     )
     .bind(status)
     .bind(response_content)
@@ -174,6 +406,54 @@ pub(crate) async fn finish_glm_request_log(
     }
 }
 
+pub(crate) async fn save_stage_timings(db: &PgPool, id: Uuid, timings: &StageTimings) {
+    let result = sqlx::query(
+        "update glm_requests set glm_ms = $1, images_ms = $2, process_ms = $3 where id = $4",
+    )
+    .bind(timings.glm_ms)
+    .bind(timings.images_ms)
+    .bind(timings.process_ms)
+    .bind(id)
+    .execute(db)
+    .await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to save stage timings: {}", e);
+    }
+}
+
+pub(crate) async fn save_effective_params(db: &PgPool, id: Uuid, params: &serde_json::Value) {
+    let result = sqlx::query("update glm_requests set effective_params = $1 where id = $2")
+        .bind(params)
+        .bind(id)
+        .execute(db)
+        .await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to save effective params: {}", e);
+    }
+}
+
+/// Links a `/generate/sequel` record to the game and ending it continues.
+pub(crate) async fn save_sequel_parent(
+    db: &PgPool,
+    id: Uuid,
+    parent_id: Uuid,
+    parent_ending: &str,
+) {
+    let result =
+        sqlx::query("update glm_requests set parent_id = $1, parent_ending = $2 where id = $3")
+            .bind(parent_id)
+            .bind(parent_ending)
+            .bind(id)
+            .execute(db)
+            .await;
+
+    if let Err(e) = result {
+        eprintln!("Failed to save sequel parent: {}", e);
+    }
+}
+
 pub(crate) async fn save_processed_response(
     db: &PgPool,
     id: Uuid,
@@ -187,6 +467,45 @@ pub(crate) async fn save_processed_response(
     Ok(())
 }
 
+/// Successful `/generate` rows that kept their raw content but never got a
+/// `processed_response` (generated before it was saved), in id order after
+/// `after` so rows that cannot be rebuilt do not block later batches.
+pub(crate) async fn list_unprocessed_generations(
+    db: &PgPool,
+    after: Option<Uuid>,
+    limit: i64,
+) -> Result<Vec<(Uuid, serde_json::Value, String)>, sqlx::Error> {
+    sqlx::query_as(
+        "select id, request_payload, glm_response from glm_requests \
+         where route = '/generate' and status = 'success' \
+         and processed_response is null and glm_response is not null \
+         and ($1::uuid is null or id > $1) \
+         order by id limit $2",
+    )
+    .bind(after)
+    .bind(limit)
+    .fetch_all(db)
+    .await
+}
+
+/// Sets `processed_response` only while it is still null, so a backfill
+/// never overwrites a template saved in the meantime. Returns whether the
+/// row was updated.
+pub(crate) async fn backfill_processed_response(
+    db: &PgPool,
+    id: Uuid,
+    response: &serde_json::Value,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "update glm_requests set processed_response = $1 where id = $2 and processed_response is null",
+    )
+    .bind(response)
+    .bind(id)
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
 pub(crate) async fn get_request_owner(
     db: &PgPool,
     id: Uuid,
@@ -199,6 +518,56 @@ pub(crate) async fn get_request_owner(
     Ok(row)
 }
 
+pub(crate) async fn get_raw_glm_response(
+    db: &PgPool,
+    id: Uuid,
+) -> Result<Option<(String, Option<String>)>, sqlx::Error> {
+    let row: Option<(String, Option<String>)> =
+        sqlx::query_as("select client_ip, glm_response from glm_requests where id = $1")
+            .bind(id)
+            .fetch_optional(db)
+            .await?;
+    Ok(row)
+}
+
+pub(crate) async fn get_glm_prompt(db: &PgPool, id: Uuid) -> Result<Option<String>, sqlx::Error> {
+    let prompt: Option<Option<String>> =
+        sqlx::query_scalar("select glm_prompt from glm_requests where id = $1")
+            .bind(id)
+            .fetch_optional(db)
+            .await?;
+    Ok(prompt.flatten())
+}
+
+/// `(client_ip, status, created_at, finished_at, response_time_ms,
+/// finished_at - created_at in ms, glm_ms, images_ms, process_ms)`.
+pub(crate) type RequestTimelineRow = (
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<i64>,
+    Option<i64>,
+    Option<i64>,
+    Option<i64>,
+    Option<i64>,
+);
+
+pub(crate) async fn get_request_timeline_row(
+    db: &PgPool,
+    id: Uuid,
+) -> Result<Option<RequestTimelineRow>, sqlx::Error> {
+    sqlx::query_as(
+        "select client_ip, status, created_at::text, finished_at::text, response_time_ms, \
+         (extract(epoch from (finished_at - created_at)) * 1000)::bigint, \
+         glm_ms, images_ms, process_ms \
+         from glm_requests where id = $1",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await
+}
+
 pub(crate) async fn set_share_status(
     db: &PgPool,
     id: Uuid,
@@ -212,23 +581,21 @@ pub(crate) async fn set_share_status(
     Ok(())
 }
 
+/// What deleting a game removes, dependents first: every table whose
+/// `request_id` references `glm_requests` must be cleared before the row.
+pub(crate) const DELETE_GAME_STATEMENTS: [&str; 4] = [
+    "delete from records where request_id = $1",
+    "delete from shared_records where request_id = $1",
+    "delete from feedback where request_id = $1",
+    "delete from glm_requests where id = $1",
+];
+
 pub(crate) async fn delete_game_by_request_id(db: &PgPool, id: Uuid) -> Result<(), sqlx::Error> {
     let mut tx = db.begin().await?;
 
-    sqlx::query("delete from records where request_id = $1")
-        .bind(id)
-        .execute(&mut *tx)
-        .await?;
-
-    sqlx::query("delete from shared_records where request_id = $1")
-        .bind(id)
-        .execute(&mut *tx)
-        .await?;
-
-    sqlx::query("delete from glm_requests where id = $1")
-        .bind(id)
-        .execute(&mut *tx)
-        .await?;
+    for statement in DELETE_GAME_STATEMENTS {
+        sqlx::query(statement).bind(id).execute(&mut *tx).await?;
+    }
 
     tx.commit().await?;
     Ok(())
@@ -248,6 +615,20 @@ pub(crate) async fn get_game_for_play(
     Ok(row)
 }
 
+/// A random shared, successfully generated game for the landing page demo.
+/// Backed by the partial index `idx_glm_requests_playable_shared`.
+pub(crate) async fn get_random_shared_game(
+    db: &PgPool,
+) -> Result<Option<(Uuid, Option<serde_json::Value>, bool)>, sqlx::Error> {
+    sqlx::query_as(
+        "select id, processed_response, shared from glm_requests \
+         where shared and status = 'success' and processed_response is not null \
+         order by random() limit 1",
+    )
+    .fetch_optional(db)
+    .await
+}
+
 pub(crate) async fn record_visit(
     db: &PgPool,
     request_id: Uuid,
@@ -273,20 +654,20 @@ pub(crate) async fn upsert_shared_record(
     shared_ip: &str,
     shared_user_agent: Option<&str>,
 ) -> Result<Uuid, DbError> {
-    let mut tx = db.begin().await.map_err(|_| DbError::InternalError)?;
+    let mut tx = db.begin().await.map_err(DbError::from_sqlx)?;
 
     let _ = sqlx::query("select pg_advisory_xact_lock($1)")
         .bind(9002i64)
         .execute(&mut *tx)
         .await
-        .map_err(|_| DbError::InternalError)?;
+        .map_err(DbError::from_sqlx)?;
 
     let existing: Option<Uuid> =
         sqlx::query_scalar("select id from shared_records where request_id = $1")
             .bind(request_id)
             .fetch_optional(&mut *tx)
             .await
-            .map_err(|_| DbError::InternalError)?;
+            .map_err(DbError::from_sqlx)?;
 
     if let Some(id) = existing {
         sqlx::query(
@@ -297,9 +678,9 @@ pub(crate) async fn upsert_shared_record(
         .bind(shared_user_agent)
         .execute(&mut *tx)
         .await
-        .map_err(|_| DbError::InternalError)?;
+        .map_err(DbError::from_sqlx)?;
 
-        tx.commit().await.map_err(|_| DbError::InternalError)?;
+        tx.commit().await.map_err(DbError::from_sqlx)?;
         return Ok(id);
     }
 
@@ -307,7 +688,7 @@ pub(crate) async fn upsert_shared_record(
         sqlx::query_scalar("select count(*) from shared_records where shared_at > current_date")
             .fetch_one(&mut *tx)
             .await
-            .map_err(|_| DbError::InternalError)?;
+            .map_err(DbError::from_sqlx)?;
 
     if daily_total >= 20 {
         return Err(DbError::ServiceBusy);
@@ -319,7 +700,7 @@ pub(crate) async fn upsert_shared_record(
     .bind(shared_ip)
     .fetch_one(&mut *tx)
     .await
-    .map_err(|_| DbError::InternalError)?;
+    .map_err(DbError::from_sqlx)?;
 
     if daily_ip >= 100 {
         return Err(DbError::ServiceBusy);
@@ -335,9 +716,9 @@ pub(crate) async fn upsert_shared_record(
     .bind(shared_user_agent)
     .fetch_one(&mut *tx)
     .await
-    .map_err(|_| DbError::InternalError)?;
+    .map_err(DbError::from_sqlx)?;
 
-    tx.commit().await.map_err(|_| DbError::InternalError)?;
+    tx.commit().await.map_err(DbError::from_sqlx)?;
 
     Ok(row.0)
 }
@@ -372,6 +753,7 @@ pub(crate) async fn list_shared_records_by_request_ids(
         Option<String>,
         Option<String>,
         i64,
+        String,
     )>,
     sqlx::Error,
 > {
@@ -384,7 +766,8 @@ pub(crate) async fn list_shared_records_by_request_ids(
             (gr.processed_response->'meta'->>'synopsis') as synopsis, \
             (gr.processed_response->'meta'->>'genre') as genre, \
             (gr.processed_response->'meta'->>'language') as language, \
-            (select count(*) from records r where r.request_id = sr.request_id) as play_count \
+            (select count(*) from records r where r.request_id = sr.request_id) as play_count, \
+            coalesce(gr.source, gr.route) as source \
          from shared_records sr \
          join glm_requests gr on gr.id = sr.request_id \
          where sr.request_id = any($1) \
@@ -409,19 +792,21 @@ pub(crate) async fn create_imported_request(
     user_agent: &str,
     request_payload: serde_json::Value,
     processed_response: serde_json::Value,
+    source: &str,
 ) -> Result<Uuid, DbError> {
     let id = Uuid::new_v4();
     sqlx::query(
-        "insert into glm_requests (id, client_ip, user_agent, route, status, request_payload, glm_prompt, processed_response, template_source) values ($1, $2, $3, '/import', 'success', $4, '[import]', $5, 'import')",
+        "insert into glm_requests (id, client_ip, user_agent, route, status, request_payload, glm_prompt, processed_response, template_source, source) values ($1, $2, $3, '/import', 'success', $4, '[import]', $5, 'import', $6)",
     )
     .bind(id)
     .bind(client_ip)
     .bind(user_agent)
     .bind(request_payload)
     .bind(processed_response)
+    .bind(source)
     .execute(db)
     .await
-    .map_err(|_| DbError::InternalError)?;
+    .map_err(DbError::from_sqlx)?;
 
     Ok(id)
 }
@@ -436,6 +821,18 @@ pub(crate) async fn set_request_template_source(
         .bind(id)
         .execute(db)
         .await
-        .map_err(|_| DbError::InternalError)?;
+        .map_err(DbError::from_sqlx)?;
+    Ok(())
+}
+
+/// Records which operation last wrote the stored template (audit trail for
+/// the history view).
+pub(crate) async fn set_request_source(db: &PgPool, id: Uuid, source: &str) -> Result<(), DbError> {
+    sqlx::query("update glm_requests set source = $1, updated_at = now() where id = $2")
+        .bind(source)
+        .bind(id)
+        .execute(db)
+        .await
+        .map_err(DbError::from_sqlx)?;
     Ok(())
 }
@@ -0,0 +1,45 @@
+use serde::Serialize;
+
+/// Optional capabilities of this deployment, read once at startup and
+/// reported by `GET /features` so clients can adapt their UI. Each env switch
+/// is on unless set to `0`/`false`/`off`/`no`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FeatureFlags {
+    /// NDJSON `/generate` responses (`Accept: application/x-ndjson`) and
+    /// `/generate/stream`; `FEATURE_STREAMING`.
+    pub(crate) streaming: bool,
+    /// `Prefer: respond-async` on `/generate`; `FEATURE_ASYNC_GENERATE`.
+    pub(crate) async_generate: bool,
+    /// CogView backgrounds and avatars; `FEATURE_IMAGE_GENERATION`.
+    pub(crate) image_generation: bool,
+    /// `perNodeBackgrounds` on `/generate`; `FEATURE_PER_NODE_BACKGROUNDS`.
+    pub(crate) per_node_backgrounds: bool,
+    /// Whether the sensitive-word filter has any words to match. Follows the
+    /// loaded dictionary rather than a switch of its own.
+    pub(crate) sensitive_filter_active: bool,
+}
+
+impl FeatureFlags {
+    /// Reads the switches through `env`, which is `std::env::var` at startup.
+    pub(crate) fn from_env(
+        sensitive_filter_active: bool,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Self {
+        let flag = |name: &str| parse_feature_flag(env(name).as_deref());
+        Self {
+            streaming: flag("FEATURE_STREAMING"),
+            async_generate: flag("FEATURE_ASYNC_GENERATE"),
+            image_generation: flag("FEATURE_IMAGE_GENERATION"),
+            per_node_backgrounds: flag("FEATURE_PER_NODE_BACKGROUNDS"),
+            sensitive_filter_active,
+        }
+    }
+}
+
+pub(crate) fn parse_feature_flag(value: Option<&str>) -> bool {
+    !matches!(
+        value.map(|v| v.trim().to_ascii_lowercase()).as_deref(),
+        Some("0" | "false" | "off" | "no")
+    )
+}
@@ -6,6 +6,104 @@ use url::Url;
 const API_URL: &str = "https://open.bigmodel.cn/api/paas/v4/chat/completions";
 const DEFAULT_MODEL: &str = "glm-4.6v-flash";
 
+const DEFAULT_MAX_TOKENS: u32 = 8192;
+
+/// Output token budget per model family: (name prefix, default, ceiling).
+/// Matched by the longest prefix so `glm-4.6v-flash` doesn't fall under `glm-4.6`.
+const MODEL_TOKEN_CAPS: &[(&str, u32, u32)] = &[
+    ("glm-4v-flash", 1024, 1024),
+    ("glm-4-flash", 4095, 4095),
+    ("glm-4-air", 4095, 4095),
+    ("glm-4-plus", 4095, 4095),
+    ("glm-4-long", 4095, 4095),
+    ("glm-4.5", 16384, 98304),
+    ("glm-4.6", 16384, 131072),
+    ("glm-4.6v", 8192, 16384),
+];
+
+/// Picks `max_tokens` for `model`: the caller's value when given (clamped to
+/// the model's ceiling), otherwise the model default. Unknown models get 8192.
+pub fn resolve_max_tokens(model: &str, requested: Option<u32>) -> u32 {
+    let (default, ceiling) = model_token_caps(model);
+    requested.filter(|n| *n > 0).unwrap_or(default).min(ceiling)
+}
+
+/// `(default, ceiling)` output tokens for `model`, see `MODEL_TOKEN_CAPS`.
+fn model_token_caps(model: &str) -> (u32, u32) {
+    let model = model.trim().to_ascii_lowercase();
+    MODEL_TOKEN_CAPS
+        .iter()
+        .filter(|(prefix, _, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|(_, default, ceiling)| (*default, *ceiling))
+        .unwrap_or((DEFAULT_MAX_TOKENS, DEFAULT_MAX_TOKENS))
+}
+
+/// Chat models offered when `ALLOWED_MODELS` is unset, with whether they
+/// accept `response_format: json_object`.
+const KNOWN_TEXT_MODELS: &[(&str, bool)] = &[
+    ("glm-4.6v-flash", true),
+    ("glm-4.6v", true),
+    ("glm-4.6", true),
+    ("glm-4.5", true),
+    ("glm-4-plus", true),
+    ("glm-4-air", true),
+    ("glm-4-flash", true),
+    ("glm-4-long", true),
+    ("glm-4v-flash", false),
+];
+
+/// One entry of `GET /models`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    pub id: String,
+    pub is_default: bool,
+    pub is_image_model: bool,
+    /// Output token default and ceiling; absent for image models.
+    pub default_max_tokens: Option<u32>,
+    pub max_tokens: Option<u32>,
+    pub supports_json_schema: bool,
+}
+
+fn model_info(id: &str, image_models: &[&str]) -> ModelInfo {
+    let is_image_model = image_models.contains(&id) || id.starts_with("cogview");
+    let caps = (!is_image_model).then(|| model_token_caps(id));
+    ModelInfo {
+        id: id.to_string(),
+        is_default: id == DEFAULT_MODEL,
+        is_image_model,
+        default_max_tokens: caps.map(|(default, _)| default),
+        max_tokens: caps.map(|(_, ceiling)| ceiling),
+        supports_json_schema: KNOWN_TEXT_MODELS
+            .iter()
+            .any(|(name, json)| *name == id && *json),
+    }
+}
+
+/// Models for the frontend picker: the comma-separated `allowed` list when
+/// given (an `ALLOWED_MODELS` value), otherwise the built-in chat models
+/// followed by `image_models`.
+pub fn list_models(allowed: Option<&str>, image_models: &[&str]) -> Vec<ModelInfo> {
+    let mut ids: Vec<&str> = match allowed.map(str::trim).filter(|s| !s.is_empty()) {
+        Some(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|m| !m.is_empty())
+            .collect(),
+        None => KNOWN_TEXT_MODELS
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(image_models.iter().copied())
+            .collect(),
+    };
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(*id));
+    ids.into_iter()
+        .map(|id| model_info(id, image_models))
+        .collect()
+}
+
 pub const GLM_LIMIT_FRIENDLY_MESSAGE: &str =
     "GLM 已达最大调用频率, 请填写自己的 API Key 并再次尝试";
 
@@ -34,6 +132,47 @@ pub fn extract_glm_error_code(text: &str) -> Option<String> {
     None
 }
 
+/// Error code 1211 from GLM API: "模型不存在，请检查模型代码。"
+/// The requested model is unknown or not available to this key.
+pub const GLM_MODEL_NOT_FOUND_CODE: &str = "1211";
+
+pub fn is_model_not_found_error(text: &str) -> bool {
+    extract_glm_error_code(text).as_deref() == Some(GLM_MODEL_NOT_FOUND_CODE)
+}
+
+/// Error code 1301 from GLM API: "系统检测到输入或生成内容可能包含不安全或敏感内容…"
+/// The prompt or the output tripped GLM's content filter.
+pub const GLM_CONTENT_POLICY_CODE: &str = "1301";
+
+/// Phrases GLM (or an OpenAI-compatible gateway) uses for content-filter
+/// rejections when the code is missing or different.
+const CONTENT_POLICY_MARKERS: &[&str] = &[
+    "contentfilter",
+    "content_filter",
+    "content_policy",
+    "不安全或敏感内容",
+];
+
+/// GLM refused on content-policy grounds: error code 1301, a known filter
+/// phrase in the error, or a completion stopped with `finish_reason:
+/// "sensitive"`. Works on both error bodies and 200 responses.
+pub fn is_content_policy_error(text: &str) -> bool {
+    if extract_glm_error_code(text).as_deref() == Some(GLM_CONTENT_POLICY_CODE) {
+        return true;
+    }
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(text) else {
+        return false;
+    };
+    if value["choices"][0]["finish_reason"].as_str() == Some("sensitive") {
+        return true;
+    }
+    let Some(error) = value.get("error") else {
+        return false;
+    };
+    let error = error.to_string().to_lowercase();
+    CONTENT_POLICY_MARKERS.iter().any(|m| error.contains(m))
+}
+
 pub fn contains_limit(text: &str) -> bool {
     text.to_ascii_lowercase().contains("limit")
 }
@@ -128,10 +267,98 @@ struct Choice {
 
 #[derive(Deserialize, Debug)]
 struct MessageContent {
-    content: String,
+    content: serde_json::Value,
+}
+
+/// Text of a chat `message.content`: either a plain string, or the `text`
+/// parts of `[{ "type": "text", "text": "..." }]` concatenated in order, as
+/// some vision models return. `None` when there is no text at all.
+pub(crate) fn message_content_text(content: &serde_json::Value) -> Option<String> {
+    if let Some(text) = content.as_str() {
+        return Some(text.to_string());
+    }
+    let parts: Vec<&str> = content
+        .as_array()?
+        .iter()
+        .filter(|part| part.get("type").and_then(|t| t.as_str()).unwrap_or("text") == "text")
+        .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+        .collect();
+    (!parts.is_empty()).then(|| parts.concat())
+}
+
+/// One line of a `stream: true` chat completion.
+#[derive(Debug, PartialEq)]
+pub(crate) enum StreamLine {
+    /// Text of `choices[0].delta.content`; may be empty.
+    Delta(String),
+    /// `data: [DONE]`.
+    Done,
+    /// An `error` object sent in place of a chunk, as raw JSON.
+    Error(String),
+    /// Blank lines, comments, other SSE fields and chunks without text.
+    Skip,
+}
+
+pub(crate) fn parse_stream_line(line: &str) -> StreamLine {
+    let Some(data) = line.trim_end_matches('\r').strip_prefix("data:") else {
+        return StreamLine::Skip;
+    };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return StreamLine::Done;
+    }
+    let Ok(chunk) = serde_json::from_str::<serde_json::Value>(data) else {
+        return StreamLine::Skip;
+    };
+    if let Some(error) = chunk.get("error") {
+        return StreamLine::Error(error.to_string());
+    }
+    match message_content_text(&chunk["choices"][0]["delta"]["content"]) {
+        Some(text) => StreamLine::Delta(text),
+        None => StreamLine::Skip,
+    }
+}
+
+/// Reads a streamed chat completion to its end, handing each non-empty
+/// delta to `on_delta` and returning the concatenated content. `Ok(None)`
+/// when `on_delta` returns false, i.e. nobody wants the rest.
+pub(crate) async fn read_chat_stream(
+    response: &mut reqwest::Response,
+    mut on_delta: impl FnMut(&str) -> bool,
+) -> Result<Option<String>, String> {
+    let mut content = String::new();
+    let mut pending: Vec<u8> = Vec::new();
+    loop {
+        let chunk = response.chunk().await.map_err(|e| e.to_string())?;
+        let done = chunk.is_none();
+        if let Some(bytes) = chunk {
+            pending.extend_from_slice(&bytes);
+        } else if !pending.is_empty() {
+            pending.push(b'\n');
+        }
+
+        // Split on whole lines only, so a multi-byte character cut across
+        // two chunks is decoded once both halves have arrived.
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            match parse_stream_line(&String::from_utf8_lossy(&line[..end])) {
+                StreamLine::Delta(text) if !text.is_empty() => {
+                    content.push_str(&text);
+                    if !on_delta(&text) {
+                        return Ok(None);
+                    }
+                }
+                StreamLine::Done => return Ok(Some(content)),
+                StreamLine::Error(error) => return Err(error),
+                _ => {}
+            }
+        }
+        if done {
+            return Ok(Some(content));
+        }
+    }
 }
 
-#[allow(dead_code)]
 pub async fn call_glm_with_api_key(
     prompt: String,
     json_mode: bool,
@@ -152,6 +379,7 @@ pub async fn call_glm_with_api_key(
     let model = model.unwrap_or_else(|| DEFAULT_MODEL.to_string());
 
     let request_body = ChatRequest {
+        max_tokens: resolve_max_tokens(&model, None),
         model,
         messages: vec![
             Message {
@@ -167,7 +395,6 @@ pub async fn call_glm_with_api_key(
                 content: prompt.clone(),
             },
         ],
-        max_tokens: 8192,
         response_format: if json_mode {
             Some(ResponseFormat { r#type: "json_object".to_string() })
         } else {
@@ -195,6 +422,10 @@ pub async fn call_glm_with_api_key(
         let text = response.text().await.unwrap_or_default();
         println!("GLM Error Body: {}", text);
 
+        if is_content_policy_error(&text) {
+            return Err(text);
+        }
+
         if is_rate_limit_error(&text) {
             return Err(format!(
                 "GLM API 返回错误码 {}: {}",
@@ -217,8 +448,11 @@ pub async fn call_glm_with_api_key(
     // Try to parse as generic JSON first to check for "error" field
     // (GLM sometimes returns 200 OK with "error" in body)
     if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&text_response) {
-        if json_value.get("error").is_some() {
-            println!("GLM returned 200 OK but with error body: {}", text_response);
+        if json_value.get("error").is_some() || is_content_policy_error(&text_response) {
+            println!(
+                "GLM returned 200 OK but with error body: {}",
+                crate::prompt::truncate_chars(&text_response, crate::prompt::LOG_PREVIEW_CHARS)
+            );
 
             // Check for rate limit in this body
             if is_rate_limit_error(&text_response) {
@@ -240,12 +474,104 @@ pub async fn call_glm_with_api_key(
     }
 
     if let Some(choice) = chat_response.choices.first() {
+        let content = message_content_text(&choice.message.content)
+            .ok_or_else(|| "Invalid GLM response structure".to_string())?;
         println!(
             "GLM Response Content Length: {}",
-            choice.message.content.len()
+            crate::prompt::count_display_chars(&content)
         );
-        Ok(choice.message.content.clone())
+        Ok(content)
     } else {
         Err("No choices in response".to_string())
     }
 }
+
+/// Outcome of a 1-token connectivity probe, see `ping_glm`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GlmPingResult {
+    pub ok: bool,
+    pub model: String,
+    pub latency_ms: u64,
+    /// GLM error code from the response body, when there is one.
+    pub error_code: Option<String>,
+    /// True for error code 1305 (too many requests).
+    pub rate_limited: bool,
+    pub error: Option<String>,
+}
+
+/// Sends a trivial 1-token completion to check that the key, endpoint and
+/// model work, without spending a full generation.
+pub async fn ping_glm(
+    api_key: Option<String>,
+    base_url: Option<String>,
+    model: String,
+) -> GlmPingResult {
+    let mut result = GlmPingResult {
+        ok: false,
+        model: model.clone(),
+        latency_ms: 0,
+        error_code: None,
+        rate_limited: false,
+        error: None,
+    };
+
+    let prepared = resolve_glm_api_key(api_key).and_then(|key| {
+        let endpoint = resolve_glm_endpoint(base_url)?;
+        let client = Client::builder()
+            .timeout(Duration::from_secs(20))
+            .build()
+            .map_err(|e| format!("Failed to build client: {}", e))?;
+        Ok((key, endpoint, client))
+    });
+    let (api_key, endpoint, client) = match prepared {
+        Ok(p) => p,
+        Err(e) => {
+            result.error = Some(e);
+            return result;
+        }
+    };
+
+    let request_body = ChatRequest {
+        model,
+        messages: vec![Message {
+            role: "user".to_string(),
+            content: "ping".to_string(),
+        }],
+        response_format: None,
+        stream: false,
+        max_tokens: 1,
+    };
+
+    let start = std::time::Instant::now();
+    let response = client
+        .post(endpoint)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .header("Content-Type", "application/json")
+        .json(&request_body)
+        .send()
+        .await;
+    let text = match response {
+        Ok(r) => {
+            let success = r.status().is_success();
+            let text = r.text().await.unwrap_or_default();
+            if success && serde_json::from_str::<ChatResponse>(&text).is_ok() {
+                None
+            } else {
+                Some(text)
+            }
+        }
+        Err(e) => Some(format!("Request failed: {}", e)),
+    };
+    result.latency_ms = start.elapsed().as_millis() as u64;
+
+    match text {
+        None => result.ok = true,
+        Some(text) => {
+            result.error_code = extract_glm_error_code(&text);
+            result.rate_limited = is_rate_limit_error(&text);
+            result.error = Some(text);
+        }
+    }
+    result
+}
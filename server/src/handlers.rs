@@ -1,6 +1,7 @@
 use axum::{
-    extract::{ConnectInfo, Path, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
     response::{IntoResponse, Response},
     Json,
 };
@@ -12,31 +13,64 @@ use url::Url;
 use uuid::Uuid;
 
 use crate::api_types::{
-    CharacterInput, DeleteTemplateRequest, ExpandCharacterRequest, ExpandWorldviewRequest,
-    GenerateRequest, GenerateResponse, ImportTemplateRequest, RecordsListRequest, ShareRequest,
-    UpdateTemplateRequest,
+    AutofixTemplateResponse, BackfillFailure, BackfillQuery, BackfillResult, CompileRequest,
+    ContinueGenerationRequest, DbVersionInfo, DeleteTemplateRequest, ExpandCharacterRequest,
+    ExpandWorldviewRequest, ExportJsonQuery, FeedbackRequest, FeedbackStat, GenerateRequest,
+    GenerateResponse, GenerationWarning, GlmPingRequest, ImportTemplateRequest, NodeLayout,
+    PromptSizeStat, RecordsListRequest, RelationshipQuery, RenumberTemplateRequest,
+    RequestTimeline, SanitizeTemplateRequest, SanitizeTemplateResponse, SequelRequest,
+    ShareRequest, SharedGeneration, SplitNodeRequest, UpdateTemplateRequest,
+    UpdateTemplateResponse,
 };
+use crate::cancellation::CancelToken;
 use crate::db::{
-    begin_glm_request_log, create_imported_request, delete_game_by_request_id,
-    finish_glm_request_log, get_request_owner,
-    get_shared_record_meta_by_request_id, record_visit,
-    save_processed_response, set_request_template_source, set_share_status, upsert_shared_record,
-    AppState, DbError,
+    backfill_processed_response, begin_glm_request_log, create_imported_request,
+    delete_game_by_request_id, finish_glm_request_log, get_applied_migrations, get_feedback_totals,
+    get_glm_prompt, get_prompt_size_totals, get_raw_glm_response, get_request_owner,
+    get_request_timeline_row, get_shared_record_meta_by_request_id, insert_feedback,
+    known_migration_versions, list_unprocessed_generations, record_visit, save_effective_params,
+    save_processed_response, save_sequel_parent, save_stage_timings, set_request_source,
+    set_request_template_source, set_share_status, upsert_shared_record, AppState, DbError,
+    QuotaCheck, RequestTimelineRow,
 };
 use crate::glm;
 use crate::images::{
-    ensure_avatar_fallbacks, fallback_background_data_uri, generate_scene_background_base64,
-    maybe_attach_generated_avatars, normalize_cogview_size, pick_background_prompt,
+    attach_node_backgrounds, attach_supplied_avatars, ensure_avatar_fallbacks,
+    fallback_background_data_uri, generate_scene_background_base64, max_image_bytes,
+    max_node_backgrounds, maybe_attach_generated_avatars, normalize_cogview_size,
+    pick_background_prompt, pick_image_prompt_language, preview_card_svg, resolve_image_options,
+    strip_oversized_images, validate_image_options, watermark_text,
 };
+use crate::player_bundle::build_player_html;
 use crate::prompt::{
-    clean_json, construct_expand_character_prompt, construct_expand_worldview_prompt, construct_prompt,
+    build_sequel_request, cap_prompt_characters, clean_json, construct_continue_prompt,
+    construct_expand_character_prompt, construct_expand_worldview_prompt, construct_prompt,
+    construct_split_node_prompt, construct_worldview_length_retry_prompt, count_display_chars,
+    ensure_default_protagonist, parse_expanded_characters, prompt_character_cap,
+    prompt_endings_range, sanitize_genre_tags, truncate_chars, unwrap_worldview_text,
+    worldview_length_gap, ParseDiagnostic, LOG_PREVIEW_CHARS, PROMPT_NODE_RANGE,
+};
+use crate::rate_limit::{sha256_hex, TrustedClients};
+use crate::request_summary::{
+    emit_request_summary, update_summary, RequestSummary, SharedRequestSummary, Stage, StageClock,
 };
 use crate::sensitive::SensitiveFilter;
+use crate::single_flight::Flight;
 use crate::template::{
-    convert_lite_to_full, normalize_character_ids, normalize_template_endings,
-    normalize_template_nodes, sanitize_affinity_effects, sanitize_template_graph,
-    MovieTemplateLite,
+    analyze_graph, apply_genre_tags, build_layout_hints, build_relationship_graph,
+    convert_lite_to_full, enforce_exact_endings, enforce_level_cap,
+    enforce_quick_ending, find_short_paths, max_endings_cap, max_nodes_hard_limit,
+    max_nodes_per_level, normalize_character_ids, normalize_template_endings,
+    normalize_template_endings_with_cap, normalize_template_nodes, pad_short_paths,
+    parse_continuation, parse_split_beats, reconcile_orphan_endings, redirect_backward_choices,
+    relationship_graph_mermaid, renumber_nodes_topologically, sanitize_affinity_effects,
+    sanitize_template_graph, sanitize_template_graph_with, splice_continuation, split_node,
+    strip_template_markdown, touch_provenance, GraphRepairOptions, MovieTemplateLite, OrphanAction, OrphanEnding,
+    OrphanEndingPolicy, DEFAULT_MIN_PATH_DEPTH, DEFAULT_QUICK_ENDING_LEVEL, MAX_CONTINUE_NODES,
+    MAX_EXACT_ENDINGS, MAX_MIN_PATH_DEPTH, MAX_QUICK_ENDING_LEVEL, MAX_SPLIT_PARTS,
+    MIN_SPLIT_PARTS,
 };
+use crate::types::{ordered_endings, sorted_entries, MovieTemplate};
 
 // ===== 统一响应格式 =====
 
@@ -52,6 +86,14 @@ pub const CODE_BAD_REQUEST: &str = "BAD_REQUEST";
 pub const CODE_INTERNAL_ERROR: &str = "INTERNAL_ERROR";
 // 无效的 baseUrl
 pub const CODE_INVALID_BASE_URL: &str = "INVALID_BASE_URL";
+// 模型内容安全策略拒绝
+pub const CODE_CONTENT_POLICY: &str = "CONTENT_POLICY";
+// 状态冲突（如已结束的生成无法取消）
+pub const CODE_CONFLICT: &str = "CONFLICT";
+// 生成已被客户端取消
+pub const CODE_CANCELLED: &str = "CANCELLED";
+// 超出请求截止时间（X-Deadline-Ms 或服务端软截止）
+pub const CODE_DEADLINE_EXCEEDED: &str = "DEADLINE_EXCEEDED";
 
 /// 统一 API 响应格式
 #[derive(Serialize)]
@@ -94,20 +136,26 @@ fn success_response<T: Serialize>(data: T) -> Json<ApiResponse<T>> {
     Json(ApiResponse::success(data))
 }
 
+pub(crate) fn status_for_code(code: &str) -> StatusCode {
+    match code {
+        CODE_TOO_MANY_REQUESTS | "SERVICE_BUSY" => StatusCode::TOO_MANY_REQUESTS,
+        CODE_BAD_REQUEST | CODE_INVALID_BASE_URL | CODE_CONTENT_POLICY => StatusCode::BAD_REQUEST,
+        "FORBIDDEN" => StatusCode::FORBIDDEN,
+        "NOT_FOUND" => StatusCode::NOT_FOUND,
+        "SERVICE_UNAVAILABLE" => StatusCode::SERVICE_UNAVAILABLE,
+        CODE_DEADLINE_EXCEEDED => StatusCode::GATEWAY_TIMEOUT,
+        CODE_CONFLICT | CODE_CANCELLED => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
 fn error_response(
     code: impl Into<String>,
     msg: impl Into<String>,
 ) -> (StatusCode, Json<ApiResponse<()>>) {
     let code_str = code.into();
-    let status = match code_str.as_str() {
-        CODE_TOO_MANY_REQUESTS | "SERVICE_BUSY" => StatusCode::TOO_MANY_REQUESTS,
-        CODE_BAD_REQUEST | CODE_INVALID_BASE_URL => StatusCode::BAD_REQUEST,
-        "FORBIDDEN" => StatusCode::FORBIDDEN,
-        "NOT_FOUND" => StatusCode::NOT_FOUND,
-        _ => StatusCode::INTERNAL_SERVER_ERROR,
-    };
     (
-        status,
+        status_for_code(&code_str),
         Json(ApiResponse {
             code: code_str,
             msg: msg.into(),
@@ -130,15 +178,8 @@ fn error_response_with_data<T: Serialize>(
     data: T,
 ) -> (StatusCode, Json<ApiResponse<T>>) {
     let code_str = code.into();
-    let status = match code_str.as_str() {
-        CODE_TOO_MANY_REQUESTS | "SERVICE_BUSY" => StatusCode::TOO_MANY_REQUESTS,
-        CODE_BAD_REQUEST | CODE_INVALID_BASE_URL => StatusCode::BAD_REQUEST,
-        "FORBIDDEN" => StatusCode::FORBIDDEN,
-        "NOT_FOUND" => StatusCode::NOT_FOUND,
-        _ => StatusCode::INTERNAL_SERVER_ERROR,
-    };
     (
-        status,
+        status_for_code(&code_str),
         Json(ApiResponse {
             code: code_str,
             msg: msg.into(),
@@ -192,16 +233,43 @@ fn sanitize_request_payload<T: Serialize + DeserializeOwned>(
     filter: &SensitiveFilter,
     payload: T,
 ) -> Result<T, Response> {
-    let mut v = serde_json::to_value(payload)
-        .map_err(|_| error_response(CODE_BAD_REQUEST, "Invalid payload").into_response())?;
+    sanitize_request_payload_counted(filter, payload)
+        .map(|(payload, _)| payload)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())
+}
+
+/// Like `sanitize_request_payload`, also returning how many words were replaced.
+fn sanitize_request_payload_counted<T: Serialize + DeserializeOwned>(
+    filter: &SensitiveFilter,
+    payload: T,
+) -> Result<(T, usize), (&'static str, &'static str)> {
+    let mut v = serde_json::to_value(payload).map_err(|_| (CODE_BAD_REQUEST, "Invalid payload"))?;
 
     // We only sanitize string values recursively, we should NOT fail if sensitive words are found.
     // sanitize_json modifies the value in place and returns the count of replacements.
-    // We ignore the return value because we want to proceed even if replacements occurred.
-    filter.sanitize_json(&mut v);
+    let hits = filter.sanitize_json(&mut v);
 
     serde_json::from_value(v)
-        .map_err(|_| error_response(CODE_BAD_REQUEST, "Invalid payload").into_response())
+        .map(|payload| (payload, hits))
+        .map_err(|_| (CODE_BAD_REQUEST, "Invalid payload"))
+}
+
+/// Replaces uploaded avatar data URIs in a logged request body with their
+/// length, keeping image data out of `glm_requests.request_body`.
+fn redact_character_avatars(payload: &mut serde_json::Value) {
+    let Some(chars) = payload
+        .get_mut("characters")
+        .and_then(serde_json::Value::as_array_mut)
+    else {
+        return;
+    };
+    for c in chars {
+        if let Some(avatar) = c.get_mut("avatar") {
+            if let Some(len) = avatar.as_str().map(str::len) {
+                *avatar = serde_json::Value::String(format!("<{} bytes omitted>", len));
+            }
+        }
+    }
 }
 
 fn is_trusted_proxy_hop(ip: IpAddr) -> bool {
@@ -279,6 +347,21 @@ fn resolve_glm_api_key(override_key: Option<&str>) -> Result<String, StatusCode>
     glm_api_key()
 }
 
+/// Drops a trusted pass key from the request so it is neither forwarded to GLM
+/// nor treated as an override key, and reports whether the caller is exempt
+/// from the per-IP quotas via `TRUSTED_IPS`/`TRUSTED_KEYS`.
+fn take_trusted_key(
+    trusted: &TrustedClients,
+    client_ip: &str,
+    api_key: &mut Option<String>,
+) -> bool {
+    let key_trusted = trusted.is_trusted_key(api_key.as_deref());
+    if key_trusted {
+        *api_key = None;
+    }
+    key_trusted || trusted.is_trusted_ip(client_ip)
+}
+
 fn resolve_glm_endpoint(base_url: Option<&str>) -> Result<String, StatusCode> {
     let raw = base_url.unwrap_or("").trim();
     if raw.is_empty() {
@@ -308,14 +391,409 @@ fn resolve_glm_endpoint(base_url: Option<&str>) -> Result<String, StatusCode> {
         .map_err(|_| StatusCode::BAD_REQUEST)
 }
 
+/// Image endpoint behind the same base URL as chat, so gateways that proxy
+/// GLM also serve CogView. Defaults to the official endpoint.
+pub(crate) fn resolve_image_endpoint(base_url: Option<&str>) -> Result<String, StatusCode> {
+    let chat = resolve_glm_endpoint(base_url)?;
+    const CHAT_PATH: &str = "chat/completions";
+    Ok(match chat.rfind(CHAT_PATH) {
+        Some(i) => format!(
+            "{}images/generations{}",
+            &chat[..i],
+            &chat[i + CHAT_PATH.len()..]
+        ),
+        None => chat,
+    })
+}
+
+const DEFAULT_GLM_MODEL: &str = "glm-4.6v-flash";
+
+// 各接口免费额度下使用的默认模型（环境变量名）
+pub(crate) const MODEL_GENERATE_ENV: &str = "MODEL_GENERATE";
+pub(crate) const MODEL_WORLDVIEW_ENV: &str = "MODEL_WORLDVIEW";
+pub(crate) const MODEL_CHARACTER_ENV: &str = "MODEL_CHARACTER";
+
+/// Response header carrying the model that actually served the request.
+pub(crate) const MODEL_HEADER: &str = "x-glm-model";
+
+/// Response header summarizing `/generate` warnings, see
+/// `generation_warnings_header`.
+pub(crate) const WARNINGS_HEADER: &str = "x-generation-warnings";
+
+/// Default model for an endpoint: the given env var when set, otherwise
+/// `glm-4.6v-flash`.
+pub(crate) fn endpoint_default_model(env_key: &str) -> String {
+    std::env::var(env_key)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| DEFAULT_GLM_MODEL.to_string())
+}
+
+/// The requested model is honored only with the caller's own key; otherwise
+/// the endpoint default from `env_key` is used.
+pub(crate) fn effective_model(
+    requested: Option<&str>,
+    using_override_key: bool,
+    env_key: &str,
+) -> String {
+    requested
+        .map(str::trim)
+        .filter(|m| using_override_key && !m.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| endpoint_default_model(env_key))
+}
+
+fn has_preference(headers: &HeaderMap, preference: &str) -> bool {
+    headers
+        .get_all("prefer")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|pref| {
+            let pref = pref.split(';').next().unwrap_or("").trim();
+            pref.eq_ignore_ascii_case(preference)
+        })
+}
+
+/// True when a `Prefer` header asks for `return=minimal` (RFC 7240).
+pub(crate) fn prefers_minimal_return(headers: &HeaderMap) -> bool {
+    has_preference(headers, "return=minimal")
+}
+
+/// True when a `Prefer` header asks for `respond-async` (RFC 7240).
+pub(crate) fn prefers_respond_async(headers: &HeaderMap) -> bool {
+    has_preference(headers, "respond-async")
+}
+
+/// `202 Accepted` for `Prefer: respond-async` on `/generate`: the record id,
+/// to poll with `/request/:id/timeline` or stop with `/generate/cancel/:id`.
+pub(crate) fn accepted_generate_response(id: Uuid) -> Response {
+    let mut res = (StatusCode::ACCEPTED, success_response(json!({ "id": id }))).into_response();
+    res.headers_mut().insert(
+        "preference-applied",
+        axum::http::HeaderValue::from_static("respond-async"),
+    );
+    res
+}
+
+/// Body for `Prefer: return=minimal` on `/generate`: only the record id, which
+/// `/play/:id` resolves to the persisted template.
+pub(crate) fn minimal_generate_response(id: Uuid) -> Response {
+    let mut res = success_response(json!({ "id": id })).into_response();
+    res.headers_mut().insert(
+        "preference-applied",
+        axum::http::HeaderValue::from_static("return=minimal"),
+    );
+    res
+}
+
+/// True when the client asks for the NDJSON form of `/generate` via `Accept`.
+pub(crate) fn prefers_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(axum::http::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| {
+            v.split(';')
+                .next()
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case(NDJSON_CONTENT_TYPE))
+        })
+}
+
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Splits a finished template into NDJSON records: `envelope` (the template
+/// without images, characters, nodes and endings), `backgroundImage`, one
+/// `character` / `node` / `ending` per entry in stable key order, then `done`
+/// with the warnings. Each record is serialized only when the iterator
+/// reaches it, so the first bytes go out before the large parts are encoded.
+pub(crate) fn template_ndjson_lines(
+    id: Uuid,
+    mut template: MovieTemplate,
+    warnings: Vec<GenerationWarning>,
+) -> impl Iterator<Item = String> + Send + 'static {
+    fn line(value: serde_json::Value) -> String {
+        let mut s = value.to_string();
+        s.push('\n');
+        s
+    }
+
+    let background = template.background_image_base64.take();
+    let characters = sorted_entries(std::mem::take(&mut template.characters));
+    let nodes = sorted_entries(std::mem::take(&mut template.nodes));
+    let endings = sorted_entries(std::mem::take(&mut template.endings));
+
+    std::iter::once_with(move || {
+        line(json!({ "type": "envelope", "id": id, "template": template }))
+    })
+    .chain(
+        background
+            .into_iter()
+            .map(|bg| line(json!({ "type": "backgroundImage", "backgroundImageBase64": bg }))),
+    )
+    .chain(characters.into_iter().map(|(key, character)| {
+        line(json!({ "type": "character", "key": key, "character": character }))
+    }))
+    .chain(
+        nodes
+            .into_iter()
+            .map(|(key, node)| line(json!({ "type": "node", "key": key, "node": node }))),
+    )
+    .chain(
+        endings
+            .into_iter()
+            .map(|(key, ending)| line(json!({ "type": "ending", "key": key, "ending": ending }))),
+    )
+    .chain(std::iter::once_with(move || {
+        line(json!({ "type": "done", "warnings": warnings }))
+    }))
+}
+
+/// Streams the `/generate` result as NDJSON (see `template_ndjson_lines`).
+pub(crate) fn ndjson_generate_response(
+    id: Uuid,
+    template: MovieTemplate,
+    warnings: Vec<GenerationWarning>,
+) -> Response {
+    let lines =
+        template_ndjson_lines(id, template, warnings).map(Ok::<_, std::convert::Infallible>);
+    let mut res = Response::new(axum::body::Body::from_stream(futures_util::stream::iter(
+        lines,
+    )));
+    res.headers_mut().insert(
+        axum::http::header::CONTENT_TYPE,
+        axum::http::HeaderValue::from_static(NDJSON_CONTENT_TYPE),
+    );
+    res
+}
+
+/// Single-flight key for `/generate`: the normalized request (after presets,
+/// sanitizing and defaults) without the API key, so identical requests from
+/// different clients share one GLM call; see `PreparedGeneration::adopt`.
+/// Response-shape headers are left out, each caller formats the shared
+/// result itself.
+pub(crate) fn generation_flight_key(payload: &GenerateRequest) -> String {
+    let mut value = serde_json::to_value(payload).unwrap_or(json!({}));
+    if let Some(obj) = value.as_object_mut() {
+        obj.remove("apiKey");
+    }
+    sha256_hex(&value.to_string())
+}
+
+/// Shapes a finished generation per the caller's `Prefer`/`Accept` headers.
+pub(crate) fn generated_response(
+    generated: GenerateResponse,
+    minimal: bool,
+    ndjson: bool,
+) -> Response {
+    let summary = generation_warnings_header(&generated.warnings)
+        .and_then(|v| axum::http::HeaderValue::from_str(&v).ok());
+    let mut res = if minimal {
+        minimal_generate_response(generated.id)
+    } else if ndjson {
+        ndjson_generate_response(generated.id, generated.template, generated.warnings)
+    } else {
+        success_response(generated).into_response()
+    };
+    if let Some(v) = summary {
+        res.headers_mut().insert(WARNINGS_HEADER, v);
+    }
+    res
+}
+
+/// Short name of a warning code in `X-Generation-Warnings`; codes without
+/// one appear lowercased.
+fn warning_category(code: &str) -> String {
+    match code {
+        "CYCLES_BROKEN" | "BACKWARD_CHOICE_REDIRECTED" => "cycles",
+        "UNREACHABLE_NODES" => "unreachable",
+        "IMAGE_PLACEHOLDER" | "IMAGES_SKIPPED_DEADLINE" => "placeholder",
+        "LEVEL_NODES_MERGED" => "merged",
+        "SHORT_PATH" | "SHORT_PATH_PADDED" => "depth",
+        "ORPHAN_ENDING_LINKED" | "ORPHAN_ENDING_PRUNED" | "ORPHAN_ENDING_UNREACHABLE" => {
+            "endings"
+        }
+        "AVATAR_REJECTED" | "IMAGE_STRIPPED" => "image",
+        "CHARACTERS_TRUNCATED" => "characters",
+        other => return other.to_ascii_lowercase(),
+    }
+    .to_string()
+}
+
+/// Warnings counted per category, e.g. `cycles=1,placeholder=2`, for
+/// clients that want a quick signal without reading the body. `None` when
+/// there are no warnings.
+pub(crate) fn generation_warnings_header(warnings: &[GenerationWarning]) -> Option<String> {
+    let mut counts: std::collections::BTreeMap<String, usize> = Default::default();
+    for w in warnings {
+        *counts.entry(warning_category(&w.code)).or_default() += 1;
+    }
+    (!counts.is_empty()).then(|| {
+        counts
+            .iter()
+            .map(|(category, n)| format!("{}={}", category, n))
+            .collect::<Vec<_>>()
+            .join(",")
+    })
+}
+
+pub(crate) fn with_model_header(
+    res: Result<Response, Response>,
+    model: &str,
+) -> Result<Response, Response> {
+    let tag = |mut r: Response| {
+        if let Ok(v) = axum::http::HeaderValue::from_str(model) {
+            r.headers_mut().insert(MODEL_HEADER, v);
+        }
+        r
+    };
+    res.map(tag).map_err(tag)
+}
+
+const CONTENT_POLICY_MESSAGE: &str =
+    "内容未通过模型的安全审核，请调整主题、简介或角色描述中可能敏感的内容后重试";
+
+/// GLM's content filter rejected the prompt or output: the user can fix
+/// this by rewording, so it is a 400 with guidance rather than a 500.
+fn content_policy_response(error_text: &str) -> Option<Response> {
+    glm::is_content_policy_error(error_text).then(|| {
+        eprintln!(
+            "GLM content policy rejection (code {})",
+            glm::extract_glm_error_code(error_text)
+                .as_deref()
+                .unwrap_or("-")
+        );
+        error_response(CODE_CONTENT_POLICY, CONTENT_POLICY_MESSAGE).into_response()
+    })
+}
+
+fn model_unavailable_response(error_text: &str, model: &str) -> Option<Response> {
+    glm::is_model_not_found_error(error_text).then(|| {
+        error_response(
+            CODE_BAD_REQUEST,
+            format!("模型 {} 不可用，请检查 model 参数", model),
+        )
+        .into_response()
+    })
+}
+
+/// Canonicalizes a BCP 47-ish language tag (`en_us` → `en-US`). Returns
+/// `None` for wildcards and anything that isn't letters/digits/hyphens.
+pub(crate) fn normalize_language(tag: &str) -> Option<String> {
+    let tag = tag.trim().replace('_', "-");
+    if tag.is_empty()
+        || tag.len() > 35
+        || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+    let mut parts = tag.split('-').filter(|p| !p.is_empty());
+    let primary = parts.next()?.to_ascii_lowercase();
+    let mut out = primary;
+    for part in parts {
+        out.push('-');
+        if part.len() == 2 && part.chars().all(|c| c.is_ascii_alphabetic()) {
+            out.push_str(&part.to_ascii_uppercase());
+        } else {
+            out.push_str(part);
+        }
+    }
+    Some(out)
+}
+
+/// Picks the highest-weighted acceptable tag from an `Accept-Language` header;
+/// among equal weights the first listed wins.
+pub(crate) fn language_from_accept_language(header: Option<&str>) -> Option<String> {
+    let mut best: Option<(f32, String)> = None;
+    for item in header?.split(',') {
+        let mut fields = item.split(';');
+        let Some(tag) = fields.next().and_then(normalize_language) else {
+            continue;
+        };
+        let q = fields
+            .filter_map(|f| f.trim().strip_prefix("q="))
+            .find_map(|v| v.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if q <= 0.0 {
+            continue;
+        }
+        let better = match &best {
+            Some((best_q, _)) => q > *best_q,
+            None => true,
+        };
+        if better {
+            best = Some((q, tag));
+        }
+    }
+    best.map(|(_, tag)| tag)
+}
+
+/// Fills `payload.language` from `Accept-Language` when the body omits it, so
+/// prompt, labels and image hints follow the client before the `zh-CN` default.
+pub(crate) fn apply_accept_language(payload: &mut GenerateRequest, headers: &HeaderMap) {
+    if payload
+        .language
+        .as_deref()
+        .is_some_and(|l| !l.trim().is_empty())
+    {
+        return;
+    }
+    let header = headers
+        .get(axum::http::header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok());
+    if let Some(language) = language_from_accept_language(header) {
+        payload.language = Some(language);
+    }
+}
+
+const MAX_SOURCE_LEN: usize = 32;
+
+/// Audit label stored in `glm_requests.source`: the caller's `source` (e.g.
+/// `manual-edit`, `ai-regen`) when given, otherwise the route name.
+pub(crate) fn resolve_request_source(source: Option<&str>, route: &str) -> Result<String, String> {
+    let Some(s) = source.map(str::trim).filter(|s| !s.is_empty()) else {
+        return Ok(route.to_string());
+    };
+    let valid_chars = s
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '/' | '.'));
+    if count_display_chars(s) > MAX_SOURCE_LEN || !valid_chars {
+        return Err(format!(
+            "source 只能包含字母、数字及 -_/.，且不超过 {} 个字符",
+            MAX_SOURCE_LEN
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Maps an error string from `glm::call_glm_with_api_key` to a response.
+fn glm_call_error_response(error_text: &str, error_text_s: String, model: &str) -> Response {
+    if error_text == glm::GLM_LIMIT_FRIENDLY_MESSAGE || error_text.starts_with("GLM API 返回错误码")
+    {
+        return rate_limit_response(error_text_s).into_response();
+    }
+    if let Some(res) = content_policy_response(error_text) {
+        return res;
+    }
+    if let Some(res) = model_unavailable_response(error_text, model) {
+        return res;
+    }
+    error_response(CODE_INTERNAL_ERROR, error_text_s).into_response()
+}
+
 pub(crate) async fn hello() -> &'static str {
     "Hello from Axum!"
 }
 
 pub(crate) async fn generate_prompt(
     State(_state): State<AppState>,
-    Json(payload): Json<GenerateRequest>,
+    headers: HeaderMap,
+    Json(mut payload): Json<GenerateRequest>,
 ) -> Result<Json<ApiResponse<String>>, Response> {
+    apply_accept_language(&mut payload, &headers);
+    ensure_default_protagonist(&mut payload);
     let prompt = construct_prompt(&payload);
     Ok(success_response(prompt))
 }
@@ -328,29 +806,20 @@ pub(crate) async fn import_template(
 ) -> Result<Json<ApiResponse<GenerateResponse>>, Response> {
     // Check strict fields FIRST
     if let Some(theme) = &payload.theme {
-        if theme.chars().count() > 20 {
+        if count_display_chars(theme) > 20 {
             return Err(error_response(CODE_BAD_REQUEST, "主题长度不能超过 20 字").into_response());
         }
         ensure_not_sensitive(&state.sensitive, theme, "主题", &payload)?;
     }
-    if payload.template.title.chars().count() > 20 {
+    if count_display_chars(&payload.template.title) > 20 {
         return Err(error_response(CODE_BAD_REQUEST, "标题长度不能超过 20 字").into_response());
     }
     ensure_not_sensitive(&state.sensitive, &payload.template.title, "标题", &payload)?;
+    let source = resolve_request_source(payload.source.as_deref(), "/import")
+        .map_err(|e| error_response(CODE_BAD_REQUEST, e).into_response())?;
 
-    // Validate base64 image size
-    if let Some(bg) = &payload.template.background_image_base64 {
-        if bg.len() > 400_000 { // Approx 300KB
-            return Err(error_response(CODE_BAD_REQUEST, "背景图片过大 (超过 300KB)").into_response());
-        }
-    }
-    for char in payload.template.characters.values() {
-        if let Some(avatar) = &char.avatar_path {
-            if avatar.len() > 400_000 {
-                return Err(error_response(CODE_BAD_REQUEST, format!("角色 {} 头像过大 (超过 300KB)", char.name)).into_response());
-            }
-        }
-    }
+    check_node_limit(payload.template.nodes.len(), max_nodes_hard_limit())
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
 
     // Then sanitize the whole payload (this will replace sensitive words in non-strict fields with *)
     let payload = sanitize_request_payload(&state.sensitive, payload)?;
@@ -365,6 +834,14 @@ pub(crate) async fn import_template(
     state.sensitive.sanitize_json(&mut request_payload);
 
     let mut template = payload.template;
+    let warnings: Vec<GenerationWarning> =
+        strip_oversized_images(&mut template, None, max_image_bytes())
+        .into_iter()
+        .map(|message| GenerationWarning {
+            code: "IMAGE_STRIPPED".to_string(),
+            message,
+        })
+        .collect();
 
     if let Some(theme) = payload
         .theme
@@ -394,17 +871,7 @@ pub(crate) async fn import_template(
         template.meta.language = language.to_string();
     }
 
-    if let Some(genre_list) = payload.genre.as_ref() {
-        let cleaned: Vec<String> = genre_list
-            .iter()
-            .map(|s| s.trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| s.to_string())
-            .collect();
-        if !cleaned.is_empty() {
-            template.meta.genre = cleaned.join(" / ");
-        }
-    }
+    apply_genre_tags(&mut template, sanitize_genre_tags(payload.genre.as_deref()));
 
     if template.characters.is_empty() {
         crate::template::enforce_character_consistency(&mut template, payload.characters.clone());
@@ -425,175 +892,676 @@ pub(crate) async fn import_template(
         template = t;
     }
 
+    if payload.dry_run {
+        // Preview only: nothing is persisted, so there is no request id.
+        return Ok(success_response(GenerateResponse {
+            id: Uuid::nil(),
+            template,
+            warnings,
+        }));
+    }
+
     let id = create_imported_request(
         &state.db,
         &client_ip,
         user_agent,
         request_payload,
         processed_response,
+        &source,
     )
     .await
     .map_err(|e| db_error_response(e).into_response())?;
 
-    Ok(success_response(GenerateResponse { id, template }))
+    Ok(success_response(GenerateResponse {
+        id,
+        template,
+        warnings,
+    }))
 }
 
-pub(crate) async fn share_game(
-    State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    headers: HeaderMap,
-    Json(payload): Json<ShareRequest>,
-) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
-    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+const DEFAULT_GENERATE_SOFT_DEADLINE_SECS: u64 = 200;
+/// Image generation is not started with less than this left before the deadline.
+const MIN_IMAGE_BUDGET: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// Time `/generate` may spend before it stops waiting on images and returns
+/// the text-only template, from `GENERATE_SOFT_DEADLINE_SECS` (default 200s).
+pub(crate) fn generate_soft_deadline() -> std::time::Duration {
+    let secs = std::env::var("GENERATE_SOFT_DEADLINE_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_GENERATE_SOFT_DEADLINE_SECS);
+    std::time::Duration::from_secs(secs)
+}
 
-    let request_info = get_request_owner(&state.db, payload.id)
-        .await
-        .map_err(|e| {
-            eprintln!("Database error: {}", e);
-            db_error_response(DbError::InternalError).into_response()
-        })?;
+/// The request's overall budget: `X-Deadline-Ms` when the client sends a
+/// positive value, capped at `default` so a client can only shorten it.
+pub(crate) fn resolve_request_deadline(
+    header: Option<&str>,
+    default: std::time::Duration,
+) -> std::time::Duration {
+    header
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|ms| *ms > 0)
+        .map(std::time::Duration::from_millis)
+        .map_or(default, |d| d.min(default))
+}
 
-    let Some((owner_ip, status)) = request_info else {
-        return Err(error_response("NOT_FOUND", "Game not found").into_response());
-    };
+/// What is left of the soft deadline for images, or `None` when too little
+/// remains to be worth starting.
+pub(crate) fn image_time_budget(
+    elapsed: std::time::Duration,
+    deadline: std::time::Duration,
+) -> Option<std::time::Duration> {
+    deadline
+        .checked_sub(elapsed)
+        .filter(|remaining| *remaining >= MIN_IMAGE_BUDGET)
+}
 
-    if status != "success" {
-        return Err(
-            error_response("FORBIDDEN", "Game generation not successful, cannot share")
-                .into_response(),
-        );
+/// Runs `step` for at most `budget`; with no budget it is never started.
+/// Returns `None` when skipped or cut off.
+pub(crate) async fn run_within_budget<F: std::future::Future>(
+    budget: Option<std::time::Duration>,
+    step: F,
+) -> Option<F::Output> {
+    tokio::time::timeout(budget?, step).await.ok()
+}
+
+/// Effect of the `quality` preset that is not expressed as a request flag.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct QualityPreset {
+    pub(crate) generate_images: bool,
+    pub(crate) temperature: f64,
+}
+
+/// Applies the `quality` preset (`fast` / `balanced` / `strict`, default
+/// `balanced`) by filling request flags the caller left unset, so explicit
+/// flags always win over the preset. `fast` skips images, node dedup and
+/// orphan-ending rewiring; `strict` adds near-duplicate and same-target
+/// merging, the quick-ending guarantee and a lower temperature. Neither
+/// touches the ending count the prompt asks for.
+pub(crate) fn apply_quality_preset(req: &mut GenerateRequest) -> Result<QualityPreset, String> {
+    let quality = req
+        .quality
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .unwrap_or("balanced");
+
+    match quality {
+        "balanced" => Ok(QualityPreset {
+            generate_images: true,
+            temperature: 1.0,
+        }),
+        "fast" => {
+            req.dedup_nodes.get_or_insert(false);
+            req.orphan_endings
+                .get_or_insert_with(|| OrphanEndingPolicy::Report.as_str().to_string());
+            Ok(QualityPreset {
+                generate_images: false,
+                temperature: 1.0,
+            })
+        }
+        "strict" => {
+            req.near_duplicate_threshold.get_or_insert(0.9);
+            req.merge_same_target_choices.get_or_insert(true);
+            req.quick_ending_level
+                .get_or_insert(DEFAULT_QUICK_ENDING_LEVEL);
+            Ok(QualityPreset {
+                generate_images: true,
+                temperature: 0.7,
+            })
+        }
+        other => Err(format!("不支持的 quality: {}", other)),
     }
+}
 
-    let request_ip = resolve_client_ip(&headers, &addr);
-    let is_owner = is_owner_ip(&owner_ip, &request_ip);
+/// Temperatures for re-asking GLM after its output fails to parse: each
+/// retry runs cooler, nudging the model toward well-formed JSON.
+pub(crate) const PARSE_RETRY_TEMPERATURES: [f64; 2] = [0.7, 0.4];
+
+/// Parse-failure retries per `/generate` call, from `GENERATE_PARSE_RETRIES`
+/// (default and maximum: the length of `PARSE_RETRY_TEMPERATURES`; `0`
+/// disables them).
+pub(crate) fn parse_retry_limit() -> usize {
+    std::env::var("GENERATE_PARSE_RETRIES")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(PARSE_RETRY_TEMPERATURES.len())
+        .min(PARSE_RETRY_TEMPERATURES.len())
+}
 
-    if !is_owner {
-        return Err(
-            error_response("FORBIDDEN", "You are not the owner of this game").into_response(),
+/// Temperature of parse-failure retry `attempt` (1-based) after a first call
+/// at `initial`. Steps not below `initial` are skipped, so a call that
+/// already ran cool only gets the cooler steps.
+pub(crate) fn parse_retry_temperature(initial: f64, attempt: usize) -> Option<f64> {
+    PARSE_RETRY_TEMPERATURES
+        .iter()
+        .copied()
+        .filter(|t| *t < initial)
+        .nth(attempt.checked_sub(1)?)
+}
+
+/// Model output after any parse-failure retries, see `parse_with_cooler_retries`.
+pub(crate) struct ParsedContent {
+    pub(crate) content: String,
+    pub(crate) cleaned: String,
+    pub(crate) result: Result<MovieTemplateLite, serde_json::Error>,
+    /// Temperatures of the retries that were sent, in order.
+    pub(crate) retried_at: Vec<f64>,
+}
+
+/// Parses model output as a template, asking `retry` for new output at the
+/// next cooler temperature (along with the output it replaces) whenever it
+/// is not valid JSON, at most `max_retries` times. A failed retry call stops
+/// retrying and keeps the last output.
+pub(crate) async fn parse_with_cooler_retries<F, Fut>(
+    content: String,
+    initial_temperature: f64,
+    max_retries: usize,
+    mut retry: F,
+) -> ParsedContent
+where
+    F: FnMut(f64, &str) -> Fut,
+    Fut: std::future::Future<Output = Result<String, String>>,
+{
+    let mut content = content;
+    let mut cleaned = clean_json(&content);
+    let mut result = serde_json::from_str::<MovieTemplateLite>(&cleaned);
+    let mut retried_at = Vec::new();
+
+    while let Err(e) = &result {
+        let attempt = retried_at.len() + 1;
+        let Some(temperature) = parse_retry_temperature(initial_temperature, attempt)
+            .filter(|_| attempt <= max_retries)
+        else {
+            break;
+        };
+        println!(
+            "Model output is not valid JSON ({}); retry {} at temperature {}",
+            e, attempt, temperature
         );
+        retried_at.push(temperature);
+        match retry(temperature, &content).await {
+            Ok(next) => {
+                content = next;
+                cleaned = clean_json(&content);
+                result = serde_json::from_str::<MovieTemplateLite>(&cleaned);
+            }
+            Err(err) => {
+                eprintln!("Parse-failure retry failed: {}", err);
+                break;
+            }
+        }
     }
 
-    let shared_record_id = if payload.shared {
-        let ua = headers
-            .get("user-agent")
-            .and_then(|h| h.to_str().ok())
-            .filter(|s| !s.trim().is_empty());
+    ParsedContent {
+        content,
+        cleaned,
+        result,
+        retried_at,
+    }
+}
 
-        let _id = upsert_shared_record(&state.db, payload.id, &request_ip, ua)
+/// Route each `/generate` parse-failure retry is logged under, so retries
+/// show up in `glm_requests` without spending the client's `/generate` limits.
+pub(crate) const GENERATE_RETRY_ROUTE: &str = "/generate/retry";
+
+/// Re-sends a chat request at `temperature` and returns the model content.
+pub(crate) async fn request_glm_content(
+    client: &reqwest::Client,
+    endpoint: &str,
+    api_key: &str,
+    request_body: &serde_json::Value,
+    temperature: f64,
+    budget: Option<std::time::Duration>,
+) -> Result<String, String> {
+    let mut body = request_body.clone();
+    body["temperature"] = json!(temperature);
+    // The budget covers reading the body too, not just the response headers.
+    let call = async {
+        let response = client
+            .post(endpoint)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .json(&body)
+            .send()
             .await
-            .map_err(|e| db_error_response(e).into_response())?;
-
-        // Return request_id as the identifier for the client to store
-        Some(payload.id)
-    } else {
-        // Just return the request_id
-        Some(payload.id)
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("GLM returned {}", response.status()));
+        }
+        response
+            .json::<serde_json::Value>()
+            .await
+            .map_err(|e| e.to_string())
     };
-
-    set_share_status(&state.db, payload.id, payload.shared)
+    let value = run_within_budget(budget, call)
         .await
-        .map_err(|e| {
-            eprintln!("Database error: {}", e);
-            db_error_response(DbError::InternalError).into_response()
-        })?;
+        .ok_or("deadline exceeded")??;
+    glm::message_content_text(&value["choices"][0]["message"]["content"])
+        .ok_or_else(|| "Invalid GLM response structure".to_string())
+}
 
-    Ok(success_response(json!({
-        "sharedRecordId": shared_record_id // Use the same key to minimize frontend breakage, but value is request_id now
-    })))
+/// Most endings a generated template keeps: `MAX_ENDINGS`, raised to
+/// `exact_endings` when that asks for more.
+fn generated_endings_cap(payload: &GenerateRequest) -> usize {
+    let default_cap = max_endings_cap();
+    payload
+        .exact_endings
+        .map_or(default_cap, |n| (n as usize).max(default_cap))
 }
 
-pub(crate) async fn update_template(
-    State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    headers: HeaderMap,
-    Json(payload): Json<UpdateTemplateRequest>,
-) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
-    if payload.template.title.chars().count() > 20 {
-        return Err(error_response(CODE_BAD_REQUEST, "标题长度不能超过 20 字").into_response());
+/// Parameters a `/generate` call actually ran with once env defaults, the
+/// quality preset and clamping are applied; stored as
+/// `glm_requests.effective_params` so a logged request can be reproduced.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EffectiveParams {
+    pub(crate) model: String,
+    /// Host of the chat endpoint; `None` when `baseUrl` is invalid.
+    pub(crate) endpoint_host: Option<String>,
+    pub(crate) language: String,
+    pub(crate) temperature: f64,
+    pub(crate) max_tokens: u32,
+    pub(crate) generate_images: bool,
+    pub(crate) min_nodes: u32,
+    pub(crate) max_nodes: u32,
+    pub(crate) min_endings: u32,
+    pub(crate) max_endings: u32,
+    pub(crate) endings_cap: usize,
+    pub(crate) quick_ending_level: u32,
+    pub(crate) min_path_depth: u32,
+    pub(crate) normalize_ids: bool,
+    pub(crate) enforce_endings: bool,
+    pub(crate) break_cycles: bool,
+    pub(crate) dedup_nodes: bool,
+    pub(crate) near_duplicate_threshold: Option<f64>,
+    pub(crate) merge_same_target_choices: bool,
+    pub(crate) orphan_endings: &'static str,
+}
+
+pub(crate) fn build_effective_params(
+    payload: &GenerateRequest,
+    model: &str,
+    quality: &QualityPreset,
+) -> EffectiveParams {
+    let endpoint_host = resolve_glm_endpoint(payload.base_url.as_deref())
+        .ok()
+        .and_then(|endpoint| Url::parse(&endpoint).ok())
+        .and_then(|url| url.host_str().map(str::to_string));
+    let (min_nodes, max_nodes) = PROMPT_NODE_RANGE;
+    let (min_endings, max_endings) = prompt_endings_range(payload);
+    let repair = graph_repair_options(payload);
+    EffectiveParams {
+        model: model.to_string(),
+        endpoint_host,
+        language: payload
+            .language
+            .clone()
+            .unwrap_or_else(|| "zh-CN".to_string()),
+        temperature: quality.temperature,
+        max_tokens: glm::resolve_max_tokens(model, payload.max_tokens),
+        generate_images: quality.generate_images,
+        min_nodes,
+        max_nodes,
+        min_endings,
+        max_endings,
+        endings_cap: generated_endings_cap(payload),
+        quick_ending_level: payload
+            .quick_ending_level
+            .unwrap_or(DEFAULT_QUICK_ENDING_LEVEL),
+        min_path_depth: payload.min_path_depth.unwrap_or(DEFAULT_MIN_PATH_DEPTH),
+        normalize_ids: payload.normalize_ids.unwrap_or(true),
+        enforce_endings: payload.enforce_endings.unwrap_or(true),
+        break_cycles: repair.break_cycles,
+        dedup_nodes: repair.dedup_nodes,
+        near_duplicate_threshold: repair.near_duplicate_threshold,
+        merge_same_target_choices: repair.merge_same_target_choices,
+        orphan_endings: orphan_ending_policy(payload).as_str(),
     }
-    ensure_not_sensitive(&state.sensitive, &payload.template.title, "标题", &payload)?;
+}
 
-    // Validate base64 image size
-    if let Some(bg) = &payload.template.background_image_base64 {
-        if bg.len() > 400_000 {
-            return Err(error_response(CODE_BAD_REQUEST, "背景图片过大 (超过 300KB)").into_response());
-        }
+/// Unknown values are rejected by `generate` before this is read.
+fn orphan_ending_policy(req: &GenerateRequest) -> OrphanEndingPolicy {
+    OrphanEndingPolicy::parse(req.orphan_endings.as_deref()).unwrap_or(OrphanEndingPolicy::Link)
+}
+
+/// Graph-repair passes requested by a generate call; omitted flags stay on,
+/// except cycle breaking, which `allow_cycles` turns off by default.
+pub(crate) fn graph_repair_options(req: &GenerateRequest) -> GraphRepairOptions {
+    GraphRepairOptions {
+        dedup_nodes: req.dedup_nodes.unwrap_or(true),
+        break_cycles: req
+            .break_cycles
+            .unwrap_or(!req.allow_cycles.unwrap_or(false)),
+        near_duplicate_threshold: req.near_duplicate_threshold,
+        merge_same_target_choices: req.merge_same_target_choices.unwrap_or(false),
     }
-    for char in payload.template.characters.values() {
-        if let Some(avatar) = &char.avatar_path {
-            if avatar.len() > 400_000 {
-                return Err(error_response(CODE_BAD_REQUEST, format!("角色 {} 头像过大 (超过 300KB)", char.name)).into_response());
-            }
-        }
+}
+
+/// Text-only passes that turn a freshly converted `/generate` template into
+/// the stored one: ID and ending normalization, the request's characters and
+/// uploaded avatars, graph repair, node kinds, genre tags. Images are not
+/// touched. Returns the warnings these passes raise.
+pub(crate) fn finish_generated_template(
+    template: &mut MovieTemplate,
+    payload: &GenerateRequest,
+) -> Vec<GenerationWarning> {
+    let mut warnings = Vec::new();
+    let endings_cap = generated_endings_cap(payload);
+    let normalize_ids = payload.normalize_ids.unwrap_or(true);
+    let enforce_endings = payload.enforce_endings.unwrap_or(true);
+    let repair_options = graph_repair_options(payload);
+    if normalize_ids {
+        normalize_character_ids(template);
+        normalize_template_nodes(template);
+    }
+    if enforce_endings {
+        normalize_template_endings_with_cap(template, endings_cap);
     }
 
-    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+    // Only ensure minimum graph if GLM returned nothing - never overwrite GLM's data
+    // ensure_minimum_game_graph call removed to prevent write-dead data injection
 
-    let request_info = get_request_owner(&state.db, payload.id)
-        .await
-        .map_err(|e| {
-            eprintln!("Database error: {}", e);
-            db_error_response(DbError::InternalError).into_response()
-        })?;
+    // NO character modifications - preserve GLM's original output
+    // ensure_request_characters_present(template, &payload);
 
-    let Some((owner_ip, status)) = request_info else {
-        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    // User insisted: "Must return character info passed by frontend exactly as is"
+    crate::template::enforce_character_consistency(template, payload.characters.clone());
+    let supplied = payload.characters.as_ref();
+    let avatar_cap = if payload.stored_avatars {
+        usize::MAX
+    } else {
+        max_image_bytes()
     };
+    for message in attach_supplied_avatars(template, supplied, avatar_cap) {
+        warnings.push(GenerationWarning {
+            code: "AVATAR_REJECTED".to_string(),
+            message,
+        });
+    }
 
-    if status != "success" {
-        return Err(
-            error_response("FORBIDDEN", "Game generation not successful, cannot update")
-                .into_response(),
-        );
+    if normalize_ids {
+        normalize_character_ids(template);
+    }
+    if enforce_endings {
+        normalize_template_endings_with_cap(template, endings_cap);
+    }
+    // Endings added from here on are the pipeline's own, not orphans to fix.
+    let authored_endings: std::collections::HashSet<String> =
+        template.endings.keys().cloned().collect();
+    if enforce_endings {
+        if let Some(n) = payload.exact_endings {
+            enforce_exact_endings(template, n as usize);
+        }
+    }
+    template.provenance.allow_cycles = !repair_options.break_cycles;
+    let cycles_before = analyze_graph(template).cycles;
+    sanitize_template_graph_with(template, repair_options);
+    let broken = cycles_before.saturating_sub(analyze_graph(template).cycles);
+    if broken > 0 {
+        warnings.push(GenerationWarning {
+            code: "CYCLES_BROKEN".to_string(),
+            message: format!("检测到 {} 处循环跳转，已改为指向结局", broken),
+        });
+    }
+    if repair_options.break_cycles {
+        for (from, to) in redirect_backward_choices(template) {
+            warnings.push(GenerationWarning {
+                code: "BACKWARD_CHOICE_REDIRECTED".to_string(),
+                message: format!(
+                    "节点 {} 指向了编号不更大的节点 {}，已改为指向结局",
+                    from, to
+                ),
+            });
+        }
+    }
+    let level_cap = max_nodes_per_level();
+    for (from, into) in enforce_level_cap(template, level_cap) {
+        warnings.push(GenerationWarning {
+            code: "LEVEL_NODES_MERGED".to_string(),
+            message: format!(
+                "同一层级节点超过 {} 个，节点 {} 已合并到节点 {}",
+                level_cap, from, into
+            ),
+        });
     }
+    let quick_level = payload
+        .quick_ending_level
+        .unwrap_or(DEFAULT_QUICK_ENDING_LEVEL);
+    // The graph is only rewired for a quick ending the request asked for;
+    // the default level still exempts early exits from the depth check.
+    if payload.quick_ending_level.is_some() {
+        enforce_quick_ending(template, quick_level);
+    }
+    let synthesized_endings = endings_added_since(template, &authored_endings);
+    for orphan in reconcile_orphan_endings(
+        template,
+        orphan_ending_policy(payload),
+        &synthesized_endings,
+    ) {
+        warnings.push(orphan_ending_warning(&orphan));
+    }
+    let min_depth = payload.min_path_depth.unwrap_or(DEFAULT_MIN_PATH_DEPTH);
+    if payload.pad_short_paths.unwrap_or(false) {
+        for path in pad_short_paths(template, min_depth, quick_level) {
+            warnings.push(GenerationWarning {
+                code: "SHORT_PATH_PADDED".to_string(),
+                message: format!(
+                    "结局 {} 最短只需经过 {} 个节点，已插入过渡节点补足到 {} 个",
+                    path.ending, path.depth, min_depth
+                ),
+            });
+        }
+    } else {
+        for path in find_short_paths(template, min_depth, quick_level) {
+            warnings.push(GenerationWarning {
+                code: "SHORT_PATH".to_string(),
+                message: format!(
+                    "结局 {} 最短只需经过 {} 个节点，少于要求的 {} 个",
+                    path.ending, path.depth, min_depth
+                ),
+            });
+        }
+    }
+    let unreachable = analyze_graph(template).unreachable_nodes;
+    if unreachable > 0 {
+        warnings.push(GenerationWarning {
+            code: "UNREACHABLE_NODES".to_string(),
+            message: format!("{} 个节点无法从开始节点到达", unreachable),
+        });
+    }
+    sanitize_affinity_effects(template);
+    apply_genre_tags(template, sanitize_genre_tags(payload.genre.as_deref()));
+    if payload.strip_markdown.unwrap_or(false) {
+        strip_template_markdown(template);
+    }
+    warnings
+}
 
-    let request_ip = resolve_client_ip(&headers, &addr);
-    let is_owner = is_owner_ip(&owner_ip, &request_ip);
+/// Endings the repair passes synthesized after `before` was taken.
+fn endings_added_since(
+    template: &MovieTemplate,
+    before: &std::collections::HashSet<String>,
+) -> std::collections::HashSet<String> {
+    template
+        .endings
+        .keys()
+        .filter(|k| !before.contains(*k))
+        .cloned()
+        .collect()
+}
 
-    if !is_owner {
-        return Err(
-            error_response("FORBIDDEN", "You are not the owner of this game").into_response(),
-        );
+fn orphan_ending_warning(orphan: &OrphanEnding) -> GenerationWarning {
+    match &orphan.action {
+        OrphanAction::Linked(node) => GenerationWarning {
+            code: "ORPHAN_ENDING_LINKED".to_string(),
+            message: format!(
+                "结局 {} 无法从开始节点到达，已让节点 {} 的选项指向它",
+                orphan.ending, node
+            ),
+        },
+        OrphanAction::Pruned => GenerationWarning {
+            code: "ORPHAN_ENDING_PRUNED".to_string(),
+            message: format!("结局 {} 无法从开始节点到达，已移除", orphan.ending),
+        },
+        OrphanAction::Reported => GenerationWarning {
+            code: "ORPHAN_ENDING_UNREACHABLE".to_string(),
+            message: format!("结局 {} 无法从开始节点到达", orphan.ending),
+        },
     }
+}
 
-    let mut template = payload.template;
+/// Rejects templates whose node count would make graph repair too costly.
+pub(crate) fn check_node_limit(
+    node_count: usize,
+    limit: usize,
+) -> Result<(), (&'static str, String)> {
+    if node_count > limit {
+        return Err((
+            CODE_BAD_REQUEST,
+            format!("节点数量 {} 超过上限 {}", node_count, limit),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads an uploaded template in either the full or the lite shape.
+fn parse_uploaded_template(
+    raw: serde_json::Value,
+    language: Option<&str>,
+) -> Result<crate::types::MovieTemplate, String> {
+    let template = match serde_json::from_value::<crate::types::MovieTemplate>(raw.clone()) {
+        Ok(t) => t,
+        Err(_) => {
+            let lite: MovieTemplateLite =
+                serde_json::from_value(raw).map_err(|e| format!("模板结构无效: {}", e))?;
+            convert_lite_to_full(lite, language.unwrap_or("zh-CN"))
+        }
+    };
+    check_node_limit(template.nodes.len(), max_nodes_hard_limit()).map_err(|(_, msg)| msg)?;
+    Ok(template)
+}
+
+/// Runs the post-generation repair pipeline on a loose or full template:
+/// lite conversion when needed, key/ending normalization, graph cleanup and
+/// backward-edge redirection. Backward redirects and unreachable endings
+/// (handled per `orphan_policy`) are reported as warnings.
+pub(crate) fn repair_template(
+    raw: serde_json::Value,
+    language: Option<&str>,
+    orphan_policy: OrphanEndingPolicy,
+) -> Result<(crate::types::MovieTemplate, Vec<GenerationWarning>), String> {
+    let template = parse_uploaded_template(raw, language)?;
+    Ok(repair_parsed_template(template, orphan_policy))
+}
+
+/// `orphanEndings` of `/sanitize` and `/template/autofix`: user-authored
+/// templates are only reported on unless the caller opts in.
+fn sanitize_orphan_policy(value: Option<&str>) -> Result<OrphanEndingPolicy, Response> {
+    match value {
+        None => Ok(OrphanEndingPolicy::Report),
+        Some(v) => OrphanEndingPolicy::parse(Some(v)).ok_or_else(|| {
+            error_response(
+                CODE_BAD_REQUEST,
+                "orphanEndings 只能是 link、prune 或 report",
+            )
+            .into_response()
+        }),
+    }
+}
 
+fn repair_parsed_template(
+    mut template: crate::types::MovieTemplate,
+    orphan_policy: OrphanEndingPolicy,
+) -> (crate::types::MovieTemplate, Vec<GenerationWarning>) {
     normalize_character_ids(&mut template);
+    normalize_template_nodes(&mut template);
     normalize_template_endings(&mut template);
+    let authored_endings: std::collections::HashSet<String> =
+        template.endings.keys().cloned().collect();
     sanitize_template_graph(&mut template);
-    normalize_template_nodes(&mut template);
-    sanitize_affinity_effects(&mut template);
 
-    ensure_avatar_fallbacks(&mut template, None);
+    let backward = if template.provenance.allow_cycles {
+        Vec::new()
+    } else {
+        redirect_backward_choices(&mut template)
+    };
+    let mut warnings: Vec<GenerationWarning> = backward
+        .into_iter()
+        .map(|(from, to)| GenerationWarning {
+            code: "BACKWARD_CHOICE_REDIRECTED".to_string(),
+            message: format!(
+                "节点 {} 指向了编号不更大的节点 {}，已改为指向结局",
+                from, to
+            ),
+        })
+        .collect();
+    let synthesized_endings = endings_added_since(&template, &authored_endings);
+    for orphan in reconcile_orphan_endings(&mut template, orphan_policy, &synthesized_endings) {
+        warnings.push(orphan_ending_warning(&orphan));
+    }
 
-    let mut template_value = serde_json::to_value(&template).unwrap_or(json!({}));
-    template_value = sanitize_json_value(&state.sensitive, template_value);
+    (template, warnings)
+}
 
-    save_processed_response(&state.db, payload.id, &template_value)
-        .await
-        .map_err(|e| {
-            eprintln!("Database error: {}", e);
-            db_error_response(DbError::InternalError).into_response()
-        })?;
+/// Runs the `/sanitize` pipeline and reports graph metrics on both sides of it.
+pub(crate) fn autofix_template_value(
+    raw: serde_json::Value,
+    language: Option<&str>,
+    orphan_policy: OrphanEndingPolicy,
+) -> Result<AutofixTemplateResponse, String> {
+    let template = parse_uploaded_template(raw, language)?;
+    let before = analyze_graph(&template);
+    let (fixed_template, warnings) = repair_parsed_template(template, orphan_policy);
+    let after = analyze_graph(&fixed_template);
+    Ok(AutofixTemplateResponse {
+        fixed_template,
+        before,
+        after,
+        warnings,
+    })
+}
 
-    if payload
-        .source
-        .as_deref()
-        .is_some_and(|s| s.trim().eq_ignore_ascii_case("import"))
-    {
-        set_request_template_source(&state.db, payload.id, "import")
-            .await
-            .map_err(|e| db_error_response(e).into_response())?;
-    }
+pub(crate) async fn sanitize_template(
+    State(state): State<AppState>,
+    Json(payload): Json<SanitizeTemplateRequest>,
+) -> Result<Json<ApiResponse<SanitizeTemplateResponse>>, Response> {
+    let orphan_policy = sanitize_orphan_policy(payload.orphan_endings.as_deref())?;
+    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+    let (template, warnings) = repair_template(
+        payload.template,
+        payload.language.as_deref(),
+        orphan_policy,
+    )
+    .map_err(|e| error_response(CODE_BAD_REQUEST, e).into_response())?;
 
-    Ok(success_response(template_value))
+    Ok(success_response(SanitizeTemplateResponse {
+        template,
+        warnings,
+    }))
 }
 
-pub(crate) async fn delete_template(
+pub(crate) async fn autofix_template(
+    State(state): State<AppState>,
+    Json(payload): Json<SanitizeTemplateRequest>,
+) -> Result<Json<ApiResponse<AutofixTemplateResponse>>, Response> {
+    let orphan_policy = sanitize_orphan_policy(payload.orphan_endings.as_deref())?;
+    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+    let response = autofix_template_value(
+        payload.template,
+        payload.language.as_deref(),
+        orphan_policy,
+    )
+    .map_err(|e| error_response(CODE_BAD_REQUEST, e).into_response())?;
+    Ok(success_response(response))
+}
+
+pub(crate) async fn share_game(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-    Json(payload): Json<DeleteTemplateRequest>,
+    Json(payload): Json<ShareRequest>,
 ) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
     let payload = sanitize_request_payload(&state.sensitive, payload)?;
 
@@ -604,10 +1572,17 @@ pub(crate) async fn delete_template(
             db_error_response(DbError::InternalError).into_response()
         })?;
 
-    let Some((owner_ip, _status)) = request_info else {
+    let Some((owner_ip, status)) = request_info else {
         return Err(error_response("NOT_FOUND", "Game not found").into_response());
     };
 
+    if status != "success" {
+        return Err(
+            error_response("FORBIDDEN", "Game generation not successful, cannot share")
+                .into_response(),
+        );
+    }
+
     let request_ip = resolve_client_ip(&headers, &addr);
     let is_owner = is_owner_ip(&owner_ip, &request_ip);
 
@@ -617,7 +1592,24 @@ pub(crate) async fn delete_template(
         );
     }
 
-    delete_game_by_request_id(&state.db, payload.id)
+    let shared_record_id = if payload.shared {
+        let ua = headers
+            .get("user-agent")
+            .and_then(|h| h.to_str().ok())
+            .filter(|s| !s.trim().is_empty());
+
+        let _id = upsert_shared_record(&state.db, payload.id, &request_ip, ua)
+            .await
+            .map_err(|e| db_error_response(e).into_response())?;
+
+        // Return request_id as the identifier for the client to store
+        Some(payload.id)
+    } else {
+        // Just return the request_id
+        Some(payload.id)
+    };
+
+    set_share_status(&state.db, payload.id, payload.shared)
         .await
         .map_err(|e| {
             eprintln!("Database error: {}", e);
@@ -625,363 +1617,292 @@ pub(crate) async fn delete_template(
         })?;
 
     Ok(success_response(json!({
-        "deleted": true
+        "sharedRecordId": shared_record_id // Use the same key to minimize frontend breakage, but value is request_id now
     })))
 }
 
-pub(crate) async fn get_shared_game(
+pub(crate) async fn update_template(
     State(state): State<AppState>,
-    Path(id): Path<Uuid>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
-    let row = crate::db::get_game_for_play(&state.db, id)
+    Json(payload): Json<UpdateTemplateRequest>,
+) -> Result<Json<ApiResponse<UpdateTemplateResponse>>, Response> {
+    if count_display_chars(&payload.template.title) > 20 {
+        return Err(error_response(CODE_BAD_REQUEST, "标题长度不能超过 20 字").into_response());
+    }
+    ensure_not_sensitive(&state.sensitive, &payload.template.title, "标题", &payload)?;
+    let source = resolve_request_source(payload.source.as_deref(), "/template/update")
+        .map_err(|e| error_response(CODE_BAD_REQUEST, e).into_response())?;
+
+    check_node_limit(payload.template.nodes.len(), max_nodes_hard_limit())
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+
+    let request_info = get_request_owner(&state.db, payload.id)
         .await
         .map_err(|e| {
             eprintln!("Database error: {}", e);
             db_error_response(DbError::InternalError).into_response()
         })?;
 
-    let Some((data, shared, owner_ip)) = row else {
+    let Some((owner_ip, status)) = request_info else {
         return Err(error_response("NOT_FOUND", "Game not found").into_response());
     };
 
+    if status != "success" {
+        return Err(
+            error_response("FORBIDDEN", "Game generation not successful, cannot update")
+                .into_response(),
+        );
+    }
+
     let request_ip = resolve_client_ip(&headers, &addr);
     let is_owner = is_owner_ip(&owner_ip, &request_ip);
 
-    if !shared && !is_owner {
-        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    if !is_owner {
+        return Err(
+            error_response("FORBIDDEN", "You are not the owner of this game").into_response(),
+        );
     }
 
-    // 2. Record visit (async, fire and forget)
-    let db = state.db.clone();
-    let client_ip = resolve_client_ip(&headers, &addr);
-    let user_agent = headers
-        .get("user-agent")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("")
-        .to_string();
-    let referer = headers
-        .get("referer")
-        .and_then(|h| h.to_str().ok())
-        .map(|s| s.to_string());
+    let stored = crate::db::get_game_for_play(&state.db, payload.id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?
+        .and_then(|(data, _, _)| serde_json::from_value::<MovieTemplate>(data).ok());
 
-    tokio::spawn(async move {
-        if let Err(e) = record_visit(&db, id, &client_ip, &user_agent, referer.as_deref()).await {
-            eprintln!("Failed to record visit: {}", e);
-        }
-    });
+    let mut template = payload.template;
+    // Set at generation time; a client dropping it must not break the loops.
+    if let Some(stored) = &stored {
+        template.provenance.allow_cycles = stored.provenance.allow_cycles;
+    }
+    let warnings: Vec<GenerationWarning> =
+        strip_oversized_images(&mut template, stored.as_ref(), max_image_bytes())
+        .into_iter()
+        .map(|message| GenerationWarning {
+            code: "IMAGE_STRIPPED".to_string(),
+            message,
+        })
+        .collect();
 
-    // Remove filtering on game data as per user request
-    Ok(success_response(data))
-}
+    normalize_character_ids(&mut template);
+    normalize_template_endings(&mut template);
+    sanitize_template_graph(&mut template);
+    normalize_template_nodes(&mut template);
+    sanitize_affinity_effects(&mut template);
+    touch_provenance(&mut template);
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct SharedRecordListItem {
-    request_id: Uuid,
-    title: String,
-    shared_at: String,
-    shared: bool,
-    synopsis: String,
-    genre: String,
-    language: String,
-    play_count: i64,
-}
+    ensure_avatar_fallbacks(&mut template, None);
 
-pub(crate) async fn get_shared_record_meta(
-    State(state): State<AppState>,
-    Path(request_id): Path<Uuid>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    headers: HeaderMap,
-) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
-    let request_ip = resolve_client_ip(&headers, &addr);
+    let mut template_value = serde_json::to_value(&template).unwrap_or(json!({}));
+    template_value = sanitize_json_value(&state.sensitive, template_value);
 
-    let meta = get_shared_record_meta_by_request_id(&state.db, request_id)
+    save_processed_response(&state.db, payload.id, &template_value)
         .await
         .map_err(|e| {
             eprintln!("Database error: {}", e);
             db_error_response(DbError::InternalError).into_response()
         })?;
 
-    let Some((shared, shared_at, owner_ip)) = meta else {
-        return Err(error_response("NOT_FOUND", "Record not found").into_response());
-    };
-
-    let is_owner = is_owner_ip(&owner_ip, &request_ip);
+    if payload
+        .source
+        .as_deref()
+        .is_some_and(|s| s.trim().eq_ignore_ascii_case("import"))
+    {
+        set_request_template_source(&state.db, payload.id, "import")
+            .await
+            .map_err(|e| db_error_response(e).into_response())?;
+    }
+    set_request_source(&state.db, payload.id, &source)
+        .await
+        .map_err(|e| db_error_response(e).into_response())?;
 
-    Ok(success_response(json!({
-        // "sharedRecordId": ... REMOVED per security requirement
-        "requestId": request_id,
-        "shared": shared,
-        "sharedAt": shared_at.map(|v| json!(v)).unwrap_or(serde_json::Value::Null),
-        "isOwner": is_owner
-    })))
+    Ok(success_response(UpdateTemplateResponse {
+        template: template_value,
+        warnings,
+    }))
 }
 
-pub(crate) async fn list_records(
+pub(crate) async fn renumber_template(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-    Json(payload): Json<RecordsListRequest>,
-) -> Result<Json<ApiResponse<Vec<SharedRecordListItem>>>, Response> {
+    Json(payload): Json<RenumberTemplateRequest>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
     let payload = sanitize_request_payload(&state.sensitive, payload)?;
 
-    let owner_ip = resolve_client_ip(&headers, &addr);
+    let row = crate::db::get_game_for_play(&state.db, payload.id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
 
-    if payload.ids.is_empty() {
-        return Ok(success_response(Vec::<SharedRecordListItem>::new()));
-    }
+    let Some((data, _shared, owner_ip)) = row else {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    };
 
-    if payload.ids.len() > 200 {
-        return Err(error_response(CODE_BAD_REQUEST, "Too many ids").into_response());
+    let request_ip = resolve_client_ip(&headers, &addr);
+    if !is_owner_ip(&owner_ip, &request_ip) {
+        return Err(
+            error_response("FORBIDDEN", "You are not the owner of this game").into_response(),
+        );
     }
 
-    // payload.ids are now treated as request_ids
-    let rows = crate::db::list_shared_records_by_request_ids(&state.db, &payload.ids, &owner_ip)
+    let mut template: crate::types::MovieTemplate = serde_json::from_value(data).map_err(|e| {
+        eprintln!("Stored template is invalid: {}", e);
+        error_response(CODE_INTERNAL_ERROR, "Stored template is invalid").into_response()
+    })?;
+
+    let mapping = renumber_nodes_topologically(&mut template);
+    normalize_template_endings(&mut template);
+    touch_provenance(&mut template);
+
+    let template_value = serde_json::to_value(&template).unwrap_or(json!({}));
+
+    save_processed_response(&state.db, payload.id, &template_value)
         .await
         .map_err(|e| {
             eprintln!("Database error: {}", e);
             db_error_response(DbError::InternalError).into_response()
         })?;
+    set_request_source(&state.db, payload.id, "/template/renumber")
+        .await
+        .map_err(|e| db_error_response(e).into_response())?;
 
-    let mut items = rows
-        .into_iter()
-        .map(
-            |(request_id, shared_at, shared, title, synopsis, genre, language, play_count)| {
-                SharedRecordListItem {
-                    request_id,
-                    title: title.unwrap_or_else(|| "Untitled".to_string()),
-                    shared_at,
-                    shared,
-                    synopsis: synopsis.unwrap_or_default(),
-                    genre: genre.unwrap_or_default(),
-                    language: language.unwrap_or_default(),
-                    play_count,
-                }
-            },
-        )
-        .collect::<Vec<_>>();
-
-    for item in items.iter_mut() {
-        item.title = sanitize_text(&state.sensitive, &item.title);
-        item.synopsis = sanitize_text(&state.sensitive, &item.synopsis);
-        item.genre = sanitize_text(&state.sensitive, &item.genre);
-        item.language = sanitize_text(&state.sensitive, &item.language);
-    }
-
-    Ok(success_response(items))
+    Ok(success_response(json!({
+        "template": template_value,
+        "mapping": mapping
+    })))
 }
 
-pub(crate) async fn generate(
+pub(crate) async fn continue_generation(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-    Json(payload): Json<GenerateRequest>,
+    Json(payload): Json<ContinueGenerationRequest>,
 ) -> Result<Response, Response> {
-    if let Some(theme) = &payload.theme {
-        ensure_not_sensitive(&state.sensitive, theme, "主题", &payload)?;
+    let max_nodes = payload.max_nodes.unwrap_or(1);
+    if !(1..=MAX_CONTINUE_NODES).contains(&max_nodes) {
+        return Err(error_response(
+            CODE_BAD_REQUEST,
+            format!("maxNodes 必须在 1 到 {} 之间", MAX_CONTINUE_NODES),
+        )
+        .into_response());
     }
-    // Check free_input as well if it acts as theme
-    if let Some(free_input) = &payload.free_input {
-         ensure_not_sensitive(&state.sensitive, free_input, "自由输入", &payload)?;
+    if payload.instruction.trim().is_empty() {
+        return Err(error_response(CODE_BAD_REQUEST, "instruction 不能为空").into_response());
+    }
+    if count_display_chars(&payload.instruction) > 100 {
+        return Err(
+            error_response(CODE_BAD_REQUEST, "instruction 长度不能超过 100 字").into_response(),
+        );
+    }
+    check_node_limit(payload.template.nodes.len(), max_nodes_hard_limit())
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+    if !payload.template.nodes.contains_key(&payload.from_node_id) {
+        return Err(error_response("NOT_FOUND", "Node not found").into_response());
     }
 
-    let payload = sanitize_request_payload(&state.sensitive, payload)?;
-
+    let mut payload = sanitize_request_payload(&state.sensitive, payload)?;
     let client_ip = resolve_client_ip(&headers, &addr);
+    let trusted = take_trusted_key(&state.trusted, &client_ip, &mut payload.api_key);
+
+    if let Some(id) = payload.id {
+        let request_info = get_request_owner(&state.db, id).await.map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::from_sqlx(e)).into_response()
+        })?;
+        let Some((owner_ip, status)) = request_info else {
+            return Err(error_response("NOT_FOUND", "Game not found").into_response());
+        };
+        if status != "success" {
+            return Err(error_response(
+                "FORBIDDEN",
+                "Game generation not successful, cannot update",
+            )
+            .into_response());
+        }
+        if !is_owner_ip(&owner_ip, &client_ip) {
+            return Err(
+                error_response("FORBIDDEN", "You are not the owner of this game").into_response(),
+            );
+        }
+    }
+
+    resolve_glm_endpoint(payload.base_url.as_deref())
+        .map_err(|_| error_response(CODE_INVALID_BASE_URL, "Invalid baseUrl").into_response())?;
+    resolve_glm_api_key(payload.api_key.as_deref())
+        .map_err(|_| error_response("API_KEY_REQUIRED", "API Key is required").into_response())?;
 
     let user_agent = headers
         .get(axum::http::header::USER_AGENT)
         .and_then(|v| v.to_str().ok())
         .unwrap_or("unknown");
 
-    let theme = payload
-        .theme
-        .as_deref()
-        .or(payload.free_input.as_deref())
-        .unwrap_or("Unknown Theme");
-    println!(
-        "Received generate request: {:?}",
-        sanitize_text(&state.sensitive, theme)
-    );
-
-    let prompt = construct_prompt(&payload);
-    println!("Prompt constructed.");
-
-    let using_override_key = payload
-        .api_key
-        .as_ref()
-        .is_some_and(|k| !k.trim().is_empty());
-
-    let model = if using_override_key {
-        payload.model.as_deref().unwrap_or("glm-4.6v-flash")
-    } else {
-        "glm-4.6v-flash"
-    };
-
-    let client = reqwest::Client::builder()
-        .timeout(std::time::Duration::from_secs(240))
-        .build()
-        .map_err(|e| error_response(CODE_INTERNAL_ERROR, e.to_string()).into_response())?;
-
-    let mut messages = vec![];
-    messages.push(json!({
-        "role": "system",
-        "content": "You are a professional interactive movie scriptwriter and game designer. You output ONLY valid JSON. You never output markdown code blocks. You strictly follow the provided TypeScript interface definitions."
-    }));
-
-    messages.push(json!({
-        "role": "user",
-        "content": prompt
-    }));
-
-    let request_body = json!({
-        "model": model,
-        "messages": messages,
-        "response_format": { "type": "json_object" },
-        "temperature": 1,
-        "top_p": 0.95,
-        "max_tokens": 8192
-    });
-
-    println!(
-        "Sending request to GLM (Prompt len: {})...",
-        request_body["messages"][1]["content"]
-            .as_str()
-            .unwrap_or("")
-            .len()
+    let prompt = construct_continue_prompt(
+        &payload.template,
+        &payload.from_node_id,
+        &payload.instruction,
+        max_nodes,
     );
-    let start = std::time::Instant::now();
-
     let using_override_key = payload
         .api_key
         .as_ref()
         .is_some_and(|k| !k.trim().is_empty());
-
     let mut payload_json = serde_json::to_value(&payload).unwrap_or(json!({}));
     if let Some(obj) = payload_json.as_object_mut() {
         obj.remove("apiKey");
+        // The prompt already carries the relevant context; skip the full graph.
+        obj.remove("template");
     }
     state.sensitive.sanitize_json(&mut payload_json);
+    let prompt_for_log = sanitize_text(&state.sensitive, &prompt);
+
+    if !state.burst_limiter.check(&client_ip) {
+        return Err(rate_limit_response("请求过于频繁，请稍后再试").into_response());
+    }
 
-    let prompt_for_log = sanitize_text(
-        &state.sensitive,
-        request_body["messages"][1]["content"]
-            .as_str()
-            .unwrap_or(""),
-    );
     let request_id = begin_glm_request_log(
         &state.db,
         &client_ip,
         user_agent,
-        "/generate",
+        "/generate/continue",
         payload_json,
         &prompt_for_log,
-        using_override_key,
+        QuotaCheck::for_request(using_override_key, trusted),
     )
     .await
     .map_err(|e| db_error_response(e).into_response())?;
 
     let db = state.db.clone();
     let sensitive = state.sensitive.clone();
-    let payload_clone = payload.clone();
+    let model = effective_model(
+        payload.model.as_deref(),
+        using_override_key,
+        MODEL_GENERATE_ENV,
+    );
+    let served_model = model.clone();
 
-    // Spawn a background task to handle the GLM request and DB updates
-    // This ensures the request completes and is recorded even if the client disconnects
     let handle = tokio::spawn(async move {
-        let endpoint = match resolve_glm_endpoint(payload_clone.base_url.as_deref()) {
-            Ok(v) => v,
-            Err(_) => {
-                let response_time_ms = start.elapsed().as_millis().min(i64::MAX as u128) as i64;
-                finish_glm_request_log(
-                    &db,
-                    request_id,
-                    "failed",
-                    None,
-                    Some("Invalid baseUrl"),
-                    Some(response_time_ms),
-                )
-                .await;
-                return Err(error_response(CODE_INVALID_BASE_URL, "Invalid baseUrl").into_response());
-            }
-        };
-
-        let api_key = match resolve_glm_api_key(payload_clone.api_key.as_deref()) {
-            Ok(v) => v,
-            Err(_) => {
-                let response_time_ms = start.elapsed().as_millis().min(i64::MAX as u128) as i64;
-                finish_glm_request_log(
-                    &db,
-                    request_id,
-                    "failed",
-                    None,
-                    Some("Missing GLM API Key"),
-                    Some(response_time_ms),
-                )
-                .await;
-                return Err(error_response(
-                    "API_KEY_REQUIRED",
-                    "API Key is required. Please configure your own API Key in settings.",
-                )
-                .into_response());
-            }
-        };
-
-        let response = match client
-            .post(&endpoint)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .json(&request_body)
-            .send()
-            .await
-        {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("GLM Request failed: {}", e);
-                finish_glm_request_log(
-                    &db,
-                    request_id,
-                    "failed",
-                    None,
-                    Some("GLM Request failed"),
-                    None,
-                )
-                .await;
-                return Err(error_response(CODE_INTERNAL_ERROR, "GLM Request failed").into_response());
-            }
-        };
-
-        let duration = start.elapsed();
-        println!("GLM Request took: {:?}", duration);
-
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_default();
-            let error_text_s = sanitize_text(&sensitive, &error_text);
-            eprintln!("GLM Error: {}", error_text_s);
-            let response_time_ms = duration.as_millis().min(i64::MAX as u128) as i64;
-
-            // Check for GLM error code 1305 (rate limit)
-            if glm::is_rate_limit_error(&error_text) {
-                let error_message = if let Some(code) = glm::extract_glm_error_code(&error_text) {
-                    format!("GLM API 返回错误码 {}: {}", code, error_text_s)
-                } else {
-                    error_text_s.clone()
-                };
-
-                finish_glm_request_log(
-                    &db,
-                    request_id,
-                    "error",
-                    None,
-                    Some(&error_text_s),
-                    Some(response_time_ms),
-                )
-                .await;
-                return Err(rate_limit_response(error_message).into_response());
-            }
+        let start = std::time::Instant::now();
+        let result = glm::call_glm_with_api_key(
+            prompt,
+            true,
+            payload.api_key.clone(),
+            payload.base_url.clone(),
+            Some(model.clone()),
+        )
+        .await;
+        let response_time_ms = start.elapsed().as_millis().min(i64::MAX as u128) as i64;
 
-            // Fallback: check for "limit" keyword in error text
-            if glm::contains_limit(&error_text) {
+        let content = match result {
+            Ok(c) => c,
+            Err(error_text) => {
+                let error_text_s = sanitize_text(&sensitive, &error_text);
                 finish_glm_request_log(
                     &db,
                     request_id,
@@ -991,103 +1912,1816 @@ pub(crate) async fn generate(
                     Some(response_time_ms),
                 )
                 .await;
-                return Err(rate_limit_response(&error_text_s).into_response());
+                return Err(glm_call_error_response(&error_text, error_text_s, &model));
             }
+        };
 
+        let mut template = payload.template;
+        let spliced = parse_continuation(&content, max_nodes)
+            .and_then(|c| splice_continuation(&mut template, &payload.from_node_id, c));
+        let content_s = sanitize_text(&sensitive, &content);
+        if let Err(e) = spliced {
             finish_glm_request_log(
                 &db,
                 request_id,
-                "error",
-                None,
-                Some(&error_text_s),
+                "failed",
+                Some(&content_s),
+                Some(&e),
                 Some(response_time_ms),
             )
             .await;
-
-            return Err(error_response(CODE_INTERNAL_ERROR, error_text_s).into_response());
+            return Err(error_response(CODE_INTERNAL_ERROR, e).into_response());
         }
 
-        let text_response = match response.text().await {
-            Ok(t) => t,
-            Err(e) => {
-                let response_time_ms = duration.as_millis().min(i64::MAX as u128) as i64;
-                finish_glm_request_log(
-                    &db,
-                    request_id,
-                    "failed",
-                    None,
-                    Some(&format!("Failed to read response body: {}", e)),
-                    Some(response_time_ms),
-                )
-                .await;
-                return Err(error_response(
-                    CODE_INTERNAL_ERROR,
-                    format!("Failed to read response body: {}", e),
-                )
-                .into_response());
-            }
-        };
-
-        // Try to parse as generic JSON first to check for "error" field
-        if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&text_response) {
-            if json_value.get("error").is_some() {
-                let text_response_s = sanitize_text(&sensitive, &text_response);
-                println!(
-                    "GLM returned 200 OK but with error body: {}",
-                    text_response_s
-                );
-                let response_time_ms = duration.as_millis().min(i64::MAX as u128) as i64;
+        sanitize_template_graph(&mut template);
+        sanitize_affinity_effects(&mut template);
 
-                if glm::is_rate_limit_error(&text_response) {
-                    let error_message = if let Some(code) = glm::extract_glm_error_code(&text_response)
-                    {
-                        format!("GLM API 返回错误码 {}: {}", code, text_response_s)
-                    } else {
-                        text_response_s.clone()
-                    };
+        if payload.id.is_some() {
+            touch_provenance(&mut template);
+        }
 
-                    finish_glm_request_log(
-                        &db,
-                        request_id,
-                        "error",
-                        None,
-                        Some(&text_response_s),
-                        Some(response_time_ms),
-                    )
-                    .await;
-                    return Err(rate_limit_response(error_message).into_response());
-                }
+        let mut template_value = serde_json::to_value(&template).unwrap_or(json!({}));
+        template_value = sanitize_json_value(&sensitive, template_value);
 
+        if let Some(id) = payload.id {
+            let saved = match save_processed_response(&db, id, &template_value).await {
+                Ok(()) => set_request_source(&db, id, "/generate/continue").await,
+                Err(e) => Err(DbError::from_sqlx(e)),
+            };
+            if let Err(e) = saved {
+                eprintln!("Database error: {:?}", e);
                 finish_glm_request_log(
                     &db,
                     request_id,
-                    "error",
-                    None,
-                    Some(&text_response_s),
+                    "failed",
+                    Some(&content_s),
+                    Some("Failed to save template"),
                     Some(response_time_ms),
                 )
                 .await;
-                return Err(error_response(CODE_INTERNAL_ERROR, text_response_s).into_response());
+                return Err(db_error_response(e).into_response());
             }
         }
 
-        let response_json: serde_json::Value = match serde_json::from_str(&text_response) {
-            Ok(v) => v,
+        finish_glm_request_log(
+            &db,
+            request_id,
+            "success",
+            Some(&content_s),
+            None,
+            Some(response_time_ms),
+        )
+        .await;
+
+        Ok(success_response(template_value).into_response())
+    });
+
+    let res = match handle.await {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("Task join error: {}", e);
+            Err(error_response(CODE_INTERNAL_ERROR, "Internal Server Error").into_response())
+        }
+    };
+    with_model_header(res, &served_model)
+}
+
+pub(crate) async fn split_template_node(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<SplitNodeRequest>,
+) -> Result<Response, Response> {
+    if !(MIN_SPLIT_PARTS..=MAX_SPLIT_PARTS).contains(&payload.parts) {
+        return Err(error_response(
+            CODE_BAD_REQUEST,
+            format!(
+                "parts 必须在 {} 到 {} 之间",
+                MIN_SPLIT_PARTS, MAX_SPLIT_PARTS
+            ),
+        )
+        .into_response());
+    }
+
+    let mut payload = sanitize_request_payload(&state.sensitive, payload)?;
+
+    let row = crate::db::get_game_for_play(&state.db, payload.id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+
+    let Some((data, _shared, owner_ip)) = row else {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    };
+
+    let client_ip = resolve_client_ip(&headers, &addr);
+    let trusted = take_trusted_key(&state.trusted, &client_ip, &mut payload.api_key);
+    if !is_owner_ip(&owner_ip, &client_ip) {
+        return Err(
+            error_response("FORBIDDEN", "You are not the owner of this game").into_response(),
+        );
+    }
+
+    let template: crate::types::MovieTemplate = serde_json::from_value(data).map_err(|e| {
+        eprintln!("Stored template is invalid: {}", e);
+        error_response(CODE_INTERNAL_ERROR, "Stored template is invalid").into_response()
+    })?;
+
+    if !template.nodes.contains_key(&payload.node_id) {
+        return Err(error_response("NOT_FOUND", "Node not found").into_response());
+    }
+
+    resolve_glm_endpoint(payload.base_url.as_deref())
+        .map_err(|_| error_response(CODE_INVALID_BASE_URL, "Invalid baseUrl").into_response())?;
+    resolve_glm_api_key(payload.api_key.as_deref())
+        .map_err(|_| error_response("API_KEY_REQUIRED", "API Key is required").into_response())?;
+
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("unknown");
+
+    let prompt = construct_split_node_prompt(&template, &payload.node_id, payload.parts);
+    let using_override_key = payload
+        .api_key
+        .as_ref()
+        .is_some_and(|k| !k.trim().is_empty());
+    let mut payload_json = serde_json::to_value(&payload).unwrap_or(json!({}));
+    if let Some(obj) = payload_json.as_object_mut() {
+        obj.remove("apiKey");
+    }
+    state.sensitive.sanitize_json(&mut payload_json);
+    let prompt_for_log = sanitize_text(&state.sensitive, &prompt);
+
+    if !state.burst_limiter.check(&client_ip) {
+        return Err(rate_limit_response("请求过于频繁，请稍后再试").into_response());
+    }
+
+    let request_id = begin_glm_request_log(
+        &state.db,
+        &client_ip,
+        user_agent,
+        "/node/split",
+        payload_json,
+        &prompt_for_log,
+        QuotaCheck::for_request(using_override_key, trusted),
+    )
+    .await
+    .map_err(|e| db_error_response(e).into_response())?;
+
+    let db = state.db.clone();
+    let sensitive = state.sensitive.clone();
+    let model = effective_model(
+        payload.model.as_deref(),
+        using_override_key,
+        MODEL_GENERATE_ENV,
+    );
+    let served_model = model.clone();
+
+    let handle = tokio::spawn(async move {
+        let start = std::time::Instant::now();
+        let result = glm::call_glm_with_api_key(
+            prompt,
+            true,
+            payload.api_key.clone(),
+            payload.base_url.clone(),
+            Some(model.clone()),
+        )
+        .await;
+        let response_time_ms = start.elapsed().as_millis().min(i64::MAX as u128) as i64;
+
+        let content = match result {
+            Ok(c) => c,
+            Err(error_text) => {
+                let error_text_s = sanitize_text(&sensitive, &error_text);
+                finish_glm_request_log(
+                    &db,
+                    request_id,
+                    "error",
+                    None,
+                    Some(&error_text_s),
+                    Some(response_time_ms),
+                )
+                .await;
+                return Err(glm_call_error_response(&error_text, error_text_s, &model));
+            }
+        };
+
+        let mut template = template;
+        let split = parse_split_beats(&content, payload.parts)
+            .and_then(|beats| split_node(&mut template, &payload.node_id, beats));
+        let content_s = sanitize_text(&sensitive, &content);
+        if let Err(e) = split {
+            finish_glm_request_log(
+                &db,
+                request_id,
+                "failed",
+                Some(&content_s),
+                Some(&e),
+                Some(response_time_ms),
+            )
+            .await;
+            return Err(error_response(CODE_INTERNAL_ERROR, e).into_response());
+        }
+
+        sanitize_template_graph(&mut template);
+        normalize_template_endings(&mut template);
+        touch_provenance(&mut template);
+
+        let mut template_value = serde_json::to_value(&template).unwrap_or(json!({}));
+        template_value = sanitize_json_value(&sensitive, template_value);
+
+        let saved = match save_processed_response(&db, payload.id, &template_value).await {
+            Ok(()) => set_request_source(&db, payload.id, "/node/split").await,
+            Err(e) => Err(DbError::from_sqlx(e)),
+        };
+        if let Err(e) = saved {
+            eprintln!("Database error: {:?}", e);
+            finish_glm_request_log(
+                &db,
+                request_id,
+                "failed",
+                Some(&content_s),
+                Some("Failed to save template"),
+                Some(response_time_ms),
+            )
+            .await;
+            return Err(db_error_response(e).into_response());
+        }
+
+        finish_glm_request_log(
+            &db,
+            request_id,
+            "success",
+            Some(&content_s),
+            None,
+            Some(response_time_ms),
+        )
+        .await;
+
+        Ok(success_response(template_value).into_response())
+    });
+
+    let res = match handle.await {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("Task join error: {}", e);
+            Err(error_response(CODE_INTERNAL_ERROR, "Internal Server Error").into_response())
+        }
+    };
+    with_model_header(res, &served_model)
+}
+
+pub(crate) async fn delete_template(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<DeleteTemplateRequest>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
+    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+
+    let request_info = get_request_owner(&state.db, payload.id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+
+    let Some((owner_ip, _status)) = request_info else {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    };
+
+    let request_ip = resolve_client_ip(&headers, &addr);
+    let is_owner = is_owner_ip(&owner_ip, &request_ip);
+
+    if !is_owner {
+        return Err(
+            error_response("FORBIDDEN", "You are not the owner of this game").into_response(),
+        );
+    }
+
+    delete_game_by_request_id(&state.db, payload.id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+
+    Ok(success_response(json!({
+        "deleted": true
+    })))
+}
+
+const DEFAULT_PLAY_CACHE_MAX_AGE_SECS: u64 = 300;
+
+/// `max-age` for shared `/play/:id` responses; `0` makes caches revalidate
+/// every time. Kept short by default so unsharing takes effect quickly.
+pub(crate) fn play_cache_max_age() -> u64 {
+    std::env::var("PLAY_CACHE_MAX_AGE_SECS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_PLAY_CACHE_MAX_AGE_SECS)
+}
+
+/// Strong ETag over the stored template.
+pub(crate) fn play_etag(data: &serde_json::Value) -> String {
+    format!("\"{}\"", sha256_hex(&data.to_string()))
+}
+
+/// `If-None-Match` holds `*` or a comma-separated list that includes `etag`
+/// (a `W/` prefix is ignored, as weak comparison allows for GET).
+pub(crate) fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match.is_some_and(|v| {
+        v.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+    })
+}
+
+/// Wraps `/play/:id` data with caching headers, or answers `304` when the
+/// client already holds this version. Only shared games are publicly cacheable;
+/// an owner viewing an unshared game gets `private, no-cache`.
+pub(crate) fn play_response(
+    data: serde_json::Value,
+    shared: bool,
+    if_none_match: Option<&str>,
+    max_age: u64,
+) -> Response {
+    use axum::http::header::{CACHE_CONTROL, ETAG};
+    use axum::http::HeaderValue;
+
+    let etag = play_etag(&data);
+    let cache_control = if shared {
+        format!("public, max-age={}", max_age)
+    } else {
+        "private, no-cache".to_string()
+    };
+
+    let mut res = if etag_matches(if_none_match, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        success_response(data).into_response()
+    };
+    if let Ok(v) = HeaderValue::from_str(&etag) {
+        res.headers_mut().insert(ETAG, v);
+    }
+    if let Ok(v) = HeaderValue::from_str(&cache_control) {
+        res.headers_mut().insert(CACHE_CONTROL, v);
+    }
+    res
+}
+
+pub(crate) async fn get_shared_game(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let row = crate::db::get_game_for_play(&state.db, id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+
+    let Some((data, shared, owner_ip)) = row else {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    };
+
+    let request_ip = resolve_client_ip(&headers, &addr);
+    let is_owner = is_owner_ip(&owner_ip, &request_ip);
+
+    if !shared && !is_owner {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    }
+
+    // 2. Record visit (async, fire and forget)
+    spawn_record_visit(&state, id, &headers, &addr);
+
+    // Remove filtering on game data as per user request
+    let if_none_match = headers
+        .get(axum::http::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    let max_age = play_cache_max_age();
+    Ok(play_response(data, shared, if_none_match, max_age))
+}
+
+/// Share card for a stored template (the data `/play/:id` serves).
+pub(crate) fn preview_svg_for_game(data: &serde_json::Value) -> String {
+    preview_card_svg(
+        data["title"].as_str().unwrap_or(""),
+        data["meta"]["synopsis"].as_str().unwrap_or(""),
+        data["backgroundImageBase64"].as_str(),
+        watermark_text().as_deref(),
+    )
+}
+
+/// Serves a share card as `image/svg+xml` with the same ETag revalidation
+/// and public `max-age` as shared `/play/:id` data.
+pub(crate) fn preview_response(svg: String, if_none_match: Option<&str>, max_age: u64) -> Response {
+    use axum::http::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG};
+
+    let etag = format!("\"{}\"", sha256_hex(&svg));
+    let headers = [
+        (CONTENT_TYPE, "image/svg+xml".to_string()),
+        (CACHE_CONTROL, format!("public, max-age={}", max_age)),
+        (ETAG, etag.clone()),
+    ];
+    if etag_matches(if_none_match, &etag) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    (headers, svg).into_response()
+}
+
+/// `GET /preview/:id.svg`: Open Graph image for a shared game. Unshared or
+/// unknown games are `NOT_FOUND` for everyone, since crawlers fetch these.
+pub(crate) async fn get_preview_image(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let not_found = || error_response("NOT_FOUND", "Game not found").into_response();
+    let id: Uuid = file
+        .strip_suffix(".svg")
+        .unwrap_or(&file)
+        .parse()
+        .map_err(|_| not_found())?;
+
+    let row = crate::db::get_game_for_play(&state.db, id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+    let Some((data, true, _)) = row else {
+        return Err(not_found());
+    };
+
+    let if_none_match = headers
+        .get(axum::http::header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    Ok(preview_response(
+        preview_svg_for_game(&data),
+        if_none_match,
+        play_cache_max_age(),
+    ))
+}
+
+fn spawn_record_visit(state: &AppState, id: Uuid, headers: &HeaderMap, addr: &SocketAddr) {
+    let db = state.db.clone();
+    let client_ip = resolve_client_ip(headers, addr);
+    let user_agent = headers
+        .get("user-agent")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let referer = headers
+        .get("referer")
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string());
+
+    tokio::spawn(async move {
+        if let Err(e) = record_visit(&db, id, &client_ip, &user_agent, referer.as_deref()).await {
+            eprintln!("Failed to record visit: {}", e);
+        }
+    });
+}
+
+/// Keeps only a shared row with a stored template; the query already filters
+/// on both, this guards the demo against ever leaking a private game.
+pub(crate) fn pick_random_shared_game(
+    row: Option<(Uuid, Option<serde_json::Value>, bool)>,
+) -> Option<(Uuid, serde_json::Value)> {
+    match row {
+        Some((id, Some(data), true)) => Some((id, data)),
+        _ => None,
+    }
+}
+
+pub(crate) async fn get_random_game(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
+    let row = crate::db::get_random_shared_game(&state.db)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::from_sqlx(e)).into_response()
+        })?;
+
+    let Some((id, data)) = pick_random_shared_game(row) else {
+        return Err(error_response("NOT_FOUND", "No shared game available").into_response());
+    };
+
+    spawn_record_visit(&state, id, &headers, &addr);
+
+    Ok(success_response(json!({ "id": id, "template": data })))
+}
+
+/// Picks the stored raw GLM content for the owner, or the error code and
+/// message to return when the row is missing, not owned, or has no content.
+pub(crate) fn pick_raw_glm_response(
+    row: Option<(String, Option<String>)>,
+    request_ip: &str,
+) -> Result<String, (&'static str, &'static str)> {
+    let Some((owner_ip, raw)) = row else {
+        return Err(("NOT_FOUND", "Game not found"));
+    };
+
+    if !is_owner_ip(&owner_ip, request_ip) {
+        return Err(("FORBIDDEN", "You are not the owner of this game"));
+    }
+
+    raw.filter(|r| !r.trim().is_empty())
+        .ok_or(("NOT_FOUND", "Raw response not found"))
+}
+
+pub(crate) async fn get_raw_template(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
+    let row = get_raw_glm_response(&state.db, id).await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::InternalError).into_response()
+    })?;
+
+    let request_ip = resolve_client_ip(&headers, &addr);
+    let raw = pick_raw_glm_response(row, &request_ip)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    Ok(success_response(json!({
+        "requestId": id,
+        "rawResponse": raw
+    })))
+}
+
+/// Capabilities of this deployment, so the frontend can hide what it lacks.
+pub(crate) async fn get_features(
+    State(state): State<AppState>,
+) -> Json<ApiResponse<crate::features::FeatureFlags>> {
+    success_response(state.features)
+}
+
+/// Model catalogue for the frontend picker, restricted by `ALLOWED_MODELS`
+/// (comma-separated) when set.
+pub(crate) async fn get_models() -> Json<ApiResponse<Vec<glm::ModelInfo>>> {
+    let allowed = std::env::var("ALLOWED_MODELS").ok();
+    success_response(glm::list_models(
+        allowed.as_deref(),
+        crate::images::ALLOWED_IMAGE_MODELS,
+    ))
+}
+
+pub(crate) async fn ping_glm(
+    headers: HeaderMap,
+    Json(payload): Json<GlmPingRequest>,
+) -> Result<Json<ApiResponse<glm::GlmPingResult>>, Response> {
+    let configured = std::env::var("ADMIN_TOKEN").ok();
+    let provided = headers.get("x-admin-token").and_then(|v| v.to_str().ok());
+    check_admin_token(configured.as_deref(), provided)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    let model = effective_model(payload.model.as_deref(), true, MODEL_GENERATE_ENV);
+    let result = glm::ping_glm(payload.api_key, payload.base_url, model).await;
+    Ok(success_response(result))
+}
+
+const MAX_FEEDBACK_COMMENT_CHARS: usize = 500;
+
+/// Checks a rating (1-5) and trims the optional comment, dropping it when empty.
+pub(crate) fn validate_feedback(
+    rating: i64,
+    comment: Option<&str>,
+) -> Result<(i16, Option<String>), (&'static str, String)> {
+    if !(1..=5).contains(&rating) {
+        return Err((CODE_BAD_REQUEST, "rating 必须在 1 到 5 之间".to_string()));
+    }
+    let comment = comment.map(str::trim).filter(|c| !c.is_empty());
+    if comment.is_some_and(|c| count_display_chars(c) > MAX_FEEDBACK_COMMENT_CHARS) {
+        return Err((
+            CODE_BAD_REQUEST,
+            format!("comment 不能超过 {} 个字符", MAX_FEEDBACK_COMMENT_CHARS),
+        ));
+    }
+    Ok((rating as i16, comment.map(str::to_string)))
+}
+
+/// Turns per-(route, model) rating totals into averages.
+pub(crate) fn summarize_feedback(totals: Vec<(String, String, i64, i64)>) -> Vec<FeedbackStat> {
+    totals
+        .into_iter()
+        .filter(|(_, _, count, _)| *count > 0)
+        .map(|(route, model, count, sum)| FeedbackStat {
+            route,
+            model,
+            count,
+            average_rating: (sum as f64 / count as f64 * 100.0).round() / 100.0,
+        })
+        .collect()
+}
+
+pub(crate) async fn submit_feedback(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<FeedbackRequest>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
+    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+    let (rating, comment) = validate_feedback(payload.rating, payload.comment.as_deref())
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    let client_ip = resolve_client_ip(&headers, &addr);
+    if !state.burst_limiter.check(&client_ip) {
+        return Err(rate_limit_response("请求过于频繁，请稍后再试").into_response());
+    }
+
+    let request_info = get_request_owner(&state.db, payload.id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::from_sqlx(e)).into_response()
+        })?;
+    if request_info.is_none() {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    }
+
+    let ua = headers
+        .get("user-agent")
+        .and_then(|h| h.to_str().ok())
+        .filter(|s| !s.trim().is_empty());
+
+    let feedback_id = insert_feedback(
+        &state.db,
+        payload.id,
+        rating,
+        comment.as_deref(),
+        &client_ip,
+        ua,
+    )
+    .await
+    .map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::from_sqlx(e)).into_response()
+    })?;
+
+    Ok(success_response(json!({ "feedbackId": feedback_id })))
+}
+
+pub(crate) async fn get_feedback_stats(
+    State(state): State<AppState>,
+) -> Result<Json<ApiResponse<Vec<FeedbackStat>>>, Response> {
+    let totals = get_feedback_totals(&state.db).await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::from_sqlx(e)).into_response()
+    })?;
+    Ok(success_response(summarize_feedback(totals)))
+}
+
+/// Admin endpoints are disabled (`NOT_FOUND`) unless `ADMIN_TOKEN` is set,
+/// and then require the same value in the `x-admin-token` header.
+pub(crate) fn check_admin_token(
+    configured: Option<&str>,
+    provided: Option<&str>,
+) -> Result<(), (&'static str, &'static str)> {
+    let Some(configured) = configured.map(str::trim).filter(|t| !t.is_empty()) else {
+        return Err(("NOT_FOUND", "Not found"));
+    };
+    let provided = provided.unwrap_or("").trim();
+    let matches = provided.len() == configured.len()
+        && provided
+            .bytes()
+            .zip(configured.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+    if !matches {
+        return Err(("FORBIDDEN", "Invalid admin token"));
+    }
+    Ok(())
+}
+
+/// Compares applied migration versions with the ones bundled in the build.
+pub(crate) fn migration_status(known: &[i64], applied: &[i64]) -> DbVersionInfo {
+    DbVersionInfo {
+        current: applied.iter().copied().max(),
+        latest: known.iter().copied().max(),
+        pending: known
+            .iter()
+            .copied()
+            .filter(|v| !applied.contains(v))
+            .collect(),
+    }
+}
+
+pub(crate) async fn get_db_version(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<DbVersionInfo>>, Response> {
+    let configured = std::env::var("ADMIN_TOKEN").ok();
+    let provided = headers.get("x-admin-token").and_then(|v| v.to_str().ok());
+    check_admin_token(configured.as_deref(), provided)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    let applied = get_applied_migrations(&state.db).await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::from_sqlx(e)).into_response()
+    })?;
+
+    Ok(success_response(migration_status(
+        &known_migration_versions(),
+        &applied,
+    )))
+}
+
+pub(crate) async fn get_prompt_size_stats(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<Vec<PromptSizeStat>>>, Response> {
+    let configured = std::env::var("ADMIN_TOKEN").ok();
+    let provided = headers.get("x-admin-token").and_then(|v| v.to_str().ok());
+    check_admin_token(configured.as_deref(), provided)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    let totals = get_prompt_size_totals(&state.db).await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::from_sqlx(e)).into_response()
+    })?;
+    let stats = totals
+        .into_iter()
+        .map(|(route, status, count, avg, max)| PromptSizeStat {
+            route,
+            status,
+            count,
+            average_prompt_tokens: avg.round(),
+            max_prompt_tokens: max,
+        })
+        .collect();
+    Ok(success_response(stats))
+}
+
+const DEFAULT_BACKFILL_BATCH: i64 = 50;
+const MAX_BACKFILL_BATCH: i64 = 500;
+
+/// Rebuilds the template a `/generate` call would have stored from its raw
+/// model content and logged request payload. Images are the SVG fallbacks;
+/// an unreadable payload falls back to the default options.
+pub(crate) fn rebuild_processed_template(
+    raw: &str,
+    request_payload: &serde_json::Value,
+) -> Result<MovieTemplate, String> {
+    let cleaned = clean_json(raw);
+    let lite: MovieTemplateLite = serde_json::from_str(&cleaned)
+        .map_err(|e| ParseDiagnostic::new(raw, &cleaned, &e).to_string())?;
+    let payload: GenerateRequest =
+        serde_json::from_value(request_payload.clone()).unwrap_or_default();
+
+    let language_tag = payload.language.as_deref().unwrap_or("zh-CN");
+    let mut template = convert_lite_to_full(lite, language_tag);
+    check_node_limit(template.nodes.len(), max_nodes_hard_limit()).map_err(|(_, msg)| msg)?;
+    finish_generated_template(&mut template, &payload);
+    template.background_image_base64 = Some(fallback_background_data_uri(
+        &template.title,
+        &template.meta.synopsis,
+    ));
+    ensure_avatar_fallbacks(&mut template, payload.characters.as_ref());
+    Ok(template)
+}
+
+/// Fills `processed_response` for successful generations that predate it, a
+/// bounded batch (`limit`, default 50, at most 500) per call, paged by
+/// `after`. Safe to repeat: rows that gain a template in the meantime are
+/// left alone.
+pub(crate) async fn backfill_processed_responses(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<BackfillQuery>,
+) -> Result<Json<ApiResponse<BackfillResult>>, Response> {
+    let configured = std::env::var("ADMIN_TOKEN").ok();
+    let provided = headers.get("x-admin-token").and_then(|v| v.to_str().ok());
+    check_admin_token(configured.as_deref(), provided)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    let limit = query
+        .limit
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_BACKFILL_BATCH)
+        .min(MAX_BACKFILL_BATCH);
+    let rows = list_unprocessed_generations(&state.db, query.after, limit)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::from_sqlx(e)).into_response()
+        })?;
+
+    let mut result = BackfillResult {
+        scanned: rows.len(),
+        last_id: rows.last().map(|(id, _, _)| *id),
+        ..BackfillResult::default()
+    };
+    for (id, request_payload, raw) in rows {
+        let template = match rebuild_processed_template(&raw, &request_payload) {
+            Ok(t) => t,
+            Err(error) => {
+                result.failed.push(BackfillFailure { id, error });
+                continue;
+            }
+        };
+        let value = serde_json::to_value(&template).unwrap_or(json!({}));
+        match backfill_processed_response(&state.db, id, &value).await {
+            Ok(true) => result.backfilled += 1,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("Database error: {}", e);
+                return Err(db_error_response(DbError::from_sqlx(e)).into_response());
+            }
+        }
+    }
+    println!(
+        "Backfilled processed_response for {} of {} rows ({} failed)",
+        result.backfilled,
+        result.scanned,
+        result.failed.len()
+    );
+    Ok(success_response(result))
+}
+
+/// Picks the stored GLM prompt for the owner, or the error code and message
+/// to return when the request is missing, not owned, or has no prompt.
+pub(crate) fn pick_stored_prompt(
+    owner_ip: Option<&str>,
+    prompt: Option<String>,
+    request_ip: &str,
+) -> Result<String, (&'static str, &'static str)> {
+    let Some(owner_ip) = owner_ip else {
+        return Err(("NOT_FOUND", "Request not found"));
+    };
+
+    if !is_owner_ip(owner_ip, request_ip) {
+        return Err(("FORBIDDEN", "You are not the owner of this request"));
+    }
+
+    prompt
+        .filter(|p| !p.trim().is_empty())
+        .ok_or(("NOT_FOUND", "Prompt not found"))
+}
+
+pub(crate) async fn get_request_prompt(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
+    let owner = get_request_owner(&state.db, id).await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::from_sqlx(e)).into_response()
+    })?;
+
+    let prompt = match owner {
+        Some(_) => get_glm_prompt(&state.db, id).await.map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::from_sqlx(e)).into_response()
+        })?,
+        None => None,
+    };
+
+    let request_ip = resolve_client_ip(&headers, &addr);
+    let owner_ip = owner.as_ref().map(|(ip, _)| ip.as_str());
+    let prompt = pick_stored_prompt(owner_ip, prompt, &request_ip)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    Ok(success_response(json!({
+        "requestId": id,
+        "prompt": prompt
+    })))
+}
+
+/// Builds the timeline of a logged request for its owner, or the error code
+/// and message to return when the request is missing or not owned.
+pub(crate) fn build_request_timeline(
+    row: Option<RequestTimelineRow>,
+    request_ip: &str,
+) -> Result<RequestTimeline, (&'static str, &'static str)> {
+    let Some((
+        owner_ip,
+        status,
+        created_at,
+        finished_at,
+        response_time_ms,
+        elapsed_ms,
+        glm_ms,
+        images_ms,
+        process_ms,
+    )) = row
+    else {
+        return Err(("NOT_FOUND", "Request not found"));
+    };
+
+    if !is_owner_ip(&owner_ip, request_ip) {
+        return Err(("FORBIDDEN", "You are not the owner of this request"));
+    }
+
+    // Rows without stage timings (failures, older rows) only recorded the
+    // GLM call in `response_time_ms`.
+    let glm_latency_ms = glm_ms.or(response_time_ms);
+    // `finished_at` is written once with the final status; edits that later
+    // bump `updated_at` leave it alone. Rows from before it existed have none.
+    let total_ms = elapsed_ms.map(|ms| ms.max(0));
+    let other_ms = total_ms.map(|total| (total - glm_latency_ms.unwrap_or(0)).max(0));
+
+    Ok(RequestTimeline {
+        status,
+        created_at,
+        finished_at,
+        glm_latency_ms,
+        images_ms,
+        process_ms,
+        total_ms,
+        other_ms,
+    })
+}
+
+pub(crate) async fn get_request_timeline(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<RequestTimeline>>, Response> {
+    let row = get_request_timeline_row(&state.db, id).await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::from_sqlx(e)).into_response()
+    })?;
+
+    let request_ip = resolve_client_ip(&headers, &addr);
+    let timeline = build_request_timeline(row, &request_ip)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    Ok(success_response(timeline))
+}
+
+/// Loads a stored template for read-only views, with the same access rule as
+/// `/play/:id`: shared games are public, unshared ones are visible to the owner.
+async fn load_viewable_template(
+    state: &AppState,
+    id: Uuid,
+    headers: &HeaderMap,
+    addr: &SocketAddr,
+) -> Result<crate::types::MovieTemplate, Response> {
+    let row = crate::db::get_game_for_play(&state.db, id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+
+    let Some((data, shared, owner_ip)) = row else {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    };
+
+    let request_ip = resolve_client_ip(headers, addr);
+    if !shared && !is_owner_ip(&owner_ip, &request_ip) {
+        return Err(error_response("NOT_FOUND", "Game not found").into_response());
+    }
+
+    serde_json::from_value(data).map_err(|e| {
+        eprintln!("Stored template is invalid: {}", e);
+        error_response(CODE_INTERNAL_ERROR, "Stored template is invalid").into_response()
+    })
+}
+
+pub(crate) async fn get_layout(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<Vec<NodeLayout>>>, Response> {
+    let template = load_viewable_template(&state, id, &headers, &addr).await?;
+    Ok(success_response(build_layout_hints(&template)))
+}
+
+/// The cast of a template, ordered by character id for stable output.
+pub(crate) fn template_cast(
+    template: &crate::types::MovieTemplate,
+) -> Vec<crate::types::Character> {
+    let mut cast: Vec<crate::types::Character> = template.characters.values().cloned().collect();
+    cast.sort_by(|a, b| a.id.cmp(&b.id));
+    cast
+}
+
+pub(crate) async fn get_characters(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<Vec<crate::types::Character>>>, Response> {
+    let template = load_viewable_template(&state, id, &headers, &addr).await?;
+    Ok(success_response(template_cast(&template)))
+}
+
+/// `GET /relationships/:id`: the cast as a co-occurrence graph, as JSON or,
+/// with `?format=mermaid`, as a Mermaid flowchart.
+pub(crate) async fn get_relationships(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<RelationshipQuery>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let template = load_viewable_template(&state, id, &headers, &addr).await?;
+    let graph = build_relationship_graph(&template);
+    match query.format.as_deref().map(str::trim).unwrap_or("json") {
+        "" | "json" => Ok(success_response(graph).into_response()),
+        "mermaid" => Ok((
+            [(
+                axum::http::header::CONTENT_TYPE,
+                "text/plain; charset=utf-8",
+            )],
+            relationship_graph_mermaid(&graph),
+        )
+            .into_response()),
+        _ => Err(error_response(CODE_BAD_REQUEST, "Unsupported format").into_response()),
+    }
+}
+
+pub(crate) async fn export_template_json(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<ExportJsonQuery>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<crate::types::ExportedTemplate>>, Response> {
+    let template = load_viewable_template(&state, id, &headers, &addr).await?;
+    let mut exported = crate::types::ExportedTemplate::from(&template);
+    if query.ordered_endings {
+        exported.ordered_endings = Some(ordered_endings(&template.endings));
+    }
+    Ok(success_response(exported))
+}
+
+/// Downloads a stored game as one self-contained HTML file (see
+/// `build_player_html`); visible to the same callers as `/play/:id`.
+pub(crate) async fn compile_template(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<CompileRequest>,
+) -> Result<Response, Response> {
+    use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+
+    let template = load_viewable_template(&state, payload.id, &headers, &addr).await?;
+    let html = build_player_html(&crate::types::ExportedTemplate::from(&template));
+    let disposition = format!("attachment; filename=\"movie-game-{}.html\"", payload.id);
+    Ok((
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        html,
+    )
+        .into_response())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SharedRecordListItem {
+    request_id: Uuid,
+    title: String,
+    shared_at: String,
+    shared: bool,
+    synopsis: String,
+    genre: String,
+    language: String,
+    play_count: i64,
+    source: String,
+}
+
+pub(crate) async fn get_shared_record_meta(
+    State(state): State<AppState>,
+    Path(request_id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
+    let request_ip = resolve_client_ip(&headers, &addr);
+
+    let meta = get_shared_record_meta_by_request_id(&state.db, request_id)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+
+    let Some((shared, shared_at, owner_ip)) = meta else {
+        return Err(error_response("NOT_FOUND", "Record not found").into_response());
+    };
+
+    let is_owner = is_owner_ip(&owner_ip, &request_ip);
+
+    Ok(success_response(json!({
+        // "sharedRecordId": ... REMOVED per security requirement
+        "requestId": request_id,
+        "shared": shared,
+        "sharedAt": shared_at.map(|v| json!(v)).unwrap_or(serde_json::Value::Null),
+        "isOwner": is_owner
+    })))
+}
+
+pub(crate) async fn list_records(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<RecordsListRequest>,
+) -> Result<Json<ApiResponse<Vec<SharedRecordListItem>>>, Response> {
+    let payload = sanitize_request_payload(&state.sensitive, payload)?;
+
+    let owner_ip = resolve_client_ip(&headers, &addr);
+
+    if payload.ids.is_empty() {
+        return Ok(success_response(Vec::<SharedRecordListItem>::new()));
+    }
+
+    if payload.ids.len() > 200 {
+        return Err(error_response(CODE_BAD_REQUEST, "Too many ids").into_response());
+    }
+
+    // payload.ids are now treated as request_ids
+    let rows = crate::db::list_shared_records_by_request_ids(&state.db, &payload.ids, &owner_ip)
+        .await
+        .map_err(|e| {
+            eprintln!("Database error: {}", e);
+            db_error_response(DbError::InternalError).into_response()
+        })?;
+
+    let mut items = rows
+        .into_iter()
+        .map(
+            |(
+                request_id,
+                shared_at,
+                shared,
+                title,
+                synopsis,
+                genre,
+                language,
+                play_count,
+                source,
+            )| {
+                SharedRecordListItem {
+                    request_id,
+                    title: title.unwrap_or_else(|| "Untitled".to_string()),
+                    shared_at,
+                    shared,
+                    synopsis: synopsis.unwrap_or_default(),
+                    genre: genre.unwrap_or_default(),
+                    language: language.unwrap_or_default(),
+                    play_count,
+                    source,
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+
+    for item in items.iter_mut() {
+        item.title = sanitize_text(&state.sensitive, &item.title);
+        item.synopsis = sanitize_text(&state.sensitive, &item.synopsis);
+        item.genre = sanitize_text(&state.sensitive, &item.genre);
+        item.language = sanitize_text(&state.sensitive, &item.language);
+    }
+
+    Ok(success_response(items))
+}
+
+pub(crate) async fn generate(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<GenerateRequest>,
+) -> Result<Response, Response> {
+    run_generate(state, addr, headers, payload, None).await
+}
+
+/// Starts a new game continuing one ending of a stored game (visible to the
+/// caller as with `/play/:id`). The request is derived from the parent by
+/// `build_sequel_request` and then runs the regular `/generate` pipeline;
+/// the new record keeps a link to its parent and ending.
+pub(crate) async fn generate_sequel(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<SequelRequest>,
+) -> Result<Response, Response> {
+    let parent = load_viewable_template(&state, req.id, &headers, &addr).await?;
+    let Some(payload) = build_sequel_request(&parent, &req) else {
+        return Err(error_response("NOT_FOUND", "Ending not found").into_response());
+    };
+    let link = SequelLink {
+        parent_id: req.id,
+        parent_ending: req.ending_key.trim().to_string(),
+    };
+    run_generate(state, addr, headers, payload, Some(link)).await
+}
+
+/// Checks the `/generate` options that have ranges or fixed values; the
+/// error is the message for the caller.
+fn validate_generate_options(payload: &GenerateRequest) -> Result<(), String> {
+    if let Some(n) = payload.exact_endings {
+        if n == 0 || n > MAX_EXACT_ENDINGS {
+            return Err(format!(
+                "exactEndings 必须在 1 到 {} 之间",
+                MAX_EXACT_ENDINGS
+            ));
+        }
+    }
+
+    if let Some(t) = payload.near_duplicate_threshold {
+        if !(t > 0.0 && t <= 1.0) {
+            return Err("nearDuplicateThreshold 必须在 0 到 1 之间（不含 0）".to_string());
+        }
+    }
+
+    if OrphanEndingPolicy::parse(payload.orphan_endings.as_deref()).is_none() {
+        return Err("orphanEndings 只能为 link 或 prune".to_string());
+    }
+
+    if let Some(n) = payload.quick_ending_level {
+        // Level 1 is `start`; the quick ending cannot sit deeper than the node budget.
+        let max_level = payload
+            .max_nodes
+            .map_or(MAX_QUICK_ENDING_LEVEL, |m| m.min(MAX_QUICK_ENDING_LEVEL));
+        if !(2..=max_level).contains(&n) {
+            return Err(format!("quickEndingLevel 必须在 2 到 {} 之间", max_level));
+        }
+    }
+
+    if let Some(n) = payload.min_path_depth {
+        if !(2..=MAX_MIN_PATH_DEPTH).contains(&n) {
+            return Err(format!(
+                "minPathDepth 必须在 2 到 {} 之间",
+                MAX_MIN_PATH_DEPTH
+            ));
+        }
+    }
+
+    validate_image_options(payload)
+}
+
+/// Chat request for a whole template from `prompt`.
+fn generate_request_body(
+    model: &str,
+    prompt: &str,
+    temperature: f64,
+    max_tokens: Option<u32>,
+) -> serde_json::Value {
+    let messages = vec![
+        json!({
+            "role": "system",
+            "content": "You are a professional interactive movie scriptwriter and game designer. You output ONLY valid JSON. You never output markdown code blocks. You strictly follow the provided TypeScript interface definitions."
+        }),
+        json!({
+            "role": "user",
+            "content": prompt
+        }),
+    ];
+
+    json!({
+        "model": model,
+        "messages": messages,
+        "response_format": { "type": "json_object" },
+        "temperature": temperature,
+        "top_p": 0.95,
+        "max_tokens": glm::resolve_max_tokens(model, max_tokens)
+    })
+}
+
+/// Parent of a generation started by `/generate/sequel`.
+struct SequelLink {
+    parent_id: Uuid,
+    parent_ending: String,
+}
+
+/// What `/generate` and `/generate/stream` settle before calling GLM: the
+/// checked and sanitized request, its model, prompt and chat body, and the
+/// request summary. Built by `prepare_generation`.
+pub(crate) struct PreparedGeneration {
+    pub(crate) payload: GenerateRequest,
+    quality: QualityPreset,
+    client_ip: String,
+    user_agent: String,
+    trusted: bool,
+    using_override_key: bool,
+    per_node_backgrounds: bool,
+    model: String,
+    prompt: String,
+    client: reqwest::Client,
+    /// Non-streaming chat body; parse-failure retries always use it.
+    request_body: serde_json::Value,
+    /// `prompt` as stored in `glm_prompt`, sensitive words masked.
+    prompt_for_log: String,
+    deadline: std::time::Duration,
+    warnings: Vec<GenerationWarning>,
+    summary: SharedRequestSummary,
+}
+
+/// Validates and sanitizes a generate request the same way for both
+/// routes; `route` only names the request summary. Errors are the response
+/// to send, already counted in the summary once it exists.
+pub(crate) async fn prepare_generation(
+    state: &AppState,
+    addr: &SocketAddr,
+    headers: &HeaderMap,
+    mut payload: GenerateRequest,
+    route: &'static str,
+) -> Result<PreparedGeneration, Response> {
+    if let Some(theme) = &payload.theme {
+        ensure_not_sensitive(&state.sensitive, theme, "主题", &payload)?;
+    }
+    // Check free_input as well if it acts as theme
+    if let Some(free_input) = &payload.free_input {
+         ensure_not_sensitive(&state.sensitive, free_input, "自由输入", &payload)?;
+    }
+
+    let mut quality = apply_quality_preset(&mut payload)
+        .map_err(|e| error_response(CODE_BAD_REQUEST, e).into_response())?;
+    quality.generate_images &= state.features.image_generation;
+
+    if let Err(msg) = validate_generate_options(&payload) {
+        return Err(error_response(CODE_BAD_REQUEST, msg).into_response());
+    }
+
+    let (mut payload, sensitive_hits) = sanitize_request_payload_counted(&state.sensitive, payload)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+    apply_accept_language(&mut payload, headers);
+    ensure_default_protagonist(&mut payload);
+
+    let client_ip = resolve_client_ip(headers, addr);
+    let trusted = take_trusted_key(&state.trusted, &client_ip, &mut payload.api_key);
+
+    let user_agent = headers
+        .get(axum::http::header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string();
+
+    let theme = payload
+        .theme
+        .as_deref()
+        .or(payload.free_input.as_deref())
+        .unwrap_or("Unknown Theme");
+    println!(
+        "Received generate request: {:?}",
+        sanitize_text(&state.sensitive, theme)
+    );
+
+    let prompt = construct_prompt(&payload);
+    println!("Prompt constructed.");
+
+    let mut warnings: Vec<GenerationWarning> = Vec::new();
+    let character_cap = prompt_character_cap();
+    let (_, omitted_characters) =
+        cap_prompt_characters(payload.characters.as_deref().unwrap_or(&[]), character_cap);
+    if omitted_characters > 0 {
+        warnings.push(GenerationWarning {
+            code: "CHARACTERS_TRUNCATED".to_string(),
+            message: format!(
+                "角色数量超过上限 {}，提示词中省略了 {} 个配角",
+                character_cap, omitted_characters
+            ),
+        });
+    }
+
+    let using_override_key = payload
+        .api_key
+        .as_ref()
+        .is_some_and(|k| !k.trim().is_empty());
+
+    // One CogView call per scene is expensive, so it only runs on the
+    // caller's own key.
+    // A deployment that turned the feature off ignores the flag silently.
+    let per_node_backgrounds =
+        state.features.per_node_backgrounds && payload.per_node_backgrounds.unwrap_or(false);
+    if per_node_backgrounds && !using_override_key {
+        warnings.push(GenerationWarning {
+            code: "NODE_BACKGROUNDS_REQUIRE_KEY".to_string(),
+            message: "逐节点背景图需要提供自有 apiKey，已忽略".to_string(),
+        });
+    }
+    let per_node_backgrounds = per_node_backgrounds && using_override_key;
+
+    let model = effective_model(
+        payload.model.as_deref(),
+        using_override_key,
+        MODEL_GENERATE_ENV,
+    );
+
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(240))
+        .build()
+        .map_err(|e| error_response(CODE_INTERNAL_ERROR, e.to_string()).into_response())?;
+
+    let request_body =
+        generate_request_body(&model, &prompt, quality.temperature, payload.max_tokens);
+    let prompt_for_log = sanitize_text(&state.sensitive, &prompt);
+    let deadline = resolve_request_deadline(
+        headers.get("x-deadline-ms").and_then(|v| v.to_str().ok()),
+        generate_soft_deadline(),
+    );
+
+    let summary = RequestSummary {
+        model: Some(model.clone()),
+        prompt_len: Some(count_display_chars(&prompt)),
+        sensitive_hits,
+        ..RequestSummary::new(route, &client_ip)
+    }
+    .shared();
+
+    if !state.burst_limiter.check(&client_ip) {
+        let res = rate_limit_response("请求过于频繁，请稍后再试").into_response();
+        emit_request_summary(&summary, res.status().as_u16());
+        return Err(res);
+    }
+
+    Ok(PreparedGeneration {
+        payload,
+        quality,
+        client_ip,
+        user_agent,
+        trusted,
+        using_override_key,
+        per_node_backgrounds,
+        model,
+        prompt,
+        client,
+        request_body,
+        prompt_for_log,
+        deadline,
+        warnings,
+        summary,
+    })
+}
+
+impl PreparedGeneration {
+    /// Opens the `glm_requests` row and stores the effective parameters.
+    /// Both routes log under `/generate` so they share one daily quota.
+    async fn begin_log(&self, state: &AppState) -> Result<Uuid, Response> {
+        let request_id = begin_glm_request_log(
+            &state.db,
+            &self.client_ip,
+            &self.user_agent,
+            "/generate",
+            self.logged_payload(state),
+            &self.prompt_for_log,
+            QuotaCheck::for_request(self.using_override_key, self.trusted),
+        )
+        .await
+        .map_err(|e| {
+            let res = db_error_response(e).into_response();
+            emit_request_summary(&self.summary, res.status().as_u16());
+            res
+        })?;
+        let effective = build_effective_params(&self.payload, &self.model, &self.quality);
+        save_effective_params(
+            &state.db,
+            request_id,
+            &serde_json::to_value(&effective).unwrap_or(json!({})),
+        )
+        .await;
+        Ok(request_id)
+    }
+
+    /// The request as stored in `request_payload`: no API key, avatars
+    /// redacted, sensitive words masked.
+    fn logged_payload(&self, state: &AppState) -> serde_json::Value {
+        let mut payload_json = serde_json::to_value(&self.payload).unwrap_or(json!({}));
+        if let Some(obj) = payload_json.as_object_mut() {
+            obj.remove("apiKey");
+        }
+        redact_character_avatars(&mut payload_json);
+        state.sensitive.sanitize_json(&mut payload_json);
+        payload_json
+    }
+
+    /// Makes a coalesced result this caller's own game. The leader's row only
+    /// lets its own client share or edit it, so any other client gets a copy
+    /// of the template recorded under its IP, like an import.
+    pub(crate) async fn adopt(
+        &self,
+        state: &AppState,
+        shared: SharedGeneration,
+    ) -> Result<GenerateResponse, Response> {
+        let SharedGeneration {
+            owner_ip,
+            mut generated,
+        } = shared;
+        if is_owner_ip(&owner_ip, &self.client_ip) {
+            return Ok(generated);
+        }
+        let template = serde_json::to_value(&generated.template).unwrap_or(json!({}));
+        generated.id = create_imported_request(
+            &state.db,
+            &self.client_ip,
+            &self.user_agent,
+            self.logged_payload(state),
+            template,
+            "coalesced",
+        )
+        .await
+        .map_err(|e| db_error_response(e).into_response())?;
+        Ok(generated)
+    }
+}
+
+async fn run_generate(
+    state: AppState,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    payload: GenerateRequest,
+    sequel_of: Option<SequelLink>,
+) -> Result<Response, Response> {
+    let mut prepared = prepare_generation(&state, &addr, &headers, payload, "/generate").await?;
+    prepared.payload.stored_avatars = sequel_of.is_some();
+    let summary = prepared.summary.clone();
+    let model = prepared.model.clone();
+
+    println!(
+        "Sending request to GLM (Prompt len: {})...",
+        prepared.prompt.len()
+    );
+    let start = std::time::Instant::now();
+
+    let minimal = prefers_minimal_return(&headers);
+    let ndjson = state.features.streaming && prefers_ndjson(&headers);
+    let respond_async = state.features.async_generate && prefers_respond_async(&headers);
+
+    // Identical free-tier requests share one GLM call; only the leader is
+    // logged as a generation and counted against quota. Callers paying with
+    // their own key always get their own generation, as do async callers,
+    // who need an id of their own to poll and cancel.
+    let mut flight_leader = None;
+    if !prepared.using_override_key && !respond_async {
+        match state.generations.join(&generation_flight_key(&prepared.payload)) {
+            Flight::Leader(leader) => flight_leader = Some(leader),
+            Flight::Follower(result) => {
+                if let Some(shared) = result.await {
+                    update_summary(&summary, |s| s.coalesced = true);
+                    let res = prepared
+                        .adopt(&state, shared)
+                        .await
+                        .map(|generated| generated_response(generated, minimal, ndjson));
+                    let status = match &res {
+                        Ok(r) | Err(r) => r.status(),
+                    };
+                    emit_request_summary(&summary, status.as_u16());
+                    return with_model_header(res, &model);
+                }
+                // The leader failed; its error may not apply here, so retry.
+            }
+        }
+    }
+
+    let request_id = prepared.begin_log(&state).await?;
+    if let Some(link) = sequel_of {
+        save_sequel_parent(&state.db, request_id, link.parent_id, &link.parent_ending).await;
+    }
+
+    let cancel = state.cancellations.register(request_id);
+    let db = state.db.clone();
+    let sensitive = state.sensitive.clone();
+    let owner_ip = prepared.client_ip.clone();
+
+    // Spawn a background task to handle the GLM request and DB updates
+    // This ensures the request completes and is recorded even if the client disconnects
+    let handle = tokio::spawn(async move {
+        let run = GenerationRun {
+            db: &db,
+            sensitive: &sensitive,
+            request_id,
+            prepared: &prepared,
+            cancel: &cancel,
+            start,
+        };
+        match run.generate().await {
+            Ok(generated) => Ok(generated),
+            Err(failure) => Err(run.fail(failure).await),
+        }
+    });
+
+    let finished = async move {
+        let res = match handle.await {
+            Ok(res) => res,
             Err(e) => {
-                let response_time_ms = duration.as_millis().min(i64::MAX as u128) as i64;
-                let text_response_s = sanitize_text(&sensitive, &text_response);
-                finish_glm_request_log(
-                    &db,
-                    request_id,
+                eprintln!("Task join error: {}", e);
+                Err(error_response(CODE_INTERNAL_ERROR, "Internal Server Error").into_response())
+            }
+        };
+        if let (Some(leader), Ok(generated)) = (flight_leader, &res) {
+            leader.finish(SharedGeneration {
+                owner_ip,
+                generated: generated.clone(),
+            });
+        }
+        res
+    };
+
+    if respond_async {
+        let summary = summary.clone();
+        tokio::spawn(async move {
+            let status = match finished.await {
+                Ok(_) => StatusCode::OK,
+                Err(r) => r.status(),
+            };
+            emit_request_summary(&summary, status.as_u16());
+        });
+        return with_model_header(Ok(accepted_generate_response(request_id)), &model);
+    }
+
+    let res = finished
+        .await
+        .map(|generated| generated_response(generated, minimal, ndjson));
+    let status = match &res {
+        Ok(r) | Err(r) => r.status(),
+    };
+    emit_request_summary(&summary, status.as_u16());
+    with_model_header(res, &model)
+}
+
+/// Chat endpoint and key a generation calls. Resolved inside the generation
+/// task so that a bad `baseUrl` or a missing key closes the log row.
+struct GlmTarget {
+    endpoint: String,
+    api_key: String,
+}
+
+impl GlmTarget {
+    fn resolve(payload: &GenerateRequest) -> Result<Self, GenerationFailure> {
+        let endpoint = resolve_glm_endpoint(payload.base_url.as_deref()).map_err(|_| {
+            GenerationFailure::new("failed", CODE_INVALID_BASE_URL, "Invalid baseUrl")
+        })?;
+        let api_key =
+            resolve_glm_api_key(payload.api_key.as_deref()).map_err(|_| GenerationFailure {
+                error_text: "Missing GLM API Key".to_string(),
+                ..GenerationFailure::new(
                     "failed",
-                    Some(&text_response_s),
-                    Some(&format!("Failed to parse GLM response JSON: {}", e)),
-                    Some(response_time_ms),
+                    "API_KEY_REQUIRED",
+                    "API Key is required. Please configure your own API Key in settings.",
                 )
-                .await;
-                return Err(
-                    error_response(CODE_INTERNAL_ERROR, "Failed to parse GLM response").into_response(),
-                );
+            })?;
+        Ok(Self { endpoint, api_key })
+    }
+}
+
+/// Why a generation stopped after its log row was opened; see
+/// `GenerationRun::fail` and the `/generate/stream` task.
+pub(crate) struct GenerationFailure {
+    /// `glm_requests.status`: `failed`, `error` or `cancel`.
+    pub(crate) status: &'static str,
+    pub(crate) code: &'static str,
+    message: String,
+    error_text: String,
+    response: Option<String>,
+}
+
+impl GenerationFailure {
+    fn new(status: &'static str, code: &'static str, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self {
+            status,
+            code,
+            error_text: message.clone(),
+            message,
+            response: None,
+        }
+    }
+
+    fn cancelled() -> Self {
+        Self {
+            error_text: "cancelled by client".to_string(),
+            ..Self::new("cancel", CODE_CANCELLED, "生成已取消")
+        }
+    }
+
+    fn deadline_exceeded() -> Self {
+        Self {
+            error_text: "deadline exceeded".to_string(),
+            ..Self::new("failed", CODE_DEADLINE_EXCEEDED, "生成超出请求截止时间")
+        }
+    }
+
+    /// Closes the request's log row with this failure.
+    async fn log(&self, db: &sqlx::PgPool, request_id: Uuid, start: std::time::Instant) {
+        let response_time_ms = start.elapsed().as_millis().min(i64::MAX as u128) as i64;
+        finish_glm_request_log(
+            db,
+            request_id,
+            self.status,
+            self.response.as_deref(),
+            Some(&self.error_text),
+            Some(response_time_ms),
+        )
+        .await;
+    }
+}
+
+/// A generation whose log row is open, from the GLM call on. The two routes
+/// only differ in how they call GLM; what follows is `finish`.
+pub(crate) struct GenerationRun<'a> {
+    pub(crate) db: &'a sqlx::PgPool,
+    pub(crate) sensitive: &'a SensitiveFilter,
+    pub(crate) request_id: Uuid,
+    pub(crate) prepared: &'a PreparedGeneration,
+    pub(crate) cancel: &'a CancelToken,
+    pub(crate) start: std::time::Instant,
+}
+
+impl GenerationRun<'_> {
+    /// Logs `failure` and turns it into the response for the caller.
+    pub(crate) async fn fail(&self, failure: GenerationFailure) -> Response {
+        failure.log(self.db, self.request_id, self.start).await;
+        error_response(failure.code, failure.message).into_response()
+    }
+
+    /// Calls GLM once without streaming, then hands its output to `finish`.
+    pub(crate) async fn generate(&self) -> Result<GenerateResponse, GenerationFailure> {
+        let prepared = self.prepared;
+        let start = self.start;
+        let target = GlmTarget::resolve(&prepared.payload)?;
+
+        let send = prepared
+            .client
+            .post(&target.endpoint)
+            .header("Authorization", format!("Bearer {}", target.api_key))
+            .json(&prepared.request_body)
+            .send();
+        let budget = prepared.deadline.checked_sub(start.elapsed());
+        let response = match run_within_budget(budget, send).await {
+            Some(Ok(r)) => r,
+            Some(Err(e)) => {
+                eprintln!("GLM Request failed: {}", e);
+                return Err(GenerationFailure::new(
+                    "failed",
+                    CODE_INTERNAL_ERROR,
+                    "GLM Request failed",
+                ));
+            }
+            // Nothing usable exists before the story text, so there is no
+            // partial result to fall back on.
+            None => return Err(GenerationFailure::deadline_exceeded()),
+        };
+
+        let duration = start.elapsed();
+        let mut stages = StageClock::starting_at(start);
+        stages.lap(Stage::Glm);
+        println!("GLM Request took: {:?}", duration);
+        update_summary(&prepared.summary, |s| {
+            s.glm_latency_ms = Some(duration.as_millis().min(u64::MAX as u128) as u64)
+        });
+        if self.cancel.is_cancelled() {
+            return Err(GenerationFailure::cancelled());
+        }
+
+        // Reading the body spends the same budget as the call itself.
+        let budget = prepared.deadline.checked_sub(start.elapsed());
+        if !response.status().is_success() {
+            let error_text = run_within_budget(budget, response.text())
+                .await
+                .ok_or_else(GenerationFailure::deadline_exceeded)?
+                .unwrap_or_default();
+            return Err(self.glm_error(&error_text, true));
+        }
+
+        let text_response = match run_within_budget(budget, response.text()).await {
+            Some(Ok(t)) => t,
+            Some(Err(e)) => {
+                return Err(GenerationFailure::new(
+                    "failed",
+                    CODE_INTERNAL_ERROR,
+                    format!("Failed to read response body: {}", e),
+                ));
+            }
+            None => return Err(GenerationFailure::deadline_exceeded()),
+        };
+
+        // Try to parse as generic JSON first to check for "error" field
+        if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&text_response) {
+            if json_value.get("error").is_some() || glm::is_content_policy_error(&text_response) {
+                println!("GLM returned 200 OK but with error body");
+                return Err(self.glm_error(&text_response, false));
+            }
+        }
+
+        let response_json: serde_json::Value = match serde_json::from_str(&text_response) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(GenerationFailure {
+                    error_text: format!("Failed to parse GLM response JSON: {}", e),
+                    response: Some(sanitize_text(self.sensitive, &text_response)),
+                    ..GenerationFailure::new(
+                        "failed",
+                        CODE_INTERNAL_ERROR,
+                        "Failed to parse GLM response",
+                    )
+                });
             }
         };
 
@@ -1097,127 +3731,155 @@ pub(crate) async fn generate(
             }
         }
 
-        let content = match response_json["choices"][0]["message"]["content"].as_str() {
-            Some(c) => c,
-            None => {
-                let response_time_ms = duration.as_millis().min(i64::MAX as u128) as i64;
-                finish_glm_request_log(
-                    &db,
-                    request_id,
-                    "failed",
-                    None,
-                    Some("Invalid GLM response structure"),
-                    Some(response_time_ms),
-                )
-                .await;
-                return Err(
-                    error_response(CODE_INTERNAL_ERROR, "Invalid GLM response structure")
-                        .into_response(),
-                );
-            }
+        let message_content = &response_json["choices"][0]["message"]["content"];
+        let Some(content) = glm::message_content_text(message_content) else {
+            return Err(GenerationFailure::new(
+                "failed",
+                CODE_INTERNAL_ERROR,
+                "Invalid GLM response structure",
+            ));
         };
 
-        println!("GLM Response Content Length: {}", content.len());
+        println!(
+            "GLM Response Content Length: {}",
+            count_display_chars(&content)
+        );
 
-        let clean_json_str = clean_json(content);
-        let response_time_ms = duration.as_millis().min(i64::MAX as u128) as i64;
+        self.finish(&target, content, stages, || false).await
+    }
+
+    /// An error GLM returned instead of a completion, classified the same
+    /// way for both routes. `limit_keyword` also treats any mention of
+    /// "limit" as rate limiting; a 200 carrying an error body leaves it off.
+    fn glm_error(&self, error_text: &str, limit_keyword: bool) -> GenerationFailure {
+        let error_text_s = sanitize_text(self.sensitive, error_text);
+        eprintln!(
+            "GLM Error: {}",
+            truncate_chars(&error_text_s, LOG_PREVIEW_CHARS)
+        );
+        let failure = |code, message: String| GenerationFailure {
+            error_text: error_text_s.clone(),
+            ..GenerationFailure::new("error", code, message)
+        };
+
+        // Check for GLM error code 1305 (rate limit)
+        if glm::is_rate_limit_error(error_text) {
+            let message = match glm::extract_glm_error_code(error_text) {
+                Some(code) => format!("GLM API 返回错误码 {}: {}", code, error_text_s),
+                None => error_text_s.clone(),
+            };
+            return failure(CODE_TOO_MANY_REQUESTS, message);
+        }
+        if limit_keyword && glm::contains_limit(error_text) {
+            return failure(CODE_TOO_MANY_REQUESTS, error_text_s.clone());
+        }
+        if glm::is_content_policy_error(error_text) {
+            eprintln!(
+                "GLM content policy rejection (code {})",
+                glm::extract_glm_error_code(error_text)
+                    .as_deref()
+                    .unwrap_or("-")
+            );
+            return failure(CODE_CONTENT_POLICY, CONTENT_POLICY_MESSAGE.to_string());
+        }
+        if glm::is_model_not_found_error(error_text) {
+            return failure(
+                CODE_BAD_REQUEST,
+                format!("模型 {} 不可用，请检查 model 参数", self.prepared.model),
+            );
+        }
+        failure(CODE_INTERNAL_ERROR, error_text_s.clone())
+    }
 
-        let template_lite: MovieTemplateLite = match serde_json::from_str(&clean_json_str) {
+    /// Turns the model's `content` into the stored template: parse (with
+    /// cooler retries on invalid JSON), post-process, images as the quality
+    /// preset allows, then save and log the success. `stages` has the GLM
+    /// call lapped; `abandoned` reports a caller that went away meanwhile.
+    async fn finish(
+        &self,
+        target: &GlmTarget,
+        content: String,
+        mut stages: StageClock,
+        abandoned: impl Fn() -> bool,
+    ) -> Result<GenerateResponse, GenerationFailure> {
+        let prepared = self.prepared;
+        let payload = &prepared.payload;
+        let mut warnings = prepared.warnings.clone();
+
+        stages.lap(Stage::Process);
+        let parsed = parse_with_cooler_retries(
+            content,
+            prepared.quality.temperature,
+            parse_retry_limit(),
+            |temperature, rejected| self.retry_parse(target, temperature, rejected.to_string()),
+        )
+        .await;
+        if !parsed.retried_at.is_empty() {
+            stages.lap(Stage::Glm);
+            update_summary(&prepared.summary, |s| s.parse_retries = parsed.retried_at.len());
+        }
+        let ParsedContent {
+            content,
+            cleaned: clean_json_str,
+            result: parsed,
+            ..
+        } = parsed;
+
+        let template_lite: MovieTemplateLite = match parsed {
             Ok(t) => {
                 println!("JSON deserialization successful. Converting to full template.");
+                update_summary(&prepared.summary, |s| s.parse_result = Some("ok"));
                 t
             }
             Err(e) => {
-                eprintln!("JSON Error: {}", e);
-                let response_time_ms = duration.as_millis().min(i64::MAX as u128) as i64;
-                let content_s = sanitize_text(&sensitive, content);
-                finish_glm_request_log(
-                    &db,
-                    request_id,
-                    "failed",
-                    Some(&content_s),
-                    Some(&format!("JSON Parse Error: {}", e)),
-                    Some(response_time_ms),
-                )
-                .await;
-                return Err(
-                    error_response(CODE_INTERNAL_ERROR, format!("JSON Parse Error: {}", e))
-                        .into_response(),
-                );
+                let diagnostic = ParseDiagnostic::new(&content, &clean_json_str, &e);
+                eprintln!("{}", diagnostic);
+                update_summary(&prepared.summary, |s| s.parse_result = Some("invalid_json"));
+                return Err(GenerationFailure {
+                    error_text: sanitize_text(self.sensitive, &diagnostic.to_string()),
+                    response: Some(sanitize_text(self.sensitive, &content)),
+                    ..GenerationFailure::new(
+                        "failed",
+                        CODE_INTERNAL_ERROR,
+                        format!("JSON Parse Error: {}", e),
+                    )
+                });
             }
         };
 
-        let language_tag = payload_clone.language.as_deref().unwrap_or("zh-CN");
+        let language_tag = payload.language.as_deref().unwrap_or("zh-CN");
         let mut template = convert_lite_to_full(template_lite, language_tag);
-        normalize_character_ids(&mut template);
-        normalize_template_nodes(&mut template);
-        normalize_template_endings(&mut template);
-
-        // Only ensure minimum graph if GLM returned nothing - never overwrite GLM's data
-        // ensure_minimum_game_graph call removed to prevent write-dead data injection
-
-        // NO character modifications - preserve GLM's original output
-        // ensure_request_characters_present(&mut template, &payload);
-
-        // User insisted: "Must return character info passed by frontend exactly as is"
-        crate::template::enforce_character_consistency(&mut template, payload_clone.characters.clone());
-
-        normalize_character_ids(&mut template);
-        normalize_template_endings(&mut template);
-        sanitize_template_graph(&mut template);
-        sanitize_affinity_effects(&mut template);
+        if let Err((_, msg)) = check_node_limit(template.nodes.len(), max_nodes_hard_limit()) {
+            return Err(GenerationFailure {
+                response: Some(sanitize_text(self.sensitive, &content)),
+                ..GenerationFailure::new("failed", CODE_INTERNAL_ERROR, msg)
+            });
+        }
+        warnings.extend(finish_generated_template(&mut template, payload));
+        stages.lap(Stage::Process);
+        if self.cancel.is_cancelled() {
+            return Err(GenerationFailure::cancelled());
+        }
 
-        // Image generation logic
-        let should_generate_images = if using_override_key {
-            let standard_url = "https://open.bigmodel.cn/api/paas/v4/chat/completions";
-            let input_url = payload_clone.base_url.as_deref().unwrap_or("").trim();
-            input_url.is_empty() || input_url == standard_url
-        } else {
-            true
-        };
+        let image_errors = self
+            .attach_images(target, &mut template, &mut warnings)
+            .await;
+        stages.lap(Stage::Images);
 
-        if should_generate_images {
-            let size = normalize_cogview_size(payload_clone.size.as_deref());
-            let synopsis_for_image = pick_background_prompt(&payload_clone, &template);
-            match generate_scene_background_base64(
-                &client,
-                &synopsis_for_image,
-                language_tag,
-                &size,
-                &api_key,
-            )
-            .await
-            {
-                Ok(img) => template.background_image_base64 = Some(img),
-                Err(_) => {
-                    template.background_image_base64 = Some(fallback_background_data_uri(
-                        &template.title,
-                        &synopsis_for_image,
-                    ))
-                }
-            }
+        ensure_avatar_fallbacks(&mut template, payload.characters.as_ref());
+        update_summary(&prepared.summary, |s| s.record_template(&template));
 
-            maybe_attach_generated_avatars(
-                &client,
-                &mut template,
-                payload_clone.characters.as_ref(),
-                language_tag,
-                &api_key,
-            )
-            .await;
-        } else {
-            template.background_image_base64 = Some(fallback_background_data_uri(
-                &template.title,
-                &template.meta.synopsis,
-            ));
+        // Past this point a cancel request is refused, so a saved result is
+        // never reported as cancelled or the other way round. A caller that
+        // left during post-processing never sees the result either.
+        if abandoned() || !self.cancel.complete() {
+            return Err(GenerationFailure::cancelled());
         }
 
-        ensure_avatar_fallbacks(&mut template, payload_clone.characters.as_ref());
-
         let template_value = serde_json::to_value(&template).unwrap_or(json!({}));
 
         // Save the processed template (original, not sanitized)
-        if let Err(e) = save_processed_response(&db, request_id, &template_value).await {
+        if let Err(e) = save_processed_response(self.db, self.request_id, &template_value).await {
             eprintln!("Failed to save processed response: {}", e);
         }
 
@@ -1235,32 +3897,399 @@ pub(crate) async fn generate(
         // Let's keep the log sanitized (for compliance) but ensure the FRONTEND gets raw.
         // The code ALREADY does this: `save_processed_response` uses `template_value` (derived from `template`, which is raw).
         // So `generate` handler is correct.
-        
-        // Log raw content as per user demand
+
+        // Log raw content as per user demand; image failures (if any) go to error_text
+        let image_error_text = (!image_errors.is_empty()).then(|| image_errors.join("; "));
+        stages.lap(Stage::Process);
+        let timings = stages.timings();
+        update_summary(&prepared.summary, |s| {
+            s.images_ms = Some(timings.images_ms as u64);
+            s.process_ms = Some(timings.process_ms as u64);
+        });
+        save_stage_timings(self.db, self.request_id, &timings).await;
+        // On success `response_time_ms` is the whole call, not just GLM
+        let total_ms = self.start.elapsed().as_millis().min(i64::MAX as u128) as i64;
         finish_glm_request_log(
-            &db,
-            request_id,
+            self.db,
+            self.request_id,
             "success",
-            Some(content),
-            None,
-            Some(response_time_ms),
+            Some(&content),
+            image_error_text.as_deref(),
+            Some(total_ms),
         )
         .await;
 
-        Ok(success_response(GenerateResponse {
-            id: request_id,
+        Ok(GenerateResponse {
+            id: self.request_id,
             template,
+            warnings,
         })
-        .into_response())
+    }
+
+    /// One parse-failure retry at `temperature`, logged as its own
+    /// `glm_requests` row like the worldview length retry. The row's payload
+    /// names the generation it belongs to and the output it replaces.
+    async fn retry_parse(
+        &self,
+        target: &GlmTarget,
+        temperature: f64,
+        rejected: String,
+    ) -> Result<String, String> {
+        let prepared = self.prepared;
+        let payload = json!({
+            "retryOf": self.request_id,
+            "temperature": temperature,
+            "rejectedOutput": sanitize_text(self.sensitive, &rejected),
+        });
+        let retry_id = begin_glm_request_log(
+            self.db,
+            &prepared.client_ip,
+            &prepared.user_agent,
+            GENERATE_RETRY_ROUTE,
+            payload,
+            &prepared.prompt_for_log,
+            QuotaCheck::Internal,
+        )
+        .await
+        .map_err(|e| format!("Database error: {:?}", e))?;
+
+        let start = std::time::Instant::now();
+        let result = request_glm_content(
+            &prepared.client,
+            &target.endpoint,
+            &target.api_key,
+            &prepared.request_body,
+            temperature,
+            prepared.deadline.checked_sub(self.start.elapsed()),
+        )
+        .await;
+        let response_time_ms = start.elapsed().as_millis().min(i64::MAX as u128) as i64;
+        match &result {
+            Ok(content) => {
+                finish_glm_request_log(
+                    self.db,
+                    retry_id,
+                    "success",
+                    Some(content),
+                    None,
+                    Some(response_time_ms),
+                )
+                .await;
+            }
+            Err(e) => {
+                finish_glm_request_log(
+                    self.db,
+                    retry_id,
+                    "failed",
+                    None,
+                    Some(&sanitize_text(self.sensitive, e)),
+                    Some(response_time_ms),
+                )
+                .await;
+            }
+        }
+        result
+    }
+
+    /// Background, avatars and (on request) node backgrounds, within what is
+    /// left of the deadline; everything that fails falls back to the SVG
+    /// placeholders. Returns the image errors for the log.
+    async fn attach_images(
+        &self,
+        target: &GlmTarget,
+        template: &mut MovieTemplate,
+        warnings: &mut Vec<GenerationWarning>,
+    ) -> Vec<String> {
+        let prepared = self.prepared;
+        let payload = &prepared.payload;
+        let client = &prepared.client;
+        let api_key = &target.api_key;
+
+        // Image generation logic: images go through the same base URL as chat;
+        // `quality: fast` drafts skip CogView and use the SVG fallbacks
+        let image_endpoint = resolve_image_endpoint(payload.base_url.as_deref())
+            .ok()
+            .filter(|_| prepared.quality.generate_images);
+
+        let mut image_errors: Vec<String> = Vec::new();
+        let Some(image_endpoint) = image_endpoint else {
+            template.background_image_base64 = Some(fallback_background_data_uri(
+                &template.title,
+                &template.meta.synopsis,
+            ));
+            return image_errors;
+        };
+
+        let language_tag = payload.language.as_deref().unwrap_or("zh-CN");
+        let mut image_options = resolve_image_options(payload, prepared.using_override_key);
+        image_options.endpoint = image_endpoint;
+        let size = normalize_cogview_size(payload.size.as_deref());
+        let synopsis_for_image = pick_background_prompt(payload, template);
+        let image_language = pick_image_prompt_language(payload, language_tag);
+        // A slow GLM call leaves little of the soft deadline; rather than
+        // risk the whole request timing out, return the text with fallbacks.
+        let budget = image_time_budget(self.start.elapsed(), prepared.deadline);
+        let finished = run_within_budget(budget, async {
+            match generate_scene_background_base64(
+                client,
+                &synopsis_for_image,
+                image_language,
+                &size,
+                api_key,
+                &image_options,
+            )
+            .await
+            {
+                Ok(img) => template.background_image_base64 = Some(img),
+                Err(e) => {
+                    eprintln!("Background image generation failed: {}", e);
+                    image_errors.push(format!("background {}", e));
+                    template.background_image_base64 = Some(fallback_background_data_uri(
+                        &template.title,
+                        &synopsis_for_image,
+                    ))
+                }
+            }
+
+            let avatar_errors = maybe_attach_generated_avatars(
+                client,
+                template,
+                payload.characters.as_ref(),
+                image_language,
+                api_key,
+                &image_options,
+            )
+            .await;
+            for e in avatar_errors {
+                eprintln!("Avatar image generation failed: {}", e);
+                image_errors.push(format!("avatar {}", e));
+            }
+
+            if prepared.per_node_backgrounds {
+                let node_errors = attach_node_backgrounds(
+                    client,
+                    template,
+                    image_language,
+                    &size,
+                    api_key,
+                    &image_options,
+                    max_node_backgrounds(),
+                )
+                .await;
+                for e in node_errors {
+                    eprintln!("Node background generation failed: {}", e);
+                    image_errors.push(format!("node background {}", e));
+                }
+            }
+        })
+        .await;
+        if finished.is_none() {
+            image_errors.push("images skipped: soft deadline".to_string());
+            warnings.push(GenerationWarning {
+                code: "IMAGES_SKIPPED_DEADLINE".to_string(),
+                message: "生成耗时接近上限，已跳过图片生成并使用占位图".to_string(),
+            });
+            if template.background_image_base64.is_none() {
+                template.background_image_base64 = Some(fallback_background_data_uri(
+                    &template.title,
+                    &synopsis_for_image,
+                ));
+            }
+        } else if !image_errors.is_empty() {
+            warnings.push(GenerationWarning {
+                code: "IMAGE_PLACEHOLDER".to_string(),
+                message: format!("{} 张图片生成失败，已使用占位图", image_errors.len()),
+            });
+        }
+        image_errors
+    }
+}
+
+/// Streams a generation as Server-Sent Events: `start` with the request id
+/// (usable with `/generate/cancel/:id`), one `delta` per piece of model text
+/// as GLM produces it, then `template` with the same result `/generate`
+/// returns (parse retries and images included), or `error`.
+pub(crate) async fn generate_stream(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<GenerateRequest>,
+) -> Result<Response, Response> {
+    if !state.features.streaming {
+        return Err(error_response("NOT_FOUND", "流式生成未开启").into_response());
+    }
+    let prepared =
+        prepare_generation(&state, &addr, &headers, payload, "/generate/stream").await?;
+    let request_id = prepared.begin_log(&state).await?;
+    let model = prepared.model.clone();
+
+    let cancel = state.cancellations.register(request_id);
+    let db = state.db.clone();
+    let sensitive = state.sensitive.clone();
+    let start = std::time::Instant::now();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Event>();
+    let _ = tx.send(stream_event("start", json!({ "id": request_id })));
+
+    // The task outlives a client that goes away, so the log row is always
+    // closed: as `cancel` when the deltas stop being received.
+    tokio::spawn(async move {
+        let job = StreamJob {
+            run: GenerationRun {
+                db: &db,
+                sensitive: &sensitive,
+                request_id,
+                prepared: &prepared,
+                cancel: &cancel,
+                start,
+            },
+            tx: &tx,
+        };
+        let status = match job.run().await {
+            Ok(generated) => {
+                let data = serde_json::to_value(&generated).unwrap_or(json!({}));
+                let _ = tx.send(stream_event("template", data));
+                StatusCode::OK
+            }
+            Err(failure) => {
+                failure.log(&db, request_id, start).await;
+                let data = json!({ "code": failure.code, "msg": failure.message });
+                let _ = tx.send(stream_event("error", data));
+                status_for_code(failure.code)
+            }
+        };
+        emit_request_summary(&prepared.summary, status.as_u16());
     });
 
-    match handle.await {
-        Ok(res) => res,
-        Err(e) => {
-            eprintln!("Task join error: {}", e);
-            Err(error_response(CODE_INTERNAL_ERROR, "Internal Server Error").into_response())
+    let events = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|event| (Ok::<_, std::convert::Infallible>(event), rx))
+    });
+    let res = Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response();
+    with_model_header(Ok(res), &model)
+}
+
+/// SSE event whose data is `data` as JSON; model text always travels inside
+/// JSON, which keeps its line breaks out of the event framing.
+fn stream_event(name: &str, data: serde_json::Value) -> Event {
+    Event::default().event(name).data(data.to_string())
+}
+
+/// What a `/generate/stream` task needs after the response has started.
+pub(crate) struct StreamJob<'a> {
+    pub(crate) run: GenerationRun<'a>,
+    pub(crate) tx: &'a tokio::sync::mpsc::UnboundedSender<Event>,
+}
+
+impl StreamJob<'_> {
+    /// Calls GLM with streaming on and forwards its deltas, then hands the
+    /// whole output to the shared `/generate` pipeline.
+    pub(crate) async fn run(&self) -> Result<GenerateResponse, GenerationFailure> {
+        let prepared = self.run.prepared;
+        let start = self.run.start;
+        let target = GlmTarget::resolve(&prepared.payload)?;
+
+        let mut request_body = prepared.request_body.clone();
+        request_body["stream"] = json!(true);
+        let send = prepared
+            .client
+            .post(&target.endpoint)
+            .header("Authorization", format!("Bearer {}", target.api_key))
+            .json(&request_body)
+            .send();
+        let budget = prepared.deadline.checked_sub(start.elapsed());
+        let mut response = match run_within_budget(budget, send).await {
+            Some(Ok(r)) => r,
+            Some(Err(e)) => {
+                eprintln!("GLM Request failed: {}", e);
+                return Err(GenerationFailure::new(
+                    "failed",
+                    CODE_INTERNAL_ERROR,
+                    "GLM Request failed",
+                ));
+            }
+            None => return Err(GenerationFailure::deadline_exceeded()),
+        };
+        if !response.status().is_success() {
+            let budget = prepared.deadline.checked_sub(start.elapsed());
+            let error_text = run_within_budget(budget, response.text())
+                .await
+                .ok_or_else(GenerationFailure::deadline_exceeded)?
+                .unwrap_or_default();
+            return Err(self.run.glm_error(&error_text, true));
         }
+
+        let budget = prepared.deadline.checked_sub(start.elapsed());
+        let read = glm::read_chat_stream(&mut response, |text| {
+            !self.run.cancel.is_cancelled()
+                && self
+                    .tx
+                    .send(stream_event("delta", json!({ "text": text })))
+                    .is_ok()
+        });
+        let streamed = run_within_budget(budget, read)
+            .await
+            .ok_or_else(GenerationFailure::deadline_exceeded)?;
+        let glm_ms = start.elapsed().as_millis().min(u64::MAX as u128) as u64;
+        update_summary(&prepared.summary, |s| s.glm_latency_ms = Some(glm_ms));
+        let content = match streamed {
+            Ok(Some(content)) => content,
+            Ok(None) => return Err(GenerationFailure::cancelled()),
+            Err(e) => return Err(self.run.glm_error(&e, true)),
+        };
+        println!(
+            "GLM Stream Content Length: {}",
+            count_display_chars(&content)
+        );
+
+        let mut stages = StageClock::starting_at(start);
+        stages.lap(Stage::Glm);
+        self.run
+            .finish(&target, content, stages, || self.tx.is_closed())
+            .await
+    }
+}
+
+/// Who may cancel a generation: its owner, while it is still running.
+pub(crate) fn check_cancellable(
+    row: Option<(String, String)>,
+    request_ip: &str,
+) -> Result<(), (&'static str, &'static str)> {
+    let Some((owner_ip, status)) = row else {
+        return Err(("NOT_FOUND", "Request not found"));
+    };
+    if !is_owner_ip(&owner_ip, request_ip) {
+        return Err(("FORBIDDEN", "You are not the owner of this request"));
+    }
+    if status != "running" {
+        return Err((CODE_CONFLICT, "生成已结束，无法取消"));
+    }
+    Ok(())
+}
+
+pub(crate) async fn cancel_generation(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse<serde_json::Value>>, Response> {
+    let row = get_request_owner(&state.db, id).await.map_err(|e| {
+        eprintln!("Database error: {}", e);
+        db_error_response(DbError::from_sqlx(e)).into_response()
+    })?;
+
+    let request_ip = resolve_client_ip(&headers, &addr);
+    check_cancellable(row, &request_ip)
+        .map_err(|(code, msg)| error_response(code, msg).into_response())?;
+
+    // The task may be finishing right now, or run on another instance.
+    if !state.cancellations.cancel(id) {
+        return Err(error_response(CODE_CONFLICT, "生成已结束，无法取消").into_response());
     }
+
+    Ok(success_response(json!({ "id": id, "cancelled": true })))
 }
 
 pub(crate) async fn expand_worldview_prompt(
@@ -1286,9 +4315,10 @@ pub(crate) async fn expand_worldview(
     Json(req): Json<ExpandWorldviewRequest>,
 ) -> Result<Response, Response> {
     ensure_not_sensitive(&state.sensitive, &req.theme, "主题", &req)?;
-    let req = sanitize_request_payload(&state.sensitive, req)?;
+    let mut req = sanitize_request_payload(&state.sensitive, req)?;
 
     let client_ip = resolve_client_ip(&headers, &addr);
+    let trusted = take_trusted_key(&state.trusted, &client_ip, &mut req.api_key);
 
     let user_agent = headers
         .get(axum::http::header::USER_AGENT)
@@ -1313,6 +4343,11 @@ pub(crate) async fn expand_worldview(
         .build()
         .map_err(|e| error_response(CODE_INTERNAL_ERROR, e.to_string()).into_response())?;
 
+    if !state.burst_limiter.check(&client_ip) {
+        return Err(rate_limit_response("请求过于频繁，请稍后再试").into_response());
+    }
+
+    let retry_log_payload = payload_json.clone();
     let request_id = begin_glm_request_log(
         &state.db,
         &client_ip,
@@ -1320,7 +4355,7 @@ pub(crate) async fn expand_worldview(
         "/expand/worldview",
         payload_json,
         &prompt_for_log,
-        using_override_key,
+        QuotaCheck::for_request(using_override_key, trusted),
     )
     .await
     .map_err(|e| db_error_response(e).into_response())?;
@@ -1328,6 +4363,13 @@ pub(crate) async fn expand_worldview(
     let db = state.db.clone();
     let sensitive = state.sensitive.clone();
     let req_clone = req.clone();
+    let model = effective_model(
+        req.model.as_deref(),
+        using_override_key,
+        MODEL_WORLDVIEW_ENV,
+    );
+    let served_model = model.clone();
+    let user_agent = user_agent.to_string();
 
     let handle = tokio::spawn(async move {
         let start = std::time::Instant::now();
@@ -1365,12 +4407,6 @@ pub(crate) async fn expand_worldview(
             }
         };
 
-        let model = if using_override_key {
-            req_clone.model.as_deref().unwrap_or("glm-4.6v-flash")
-        } else {
-            "glm-4.6v-flash"
-        };
-
         let messages = vec![
             json!({
                 "role": "system",
@@ -1389,7 +4425,7 @@ pub(crate) async fn expand_worldview(
             // "response_format": { "type": "json_object" },
             "temperature": 1,
             "top_p": 0.95,
-            "max_tokens": 4096 // Adjusted reasonable limit for text expansion
+            "max_tokens": glm::resolve_max_tokens(&model, Some(4096)) // Adjusted reasonable limit for text expansion
         });
 
         let response = match client
@@ -1422,7 +4458,10 @@ pub(crate) async fn expand_worldview(
         if !response.status().is_success() {
             let error_text = response.text().await.unwrap_or_default();
             let error_text_s = sanitize_text(&sensitive, &error_text);
-            eprintln!("GLM Error: {}", error_text_s);
+            eprintln!(
+                "GLM Error: {}",
+                truncate_chars(&error_text_s, LOG_PREVIEW_CHARS)
+            );
 
             if glm::is_rate_limit_error(&error_text) {
                 let error_message = if let Some(code) = glm::extract_glm_error_code(&error_text) {
@@ -1466,6 +4505,12 @@ pub(crate) async fn expand_worldview(
             )
             .await;
 
+            if let Some(res) = content_policy_response(&error_text) {
+                return Err(res);
+            }
+            if let Some(res) = model_unavailable_response(&error_text, &model) {
+                return Err(res);
+            }
             return Err(error_response(CODE_INTERNAL_ERROR, error_text_s).into_response());
         }
 
@@ -1492,11 +4537,11 @@ pub(crate) async fn expand_worldview(
 
         // Try to parse as generic JSON first to check for "error" field
         if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&text_response) {
-            if json_value.get("error").is_some() {
+            if json_value.get("error").is_some() || glm::is_content_policy_error(&text_response) {
                 let text_response_s = sanitize_text(&sensitive, &text_response);
                 println!(
                     "GLM returned 200 OK but with error body: {}",
-                    text_response_s
+                    truncate_chars(&text_response_s, LOG_PREVIEW_CHARS)
                 );
                 finish_glm_request_log(
                     &db,
@@ -1533,8 +4578,9 @@ pub(crate) async fn expand_worldview(
             }
         };
 
-        let content = match response_json["choices"][0]["message"]["content"].as_str() {
-            Some(c) => c.to_string(),
+        let message_content = &response_json["choices"][0]["message"]["content"];
+        let content = match glm::message_content_text(message_content) {
+            Some(c) => c,
             None => {
                 finish_glm_request_log(
                     &db,
@@ -1563,16 +4609,129 @@ pub(crate) async fn expand_worldview(
         )
         .await;
 
+        let content = unwrap_worldview_text(&content);
+        let content = match construct_worldview_length_retry_prompt(&prompt, &content) {
+            Some(retry_prompt) => {
+                retry_worldview_length(
+                    &db,
+                    &sensitive,
+                    WorldviewRetryLog {
+                        client_ip: &client_ip,
+                        user_agent: &user_agent,
+                        payload: retry_log_payload,
+                    },
+                    retry_prompt,
+                    &req_clone,
+                    &model,
+                    content,
+                )
+                .await
+            }
+            None => content,
+        };
+
         // Return original content to frontend, log raw content to DB
         Ok(success_response(content).into_response())
     });
 
-    match handle.await {
+    let res = match handle.await {
         Ok(res) => res,
         Err(e) => {
             eprintln!("Task join error: {}", e);
             Err(error_response(CODE_INTERNAL_ERROR, "Internal Server Error").into_response())
         }
+    };
+    with_model_header(res, &served_model)
+}
+
+/// Logging context for the worldview length retry, which gets its own
+/// `glm_requests` row.
+struct WorldviewRetryLog<'a> {
+    client_ip: &'a str,
+    user_agent: &'a str,
+    payload: serde_json::Value,
+}
+
+/// Route the `/expand/worldview` length retry is logged under, so the retry
+/// never counts towards the client's `/expand/worldview` limits.
+pub(crate) const WORLDVIEW_RETRY_ROUTE: &str = "/expand/worldview/retry";
+
+/// Runs the single length-correction retry for `/expand/worldview` and
+/// returns whichever of the two synopses is closer to the requested range.
+/// Any failure keeps the first result.
+async fn retry_worldview_length(
+    db: &sqlx::PgPool,
+    sensitive: &SensitiveFilter,
+    log: WorldviewRetryLog<'_>,
+    retry_prompt: String,
+    req: &ExpandWorldviewRequest,
+    model: &str,
+    first: String,
+) -> String {
+    let retry_id = match begin_glm_request_log(
+        db,
+        log.client_ip,
+        log.user_agent,
+        WORLDVIEW_RETRY_ROUTE,
+        log.payload,
+        &sanitize_text(sensitive, &retry_prompt),
+        QuotaCheck::Internal,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("Database error: {:?}", e);
+            return first;
+        }
+    };
+
+    let start = std::time::Instant::now();
+    let result = glm::call_glm_with_api_key(
+        retry_prompt,
+        false,
+        req.api_key.clone(),
+        req.base_url.clone(),
+        Some(model.to_string()),
+    )
+    .await;
+    let response_time_ms = start.elapsed().as_millis().min(i64::MAX as u128) as i64;
+
+    match result {
+        Ok(second) => {
+            finish_glm_request_log(
+                db,
+                retry_id,
+                "success",
+                Some(&second),
+                None,
+                Some(response_time_ms),
+            )
+            .await;
+            pick_worldview_text(first, unwrap_worldview_text(&second))
+        }
+        Err(e) => {
+            finish_glm_request_log(
+                db,
+                retry_id,
+                "failed",
+                None,
+                Some(&sanitize_text(sensitive, &e)),
+                Some(response_time_ms),
+            )
+            .await;
+            first
+        }
+    }
+}
+
+/// Prefers the retry unless it lands further outside the worldview range.
+pub(crate) fn pick_worldview_text(first: String, retry: String) -> String {
+    let gap = |s: &str| worldview_length_gap(count_display_chars(s.trim()));
+    if retry.trim().is_empty() || gap(&retry) > gap(&first) {
+        first
+    } else {
+        retry
     }
 }
 
@@ -1583,101 +4742,18 @@ pub(crate) async fn expand_character(
     Json(req): Json<ExpandCharacterRequest>,
 ) -> Result<Response, Response> {
     ensure_not_sensitive(&state.sensitive, &req.theme, "主题", &req)?;
-    let req = sanitize_request_payload(&state.sensitive, req)?;
+    let mut req = sanitize_request_payload(&state.sensitive, req)?;
 
     let client_ip = resolve_client_ip(&headers, &addr);
+    let trusted = take_trusted_key(&state.trusted, &client_ip, &mut req.api_key);
 
     let user_agent = headers
         .get(axum::http::header::USER_AGENT)
         .and_then(|v| v.to_str().ok())
         .unwrap_or("unknown");
 
-    let language = req.language.as_deref().unwrap_or("zh-CN");
-    // Use worldview as the synopsis source since frontend sends it in 'worldview' field
-    let synopsis_content = if !req.worldview.is_empty() {
-        Some(&req.worldview)
-    } else {
-        req.synopsis.as_ref()
-    };
-
-    let prompt = if let Some(synopsis) = synopsis_content {
-        format!(
-            "你是一名资深电影编剧。
-
-请为一部【{}】电影，基于以下故事大纲，生成一个完整、立体、真实可信的角色设定。
-
-故事大纲：
-{}
-
-要求：
-1. 数量要求：至少生成 3 个主要角色（根据剧情复杂度可适当增加）。
-2. 角色基本信息（姓名、年龄、性别、职业、社会阶层）
-   - 性别字段是必填项，禁止为空！必须明确为 '男'、'女' 或 '其他'。
-3. 外貌特征（用于电影镜头表现）
-4. 性格特质（优点、缺点、矛盾点）
-5. 角色的“表层目标”（他/她现在想要什么）
-6. 角色的“深层需求”（内心真正缺失的东西）
-7. 角色的创伤或过去经历（推动性格形成）
-8. 角色在故事中的功能（主角 / 反派 / 配角 / 镜像角色）
-9. 角色可能经历的转变弧线（开场 → 结尾）
-10. 一句能概括该角色的核心主题句
-
-请避免模板化、脸谱化角色，强调现实逻辑与情感动机。
-
-# 语言要求
-输出语言：{}。
-
-# 输出格式
-请输出为 JSON 数组，格式如下：
-[
-  {{
-    \"name\": \"角色姓名\",
-    \"gender\": \"男\", // 严禁为空！必须是 \"男\" 或 \"女\" 或 \"其他\"
-    \"isMain\": true/false,
-    \"description\": \"这里包含上述所有详细设定（外貌、性格、目标、创伤等），请组织成一段通顺的文字或使用换行符分隔。\"
-  }}
-]
-注意：必须严格遵守 JSON 格式，不要包含 Markdown 代码块标记。",
-            req.theme, synopsis, language
-        )
-    } else {
-        format!(
-            "你是一名资深电影编剧。
-
-请为一部【{}】电影，生成一个完整、立体、真实可信的角色设定。
-
-要求：
-1. 数量要求：至少生成 3 个主要角色（根据剧情复杂度可适当增加）。
-2. 角色基本信息（姓名、年龄、性别、职业、社会阶层）
-   - 性别字段是必填项，禁止为空！必须明确为 '男'、'女' 或 '其他'。
-3. 外貌特征（用于电影镜头表现）
-4. 性格特质（优点、缺点、矛盾点）
-5. 角色的“表层目标”（他/她现在想要什么）
-6. 角色的“深层需求”（内心真正缺失的东西）
-7. 角色的创伤或过去经历（推动性格形成）
-8. 角色在故事中的功能（主角 / 反派 / 配角 / 镜像角色）
-9. 角色可能经历的转变弧线（开场 → 结尾）
-10. 一句能概括该角色的核心主题句
-
-请避免模板化、脸谱化角色，强调现实逻辑与情感动机。
-
-# 语言要求
-输出语言：{}。
-
-# 输出格式
-请输出为 JSON 数组，格式如下：
-[
-  {{
-    \"name\": \"角色姓名\",
-    \"gender\": \"男\", // 严禁为空！必须是 \"男\" 或 \"女\" 或 \"其他\"
-    \"isMain\": true/false,
-    \"description\": \"这里包含上述所有详细设定（外貌、性格、目标、创伤等），请组织成一段通顺的文字或使用换行符分隔。\"
-  }}
-]
-注意：必须严格遵守 JSON 格式，不要包含 Markdown 代码块标记。",
-            req.theme, language
-        )
-    };
+    // Same builder as `/expand/character/prompt`, so the preview matches what is sent.
+    let prompt = construct_expand_character_prompt(&req);
 
     let using_override_key = req.api_key.as_ref().is_some_and(|k| !k.trim().is_empty());
     let mut payload_json = serde_json::to_value(&req).unwrap_or(json!({}));
@@ -1694,6 +4770,10 @@ pub(crate) async fn expand_character(
     state.sensitive.sanitize_json(&mut payload_json);
     let prompt_for_log = sanitize_text(&state.sensitive, &prompt);
 
+    if !state.burst_limiter.check(&client_ip) {
+        return Err(rate_limit_response("请求过于频繁，请稍后再试").into_response());
+    }
+
     let request_id = begin_glm_request_log(
         &state.db,
         &client_ip,
@@ -1701,7 +4781,7 @@ pub(crate) async fn expand_character(
         "/expand/character",
         payload_json,
         &prompt_for_log,
-        using_override_key,
+        QuotaCheck::for_request(using_override_key, trusted),
     )
     .await
     .map_err(|e| db_error_response(e).into_response())?;
@@ -1709,6 +4789,12 @@ pub(crate) async fn expand_character(
     let db = state.db.clone();
     let sensitive = state.sensitive.clone();
     let req_clone = req.clone();
+    let model = effective_model(
+        req.model.as_deref(),
+        using_override_key,
+        MODEL_CHARACTER_ENV,
+    );
+    let served_model = model.clone();
 
     let handle = tokio::spawn(async move {
         let start = std::time::Instant::now();
@@ -1746,12 +4832,6 @@ pub(crate) async fn expand_character(
             }
         };
 
-        let model = if using_override_key {
-            req_clone.model.as_deref().unwrap_or("glm-4.6v-flash")
-        } else {
-            "glm-4.6v-flash"
-        };
-
         let messages = vec![
             json!({
                 "role": "system",
@@ -1769,7 +4849,7 @@ pub(crate) async fn expand_character(
             "response_format": { "type": "json_object" }, // Force JSON for character expansion
             "temperature": 1,
             "top_p": 0.95,
-            "max_tokens": 8192
+            "max_tokens": glm::resolve_max_tokens(&model, None)
         });
 
         let response = match client
@@ -1802,7 +4882,10 @@ pub(crate) async fn expand_character(
         if !response.status().is_success() {
             let error_text = response.text().await.unwrap_or_default();
             let error_text_s = sanitize_text(&sensitive, &error_text);
-            eprintln!("GLM Error: {}", error_text_s);
+            eprintln!(
+                "GLM Error: {}",
+                truncate_chars(&error_text_s, LOG_PREVIEW_CHARS)
+            );
 
             if glm::is_rate_limit_error(&error_text) {
                 let error_message = if let Some(code) = glm::extract_glm_error_code(&error_text) {
@@ -1845,6 +4928,12 @@ pub(crate) async fn expand_character(
                 Some(response_time_ms),
             )
             .await;
+            if let Some(res) = content_policy_response(&error_text) {
+                return Err(res);
+            }
+            if let Some(res) = model_unavailable_response(&error_text, &model) {
+                return Err(res);
+            }
             return Err(error_response(CODE_INTERNAL_ERROR, error_text_s).into_response());
         }
 
@@ -1888,11 +4977,11 @@ pub(crate) async fn expand_character(
 
         // Check for 200 OK error
         if let Ok(json_value) = serde_json::from_str::<serde_json::Value>(&text_response) {
-            if json_value.get("error").is_some() {
+            if json_value.get("error").is_some() || glm::is_content_policy_error(&text_response) {
                 let text_response_s = sanitize_text(&sensitive, &text_response);
                 println!(
                     "GLM returned 200 OK but with error body: {}",
-                    text_response_s
+                    truncate_chars(&text_response_s, LOG_PREVIEW_CHARS)
                 );
 
                 if glm::is_rate_limit_error(&text_response) {
@@ -1924,6 +5013,12 @@ pub(crate) async fn expand_character(
                     Some(response_time_ms),
                 )
                 .await;
+                if let Some(res) = content_policy_response(&text_response) {
+                    return Err(res);
+                }
+                if let Some(res) = model_unavailable_response(&text_response, &model) {
+                    return Err(res);
+                }
                 return Err(error_response(CODE_INTERNAL_ERROR, text_response_s).into_response());
             }
         }
@@ -1948,7 +5043,8 @@ pub(crate) async fn expand_character(
             }
         };
 
-        let content = match response_json["choices"][0]["message"]["content"].as_str() {
+        let message_content = &response_json["choices"][0]["message"]["content"];
+        let content = match glm::message_content_text(message_content) {
             Some(c) => c,
             None => {
                 finish_glm_request_log(
@@ -1967,8 +5063,7 @@ pub(crate) async fn expand_character(
             }
         };
 
-        let clean = clean_json(content);
-        match serde_json::from_str::<Vec<CharacterInput>>(&clean) {
+        match parse_expanded_characters(&content) {
             Ok(chars) => {
                 let chars_value = serde_json::to_value(&chars).unwrap_or(json!([]));
                 // Log raw content as per user demand
@@ -1987,7 +5082,7 @@ pub(crate) async fn expand_character(
                 Ok(success_response(chars).into_response())
             }
             Err(e) => {
-                let clean_s = sanitize_text(&sensitive, &clean);
+                let clean_s = sanitize_text(&sensitive, &clean_json(&content));
                 finish_glm_request_log(
                     &db,
                     request_id,
@@ -2002,11 +5097,12 @@ pub(crate) async fn expand_character(
         }
     });
 
-    match handle.await {
+    let res = match handle.await {
         Ok(res) => res,
         Err(e) => {
             eprintln!("Task join error: {}", e);
             Err(error_response(CODE_INTERNAL_ERROR, "Internal Server Error").into_response())
         }
-    }
+    };
+    with_model_header(res, &served_model)
 }
@@ -1,11 +1,153 @@
-use axum::http::StatusCode;
 use base64::Engine;
 use reqwest::Client;
 use serde::Deserialize;
 use serde_json::json;
+use std::collections::HashSet;
 
 use crate::api_types::{CharacterInput, GenerateRequest};
-use crate::types::MovieTemplate;
+use crate::prompt::{effective_synopsis, truncate_chars, LOG_PREVIEW_CHARS};
+use crate::types::{map_key_order, MovieTemplate};
+
+const DEFAULT_MAX_IMAGE_BYTES: usize = 300 * 1024;
+
+/// Largest decoded image a stored template may embed, from `MAX_IMAGE_BYTES`.
+/// Defaults to 300KB.
+pub(crate) fn max_image_bytes() -> usize {
+    std::env::var("MAX_IMAGE_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_IMAGE_BYTES)
+}
+
+/// Decoded size of a `data:image/...;base64,` URI, or `None` when the value
+/// is not such a URI or its payload is not valid base64.
+pub(crate) fn image_data_uri_size(value: &str) -> Option<usize> {
+    let rest = value.trim().strip_prefix("data:image/")?;
+    let (_, payload) = rest.split_once(";base64,")?;
+    base64::engine::general_purpose::STANDARD
+        .decode(payload.trim())
+        .ok()
+        .map(|bytes| bytes.len())
+}
+
+/// Every embedded image of a stored template, to tell them apart from
+/// images a client introduced.
+fn stored_images(stored: Option<&MovieTemplate>) -> HashSet<&str> {
+    let Some(stored) = stored else {
+        return HashSet::new();
+    };
+    stored
+        .background_image_base64
+        .iter()
+        .chain(stored.nodes.values().filter_map(|n| n.background_image_base64.as_ref()))
+        .chain(stored.characters.values().filter_map(|c| c.avatar_path.as_ref()))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Drops embedded images that are malformed or larger than `max_bytes`
+/// decoded, returning a description of each. The background must be a data
+/// URI; avatars may also be plain paths/URLs, which are left alone. Images
+/// already in `stored` (the saved version of this game, e.g. generated
+/// CogView backgrounds) are kept whatever their size. A dropped template
+/// background is replaced with the SVG fallback.
+pub(crate) fn strip_oversized_images(
+    template: &mut MovieTemplate,
+    stored: Option<&MovieTemplate>,
+    max_bytes: usize,
+) -> Vec<String> {
+    let mut stripped = Vec::new();
+    let known = stored_images(stored);
+    // `None` keeps the image; `Some(size)` drops it, `size` being `None` when
+    // it is not a valid data URI.
+    let check = |image: &str| -> Option<Option<usize>> {
+        if known.contains(image) {
+            return None;
+        }
+        match image_data_uri_size(image) {
+            Some(size) if size <= max_bytes => None,
+            size => Some(size),
+        }
+    };
+
+    if let Some(bg) = template.background_image_base64.as_deref() {
+        if let Some(size) = check(bg) {
+            stripped.push(match size {
+                Some(size) => format!(
+                    "背景图片过大 ({} 字节，上限 {})，已改用默认背景",
+                    size, max_bytes
+                ),
+                None => "背景图片不是合法的 base64 图片 data URI，已改用默认背景".to_string(),
+            });
+            template.background_image_base64 = Some(fallback_background_data_uri(
+                &template.title,
+                &template.meta.synopsis,
+            ));
+        }
+    }
+
+    let mut node_keys: Vec<String> = template.nodes.keys().cloned().collect();
+    node_keys.sort_by(|a, b| map_key_order(a).cmp(&map_key_order(b)));
+    for key in node_keys {
+        let Some(node) = template.nodes.get_mut(&key) else {
+            continue;
+        };
+        let Some(bg) = node.background_image_base64.as_deref() else {
+            continue;
+        };
+        match check(bg) {
+            None => {}
+            Some(Some(size)) => {
+                stripped.push(format!(
+                    "节点 {} 背景图片过大 ({} 字节，上限 {})，已移除",
+                    key, size, max_bytes
+                ));
+                node.background_image_base64 = None;
+            }
+            Some(None) => {
+                stripped.push(format!(
+                    "节点 {} 背景图片不是合法的 base64 图片 data URI，已移除",
+                    key
+                ));
+                node.background_image_base64 = None;
+            }
+        }
+    }
+
+    let mut keys: Vec<String> = template.characters.keys().cloned().collect();
+    keys.sort();
+    for key in keys {
+        let Some(ch) = template.characters.get_mut(&key) else {
+            continue;
+        };
+        let Some(avatar) = ch.avatar_path.as_deref() else {
+            continue;
+        };
+        if !avatar.trim_start().starts_with("data:") {
+            continue;
+        }
+        match check(avatar) {
+            None => {}
+            Some(Some(size)) => {
+                stripped.push(format!(
+                    "角色 {} 头像过大 ({} 字节，上限 {})，已移除",
+                    ch.name, size, max_bytes
+                ));
+                ch.avatar_path = None;
+            }
+            Some(None) => {
+                stripped.push(format!(
+                    "角色 {} 头像不是合法的 base64 图片 data URI，已移除",
+                    ch.name
+                ));
+                ch.avatar_path = None;
+            }
+        }
+    }
+
+    stripped
+}
 
 pub(crate) fn pick_background_prompt(req: &GenerateRequest, template: &MovieTemplate) -> String {
     let from_template = template.meta.synopsis.trim();
@@ -13,8 +155,7 @@ pub(crate) fn pick_background_prompt(req: &GenerateRequest, template: &MovieTemp
         return from_template.to_string();
     }
 
-    let from_req = req.synopsis.as_deref().unwrap_or("").trim();
-    if !from_req.is_empty() {
+    if let Some(from_req) = effective_synopsis(req) {
         return from_req.to_string();
     }
 
@@ -31,6 +172,19 @@ pub(crate) fn pick_background_prompt(req: &GenerateRequest, template: &MovieTemp
     template.title.trim().to_string()
 }
 
+/// Language the image prompts are written for: `imagePromptLanguage` when
+/// given, otherwise the story language.
+pub(crate) fn pick_image_prompt_language<'a>(
+    req: &'a GenerateRequest,
+    story_language: &'a str,
+) -> &'a str {
+    req.image_prompt_language
+        .as_deref()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .unwrap_or(story_language)
+}
+
 fn simple_hash_u32(s: &str) -> u32 {
     let mut h: u32 = 2166136261;
     for b in s.as_bytes() {
@@ -45,12 +199,65 @@ fn svg_to_data_uri(svg: &str) -> String {
     format!("data:image/svg+xml;base64,{}", b64)
 }
 
+/// Attribution text overlaid on fallback SVGs, from `WATERMARK_TEXT`.
+pub(crate) fn watermark_text() -> Option<String> {
+    std::env::var("WATERMARK_TEXT")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+pub(crate) fn escape_xml(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\'' => out.push_str("&apos;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Low-opacity `<text>` element for the watermark, or an empty string when
+/// no watermark is configured.
+fn watermark_svg_element(
+    text: Option<&str>,
+    x: u32,
+    y: u32,
+    anchor: &str,
+    font_size: u32,
+) -> String {
+    let Some(text) = text.map(str::trim).filter(|t| !t.is_empty()) else {
+        return String::new();
+    };
+    format!(
+        "\n  <text x='{x}' y='{y}' text-anchor='{anchor}' font-family='sans-serif' font-size='{font_size}' fill='white' opacity='0.35'>{text}</text>",
+        text = escape_xml(text)
+    )
+}
+
 pub(crate) fn fallback_background_data_uri(title: &str, synopsis: &str) -> String {
+    svg_to_data_uri(&fallback_background_svg(
+        title,
+        synopsis,
+        watermark_text().as_deref(),
+    ))
+}
+
+pub(crate) fn fallback_background_svg(
+    title: &str,
+    synopsis: &str,
+    watermark: Option<&str>,
+) -> String {
     let seed = simple_hash_u32(&format!("{}::{}", title.trim(), synopsis.trim()));
     let h1 = (seed % 360) as i32;
     let h2 = ((seed.wrapping_mul(3) % 360) as i32 + 360) % 360;
     let h3 = ((seed.wrapping_mul(7) % 360) as i32 + 360) % 360;
-    let svg = format!(
+    format!(
         r#"<svg xmlns='http://www.w3.org/2000/svg' width='1024' height='1024' viewBox='0 0 1024 1024'>
   <defs>
     <linearGradient id='g' x1='0' y1='0' x2='1' y2='1'>
@@ -68,17 +275,75 @@ pub(crate) fn fallback_background_data_uri(title: &str, synopsis: &str) -> Strin
     <circle cx='780' cy='360' r='280' fill='white' opacity='0.10'/>
     <circle cx='520' cy='820' r='320' fill='black' opacity='0.10'/>
   </g>
-  <rect width='1024' height='1024' fill='black' opacity='0.22'/>
-</svg>"#
+  <rect width='1024' height='1024' fill='black' opacity='0.22'/>{mark}
+</svg>"#,
+        mark = watermark_svg_element(watermark, 996, 996, "end", 28)
+    )
+}
+
+const PREVIEW_TITLE_CHARS: usize = 24;
+const PREVIEW_SYNOPSIS_LINE_CHARS: usize = 30;
+const PREVIEW_SYNOPSIS_LINES: usize = 2;
+
+/// 1200x630 share card (the Open Graph image size): the game's background,
+/// or the fallback gradient when it has none, under a dark band carrying the
+/// title and the start of the synopsis.
+pub(crate) fn preview_card_svg(
+    title: &str,
+    synopsis: &str,
+    background: Option<&str>,
+    watermark: Option<&str>,
+) -> String {
+    let background = background
+        .map(str::trim)
+        .filter(|b| b.starts_with("data:image/"))
+        .map(str::to_string)
+        .unwrap_or_else(|| svg_to_data_uri(&fallback_background_svg(title, synopsis, None)));
+
+    let synopsis = truncate_chars(
+        &synopsis.split_whitespace().collect::<Vec<_>>().join(" "),
+        PREVIEW_SYNOPSIS_LINE_CHARS * PREVIEW_SYNOPSIS_LINES,
     );
-    svg_to_data_uri(&svg)
+    let chars: Vec<char> = synopsis.chars().collect();
+    let synopsis_lines: String = chars
+        .chunks(PREVIEW_SYNOPSIS_LINE_CHARS)
+        .enumerate()
+        .map(|(i, line)| {
+            format!(
+                "\n  <text x='64' y='{}' font-family='sans-serif' font-size='30' fill='white' opacity='0.85'>{}</text>",
+                500 + i * 44,
+                escape_xml(&line.iter().collect::<String>())
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<svg xmlns='http://www.w3.org/2000/svg' width='1200' height='630' viewBox='0 0 1200 630'>
+  <defs>
+    <linearGradient id='shade' x1='0' y1='0' x2='0' y2='1'>
+      <stop offset='35%' stop-color='black' stop-opacity='0'/>
+      <stop offset='100%' stop-color='black' stop-opacity='0.85'/>
+    </linearGradient>
+  </defs>
+  <image href='{bg}' width='1200' height='630' preserveAspectRatio='xMidYMid slice'/>
+  <rect width='1200' height='630' fill='url(#shade)'/>
+  <text x='64' y='440' font-family='sans-serif' font-size='60' font-weight='bold' fill='white'>{title}</text>{synopsis_lines}{mark}
+</svg>"#,
+        bg = escape_xml(&background),
+        title = escape_xml(&truncate_chars(title.trim(), PREVIEW_TITLE_CHARS)),
+        mark = watermark_svg_element(watermark, 1168, 606, "end", 22)
+    )
 }
 
 pub(crate) fn fallback_avatar_data_uri(name: &str) -> String {
+    svg_to_data_uri(&fallback_avatar_svg(name, watermark_text().as_deref()))
+}
+
+pub(crate) fn fallback_avatar_svg(name: &str, watermark: Option<&str>) -> String {
     let seed = simple_hash_u32(name.trim());
     let h1 = (seed % 360) as i32;
     let h2 = ((seed.wrapping_mul(5) % 360) as i32 + 360) % 360;
-    let svg = format!(
+    format!(
         r#"<svg xmlns='http://www.w3.org/2000/svg' width='512' height='512' viewBox='0 0 512 512'>
   <defs>
     <radialGradient id='rg' cx='35%' cy='30%' r='80%'>
@@ -92,10 +357,10 @@ pub(crate) fn fallback_avatar_data_uri(name: &str) -> String {
     <circle cx='256' cy='210' r='86' fill='rgba(255,255,255,0.92)'/>
     <path d='M128 446c18-86 78-134 128-134s110 48 128 134' fill='rgba(255,255,255,0.92)'/>
   </g>
-  <rect width='512' height='512' rx='256' fill='rgba(0,0,0,0.18)'/>
-</svg>"#
-    );
-    svg_to_data_uri(&svg)
+  <rect width='512' height='512' rx='256' fill='rgba(0,0,0,0.18)'/>{mark}
+</svg>"#,
+        mark = watermark_svg_element(watermark, 256, 480, "middle", 20)
+    )
 }
 
 pub(crate) fn attach_avatar_to_template(
@@ -108,11 +373,19 @@ pub(crate) fn attach_avatar_to_template(
         return;
     }
 
-    if let Some((_k, c)) = template
+    // 同名角色可能尚未合并：优先 key 与名字一致的条目，否则取 key 最小的一个，保证只挂到唯一角色上
+    let Some(target) = template
         .characters
-        .iter_mut()
-        .find(|(_k, c)| c.name.trim() == protagonist_name)
-    {
+        .iter()
+        .filter(|(_k, c)| c.name.trim() == protagonist_name)
+        .map(|(k, _c)| k)
+        .min_by_key(|k| (k.as_str() != protagonist_name, k.as_str()))
+        .cloned()
+    else {
+        return;
+    };
+
+    if let Some(c) = template.characters.get_mut(&target) {
         if c.avatar_path.as_deref().unwrap_or("").trim().is_empty() {
             c.avatar_path = Some(avatar_data_uri);
         }
@@ -127,6 +400,47 @@ fn attach_avatar_to_first_character(template: &mut MovieTemplate, avatar_data_ur
     }
 }
 
+/// Attaches avatars the user uploaded with their characters. Each must be a
+/// base64 image data URI of at most `max_bytes` decoded; others are skipped
+/// and described in the returned list so the avatar is generated instead.
+pub(crate) fn attach_supplied_avatars(
+    template: &mut MovieTemplate,
+    req_chars: Option<&Vec<CharacterInput>>,
+    max_bytes: usize,
+) -> Vec<String> {
+    let mut rejected = Vec::new();
+    for c in req_chars.into_iter().flatten() {
+        let Some(avatar) = c.avatar.as_deref().map(str::trim) else {
+            continue;
+        };
+        if avatar.is_empty() {
+            continue;
+        }
+        match image_data_uri_size(avatar) {
+            Some(size) if size <= max_bytes => {
+                attach_avatar_to_template(template, &c.name, avatar.to_string())
+            }
+            Some(size) => rejected.push(format!(
+                "角色 {} 上传的头像过大 ({} 字节，上限 {})，已改为生成",
+                c.name.trim(),
+                size,
+                max_bytes
+            )),
+            None => rejected.push(format!(
+                "角色 {} 上传的头像不是合法的 base64 图片 data URI，已改为生成",
+                c.name.trim()
+            )),
+        }
+    }
+    rejected
+}
+
+fn has_avatar(template: &MovieTemplate, name: &str) -> bool {
+    template.characters.values().any(|c| {
+        c.name.trim() == name.trim() && !c.avatar_path.as_deref().unwrap_or("").trim().is_empty()
+    })
+}
+
 pub(crate) fn ensure_avatar_fallbacks(
     template: &mut MovieTemplate,
     req_chars: Option<&Vec<CharacterInput>>,
@@ -201,6 +515,113 @@ fn select_protagonists(req_chars: Option<&Vec<CharacterInput>>) -> Vec<Protagoni
         .collect()
 }
 
+pub(crate) const DEFAULT_IMAGE_MODEL: &str = "cogview-3-flash";
+pub(crate) const DEFAULT_IMAGE_QUALITY: &str = "hd";
+
+pub(crate) const ALLOWED_IMAGE_MODELS: &[&str] = &[
+    "cogview-3-flash",
+    "cogview-3",
+    "cogview-3-plus",
+    "cogview-4",
+    "cogview-4-250304",
+];
+const ALLOWED_IMAGE_QUALITIES: &[&str] = &["hd", "standard"];
+
+#[derive(Clone, Debug)]
+pub(crate) struct ImageOptions {
+    pub(crate) model: String,
+    pub(crate) quality: String,
+    pub(crate) endpoint: String,
+    // CogView 原生水印，默认关闭；由 COGVIEW_WATERMARK_ENABLED 开启
+    pub(crate) watermark_enabled: bool,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            model: DEFAULT_IMAGE_MODEL.to_string(),
+            quality: DEFAULT_IMAGE_QUALITY.to_string(),
+            endpoint: COGVIEW_IMAGES_ENDPOINT.to_string(),
+            watermark_enabled: cogview_watermark_enabled(),
+        }
+    }
+}
+
+fn cogview_watermark_enabled() -> bool {
+    std::env::var("COGVIEW_WATERMARK_ENABLED")
+        .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Rejects `imageModel` / `imageQuality` values outside the allowlist.
+pub(crate) fn validate_image_options(req: &GenerateRequest) -> Result<(), String> {
+    if let Some(m) = req
+        .image_model
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        if !ALLOWED_IMAGE_MODELS.contains(&m) {
+            return Err(format!("不支持的图片模型: {}", m));
+        }
+    }
+    if let Some(q) = req
+        .image_quality
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        if !ALLOWED_IMAGE_QUALITIES.contains(&q) {
+            return Err(format!("不支持的图片质量: {}", q));
+        }
+    }
+    Ok(())
+}
+
+/// Request-level overrides are only honored with the caller's own API Key,
+/// so the shared account always uses the default (cheapest) settings.
+pub(crate) fn resolve_image_options(
+    req: &GenerateRequest,
+    using_override_key: bool,
+) -> ImageOptions {
+    let mut options = ImageOptions::default();
+    if !using_override_key {
+        return options;
+    }
+
+    if let Some(m) = req
+        .image_model
+        .as_deref()
+        .map(str::trim)
+        .filter(|m| ALLOWED_IMAGE_MODELS.contains(m))
+    {
+        options.model = m.to_string();
+    }
+    if let Some(q) = req
+        .image_quality
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| ALLOWED_IMAGE_QUALITIES.contains(q))
+    {
+        options.quality = q.to_string();
+    }
+    options
+}
+
+pub(crate) fn build_cogview_request_body(
+    prompt: &str,
+    size: &str,
+    options: &ImageOptions,
+) -> serde_json::Value {
+    json!({
+        "model": options.model,
+        "prompt": prompt,
+        "quality": options.quality,
+        "size": size,
+        "watermark_enabled": options.watermark_enabled
+    })
+}
+
 pub(crate) fn normalize_cogview_size(raw: Option<&str>) -> String {
     match raw.unwrap_or("").trim() {
         "1024x1024" => "1024x1024".to_string(),
@@ -210,13 +631,106 @@ pub(crate) fn normalize_cogview_size(raw: Option<&str>) -> String {
     }
 }
 
-pub(crate) async fn generate_scene_background_base64(
+/// Why a CogView image could not be produced. Callers still fall back to the
+/// SVG placeholders; the variant only makes the logged reason actionable.
+#[derive(Debug)]
+pub(crate) enum ImageError {
+    // CogView 返回非 2xx（如内容审核拦截、参数错误、额度不足）
+    Rejected { status: u16, body: String },
+    // 请求 CogView 时网络失败
+    Network(String),
+    // 返回体无法解析或缺少图片 URL
+    InvalidResponse(String),
+    // 下载生成的图片失败
+    DownloadFailed(String),
+}
+
+impl ImageError {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            ImageError::Rejected { .. } => "IMAGE_REJECTED",
+            ImageError::Network(_) => "IMAGE_NETWORK_ERROR",
+            ImageError::InvalidResponse(_) => "IMAGE_INVALID_RESPONSE",
+            ImageError::DownloadFailed(_) => "IMAGE_DOWNLOAD_FAILED",
+        }
+    }
+
+    /// Network hiccups and 429/5xx are worth retrying; content rejections are not.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self {
+            ImageError::Rejected { status, .. } => *status == 429 || *status >= 500,
+            ImageError::Network(_) | ImageError::DownloadFailed(_) => true,
+            ImageError::InvalidResponse(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Rejected { status, body } => {
+                write!(f, "{}: CogView returned {}: {}", self.code(), status, body)
+            }
+            ImageError::Network(e)
+            | ImageError::InvalidResponse(e)
+            | ImageError::DownloadFailed(e) => write!(f, "{}: {}", self.code(), e),
+        }
+    }
+}
+
+pub(crate) fn cogview_status_error(status: u16, body: &str) -> ImageError {
+    ImageError::Rejected {
+        status,
+        body: truncate_chars(body, LOG_PREVIEW_CHARS),
+    }
+}
+
+pub(crate) fn download_status_error(status: u16) -> ImageError {
+    ImageError::DownloadFailed(format!("image download returned {}", status))
+}
+
+pub(crate) const COGVIEW_IMAGES_ENDPOINT: &str =
+    "https://open.bigmodel.cn/api/paas/v4/images/generations";
+
+/// Total CogView attempts per image (POST + download), from
+/// `MOVIE_GAMES_IMAGE_RETRY_ATTEMPTS`, clamped to 1..=5. Defaults to 2.
+pub(crate) fn image_retry_attempts() -> u32 {
+    std::env::var("MOVIE_GAMES_IMAGE_RETRY_ATTEMPTS")
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .unwrap_or(2)
+        .clamp(1, 5)
+}
+
+/// Retries transient failures (network, 429/5xx, download) with a short
+/// backoff; content-policy rejections return immediately.
+pub(crate) async fn request_cogview_image_with_retry(
     client: &Client,
-    synopsis: &str,
-    language_tag: &str,
-    size: &str,
+    endpoint: &str,
     api_key: &str,
-) -> Result<String, StatusCode> {
+    request_body: &serde_json::Value,
+    attempts: u32,
+) -> Result<String, ImageError> {
+    let mut attempt = 1;
+    loop {
+        match request_cogview_image(client, endpoint, api_key, request_body).await {
+            Ok(img) => return Ok(img),
+            Err(e) if e.is_retryable() && attempt < attempts => {
+                eprintln!("CogView attempt {} failed, retrying: {}", attempt, e);
+                tokio::time::sleep(std::time::Duration::from_millis(300 * attempt as u64)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn request_cogview_image(
+    client: &Client,
+    endpoint: &str,
+    api_key: &str,
+    request_body: &serde_json::Value,
+) -> Result<String, ImageError> {
     #[derive(Deserialize)]
     struct CogViewImageResponse {
         created: u64,
@@ -228,50 +742,25 @@ pub(crate) async fn generate_scene_background_base64(
         url: String,
     }
 
-    let language_hint = if language_tag.to_lowercase().starts_with("zh") {
-        "简体中文"
-    } else {
-        "English"
-    };
-
-    let prompt = format!(
-        "Create a cinematic environment / scene image for an interactive movie game.\n\
-Language: {}\n\
-Story synopsis: {}\n\
-Hard constraints (must follow):\n\
-- DO NOT generate any people, characters, faces, portraits, hands, or human silhouettes.\n\
-- Scene / environment ONLY: locations, lighting, atmosphere, props, architecture, weather.\n\
-- No text, no logos, no watermarks, no UI elements.\n\
-- Keep mood consistent with the synopsis.",
-        language_hint,
-        synopsis.trim()
-    );
-
-    let request_body = json!({
-        "model": "cogview-3-flash",
-        "prompt": prompt,
-        "quality": "hd",
-        "size": size,
-        "watermark_enabled": false
-    });
-
     let resp = client
-        .post("https://open.bigmodel.cn/api/paas/v4/images/generations")
+        .post(endpoint)
         .header("Authorization", format!("Bearer {}", api_key))
         .header("Content-Type", "application/json")
-        .json(&request_body)
+        .json(request_body)
         .send()
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| ImageError::Network(e.to_string()))?;
 
-    if !resp.status().is_success() {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    let status = resp.status();
+    if !status.is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(cogview_status_error(status.as_u16(), &body));
     }
 
     let json_resp: CogViewImageResponse = resp
         .json()
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| ImageError::InvalidResponse(e.to_string()))?;
 
     let _ = json_resp.created;
 
@@ -280,16 +769,16 @@ Hard constraints (must follow):\n\
         .first()
         .map(|d| d.url.trim().to_string())
         .filter(|u| !u.is_empty())
-        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        .ok_or_else(|| ImageError::InvalidResponse("missing image url".to_string()))?;
 
     let img_resp = client
         .get(url)
         .send()
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| ImageError::DownloadFailed(e.to_string()))?;
 
     if !img_resp.status().is_success() {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        return Err(download_status_error(img_resp.status().as_u16()));
     }
 
     let content_type = img_resp
@@ -302,36 +791,61 @@ Hard constraints (must follow):\n\
     let bytes = img_resp
         .bytes()
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .map_err(|e| ImageError::DownloadFailed(e.to_string()))?;
 
     let b64 = base64::engine::general_purpose::STANDARD.encode(bytes);
     Ok(format!("data:{};base64,{}", content_type, b64))
 }
 
-pub(crate) async fn generate_protagonist_avatar_base64(
+fn image_language_hint(language_tag: &str) -> &'static str {
+    if language_tag.to_lowercase().starts_with("zh") {
+        "简体中文"
+    } else {
+        "English"
+    }
+}
+
+pub(crate) fn scene_background_prompt(synopsis: &str, language_tag: &str) -> String {
+    format!(
+        "Create a cinematic environment / scene image for an interactive movie game.\n\
+Language: {}\n\
+Story synopsis: {}\n\
+Hard constraints (must follow):\n\
+- DO NOT generate any people, characters, faces, portraits, hands, or human silhouettes.\n\
+- Scene / environment ONLY: locations, lighting, atmosphere, props, architecture, weather.\n\
+- No text, no logos, no watermarks, no UI elements.\n\
+- Keep mood consistent with the synopsis.",
+        image_language_hint(language_tag),
+        synopsis.trim()
+    )
+}
+
+pub(crate) async fn generate_scene_background_base64(
     client: &Client,
-    template: &MovieTemplate,
-    protagonist: &ProtagonistSpec,
+    synopsis: &str,
     language_tag: &str,
+    size: &str,
     api_key: &str,
-) -> Result<String, StatusCode> {
-    #[derive(Deserialize)]
-    struct CogViewImageResponse {
-        created: u64,
-        data: Vec<CogViewImageData>,
-    }
-
-    #[derive(Deserialize)]
-    struct CogViewImageData {
-        url: String,
-    }
+    options: &ImageOptions,
+) -> Result<String, ImageError> {
+    let prompt = scene_background_prompt(synopsis, language_tag);
+    let request_body = build_cogview_request_body(&prompt, size, options);
 
-    let language_hint = if language_tag.to_lowercase().starts_with("zh") {
-        "简体中文"
-    } else {
-        "English"
-    };
+    request_cogview_image_with_retry(
+        client,
+        &options.endpoint,
+        api_key,
+        &request_body,
+        image_retry_attempts(),
+    )
+    .await
+}
 
+pub(crate) fn protagonist_avatar_prompt(
+    template: &MovieTemplate,
+    protagonist: &ProtagonistSpec,
+    language_tag: &str,
+) -> String {
     let extra = template
         .characters
         .values()
@@ -345,7 +859,7 @@ pub(crate) async fn generate_protagonist_avatar_base64(
         })
         .unwrap_or_default();
 
-    let prompt = format!(
+    format!(
         "Create a high-quality protagonist portrait avatar for an interactive movie game.\n\
 Language: {}\n\
 Character name: {}\n\
@@ -359,72 +873,33 @@ Hard constraints (must follow):\n\
 - No text, no logos, no watermark, no UI.\n\
 - No extra people, no hands, no full body.\n\
 - Cinematic realistic style, clean lighting, sharp focus.",
-        language_hint,
+        image_language_hint(language_tag),
         protagonist.name.trim(),
         protagonist.gender.trim(),
         protagonist.description.trim(),
         extra.trim()
-    );
-
-    let request_body = json!({
-        "model": "cogview-3-flash",
-        "prompt": prompt,
-        "quality": "hd",
-        "size": "1024x1024",
-        "watermark_enabled": false
-    });
-
-    let resp = client
-        .post("https://open.bigmodel.cn/api/paas/v4/images/generations")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Content-Type", "application/json")
-        .json(&request_body)
-        .send()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    if !resp.status().is_success() {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    let json_resp: CogViewImageResponse = resp
-        .json()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let _ = json_resp.created;
-
-    let url = json_resp
-        .data
-        .first()
-        .map(|d| d.url.trim().to_string())
-        .filter(|u| !u.is_empty())
-        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let img_resp = client
-        .get(url)
-        .send()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    if !img_resp.status().is_success() {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    let content_type = img_resp
-        .headers()
-        .get(reqwest::header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("image/png")
-        .to_string();
+    )
+}
 
-    let bytes = img_resp
-        .bytes()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+pub(crate) async fn generate_protagonist_avatar_base64(
+    client: &Client,
+    template: &MovieTemplate,
+    protagonist: &ProtagonistSpec,
+    language_tag: &str,
+    api_key: &str,
+    options: &ImageOptions,
+) -> Result<String, ImageError> {
+    let prompt = protagonist_avatar_prompt(template, protagonist, language_tag);
+    let request_body = build_cogview_request_body(&prompt, "1024x1024", options);
 
-    let b64 = base64::engine::general_purpose::STANDARD.encode(bytes);
-    Ok(format!("data:{};base64,{}", content_type, b64))
+    request_cogview_image_with_retry(
+        client,
+        &options.endpoint,
+        api_key,
+        &request_body,
+        image_retry_attempts(),
+    )
+    .await
 }
 
 pub(crate) async fn maybe_attach_generated_avatars(
@@ -433,29 +908,248 @@ pub(crate) async fn maybe_attach_generated_avatars(
     req_chars: Option<&Vec<CharacterInput>>,
     language_tag: &str,
     api_key: &str,
-) {
-    let protagonists = select_protagonists(req_chars);
+    options: &ImageOptions,
+) -> Vec<ImageError> {
+    let mut errors = Vec::new();
+    // Protagonists with an uploaded avatar keep it; no CogView call for them.
+    let protagonists: Vec<ProtagonistSpec> = select_protagonists(req_chars)
+        .into_iter()
+        .filter(|p| !has_avatar(template, &p.name))
+        .collect();
     if protagonists.len() == 1 {
         if let Some(spec) = protagonists.first() {
-            if let Ok(img) =
-                generate_protagonist_avatar_base64(client, template, spec, language_tag, api_key)
-                    .await
+            match generate_protagonist_avatar_base64(
+                client,
+                template,
+                spec,
+                language_tag,
+                api_key,
+                options,
+            )
+            .await
             {
-                attach_avatar_to_template(template, &spec.name, img);
+                Ok(img) => attach_avatar_to_template(template, &spec.name, img),
+                Err(e) => errors.push(e),
             }
         }
     } else if protagonists.len() >= 2 {
         let a = protagonists[0].clone();
         let b = protagonists[1].clone();
         let (ra, rb) = tokio::join!(
-            generate_protagonist_avatar_base64(client, template, &a, language_tag, api_key),
-            generate_protagonist_avatar_base64(client, template, &b, language_tag, api_key)
+            generate_protagonist_avatar_base64(
+                client,
+                template,
+                &a,
+                language_tag,
+                api_key,
+                options
+            ),
+            generate_protagonist_avatar_base64(
+                client,
+                template,
+                &b,
+                language_tag,
+                api_key,
+                options
+            )
         );
-        if let Ok(img) = ra {
-            attach_avatar_to_template(template, &a.name, img);
+        match ra {
+            Ok(img) => attach_avatar_to_template(template, &a.name, img),
+            Err(e) => errors.push(e),
+        }
+        match rb {
+            Ok(img) => attach_avatar_to_template(template, &b.name, img),
+            Err(e) => errors.push(e),
+        }
+    }
+    errors
+}
+
+const DEFAULT_MAX_NODE_BACKGROUNDS: usize = 4;
+/// CogView requests in flight at once for per-node backgrounds.
+const NODE_BACKGROUND_CONCURRENCY: usize = 2;
+
+/// Most distinct scene backgrounds one `/generate` may create, from
+/// `MAX_NODE_BACKGROUNDS`. Defaults to 4.
+pub(crate) fn max_node_backgrounds() -> usize {
+    std::env::var("MAX_NODE_BACKGROUNDS")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_NODE_BACKGROUNDS)
+}
+
+/// Place words looked for in node content. A node's scene is the one that
+/// appears earliest, the longer word winning when two start at the same spot.
+const SCENE_LOCATIONS: &[&str] = &[
+    "地下室",
+    "办公室",
+    "实验室",
+    "咖啡馆",
+    "图书馆",
+    "停车场",
+    "火车站",
+    "游乐园",
+    "医院",
+    "学校",
+    "教室",
+    "宿舍",
+    "操场",
+    "天台",
+    "街道",
+    "小巷",
+    "酒吧",
+    "餐厅",
+    "公园",
+    "仓库",
+    "警局",
+    "法庭",
+    "车站",
+    "机场",
+    "码头",
+    "海边",
+    "森林",
+    "山洞",
+    "村庄",
+    "宫殿",
+    "客栈",
+    "寺庙",
+    "监狱",
+    "病房",
+    "走廊",
+    "客厅",
+    "卧室",
+    "厨房",
+    "荒野",
+    "沙漠",
+    "战场",
+    "hospital",
+    "school",
+    "classroom",
+    "office",
+    "street",
+    "alley",
+    "restaurant",
+    "park",
+    "warehouse",
+    "station",
+    "airport",
+    "harbor",
+    "beach",
+    "forest",
+    "cave",
+    "village",
+    "palace",
+    "prison",
+    "rooftop",
+    "basement",
+    "library",
+    "laboratory",
+];
+
+/// The scene a node takes place in, by the earliest known place word in its
+/// content.
+pub(crate) fn node_scene_location(content: &str) -> Option<&'static str> {
+    let lower = content.to_lowercase();
+    SCENE_LOCATIONS
+        .iter()
+        .filter_map(|loc| lower.find(loc).map(|pos| (pos, *loc)))
+        .min_by_key(|(pos, loc)| (*pos, std::cmp::Reverse(loc.len())))
+        .map(|(_, loc)| loc)
+}
+
+/// Node keys grouped by scene, in story order, keeping at most `cap` scenes.
+/// Nodes without a recognizable place are left out.
+pub(crate) fn group_nodes_by_scene(
+    template: &MovieTemplate,
+    cap: usize,
+) -> Vec<(&'static str, Vec<String>)> {
+    let mut keys: Vec<&String> = template.nodes.keys().collect();
+    keys.sort_by(|a, b| map_key_order(a).cmp(&map_key_order(b)));
+
+    let mut groups: Vec<(&'static str, Vec<String>)> = Vec::new();
+    for key in keys {
+        let Some(loc) = node_scene_location(&template.nodes[key].content) else {
+            continue;
+        };
+        if let Some((_, members)) = groups.iter_mut().find(|(l, _)| *l == loc) {
+            members.push(key.clone());
+        } else if groups.len() < cap {
+            groups.push((loc, vec![key.clone()]));
         }
-        if let Ok(img) = rb {
-            attach_avatar_to_template(template, &b.name, img);
+    }
+    groups
+}
+
+pub(crate) fn node_scene_background_prompt(
+    location: &str,
+    synopsis: &str,
+    language_tag: &str,
+) -> String {
+    format!(
+        "{}\nScene location: {}",
+        scene_background_prompt(synopsis, language_tag),
+        location
+    )
+}
+
+/// Generates one background per scene (see `group_nodes_by_scene`) with
+/// bounded concurrency and attaches it to every node in that scene. Nodes
+/// whose scene fails keep no background of their own, so clients show the
+/// template background instead.
+pub(crate) async fn attach_node_backgrounds(
+    client: &Client,
+    template: &mut MovieTemplate,
+    language_tag: &str,
+    size: &str,
+    api_key: &str,
+    options: &ImageOptions,
+    cap: usize,
+) -> Vec<ImageError> {
+    use futures_util::StreamExt;
+
+    let groups = group_nodes_by_scene(template, cap);
+    let synopsis = template.meta.synopsis.clone();
+    let attempts = image_retry_attempts();
+
+    // Built up front rather than in a `.map` closure: a borrowing closure in
+    // the stream type makes the caller's spawned future fail the `Send` check.
+    let requests: Vec<_> = groups
+        .into_iter()
+        .map(|(location, keys)| {
+            let prompt = node_scene_background_prompt(location, &synopsis, language_tag);
+            let request_body = build_cogview_request_body(&prompt, size, options);
+            async move {
+                let result = request_cogview_image_with_retry(
+                    client,
+                    &options.endpoint,
+                    api_key,
+                    &request_body,
+                    attempts,
+                )
+                .await;
+                (location, keys, result)
+            }
+        })
+        .collect();
+    let results: Vec<_> = futures_util::stream::iter(requests)
+        .buffer_unordered(NODE_BACKGROUND_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut errors = Vec::new();
+    for (location, keys, result) in results {
+        match result {
+            Ok(img) => {
+                for key in keys {
+                    if let Some(node) = template.nodes.get_mut(&key) {
+                        node.background_image_base64 = Some(img.clone());
+                        node.background_alt = Some(location.to_string());
+                    }
+                }
+            }
+            Err(e) => errors.push(e),
         }
     }
+    errors
 }
@@ -3,12 +3,18 @@ use std::net::SocketAddr;
 
 mod api_types;
 mod app;
+mod cancellation;
 mod db;
+mod features;
 mod glm;
 mod handlers;
 mod images;
+mod player_bundle;
 mod prompt;
+mod rate_limit;
+mod request_summary;
 mod sensitive;
+mod single_flight;
 mod template;
 #[cfg(test)]
 mod tests_repro;
@@ -31,19 +37,29 @@ async fn main() {
 
     let sensitive = std::sync::Arc::new(sensitive::SensitiveFilter::from_env());
 
+    let burst_limiter = std::sync::Arc::new(rate_limit::BurstLimiter::from_env());
+    let trusted = std::sync::Arc::new(rate_limit::TrustedClients::from_env());
+    let generations = std::sync::Arc::new(single_flight::SingleFlight::new());
+    let cancellations = std::sync::Arc::new(cancellation::Cancellations::new());
+    let features =
+        features::FeatureFlags::from_env(sensitive.is_active(), |name| std::env::var(name).ok());
+
     let state = db::AppState {
         db: db_pool,
         sensitive,
+        burst_limiter,
+        trusted,
+        generations,
+        cancellations,
+        features,
     };
     let app = app::build_app(state);
 
-    // 监听 0.0.0.0 以允许外部访问 (部署时的常见坑)
-    // 端口已从 8080 改为 35275 (用户要求)
-    let port = std::env::var("PORT")
-        .unwrap_or_else(|_| "35275".to_string())
-        .parse()
-        .unwrap_or(35275);
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let addr = resolve_bind_addr(
+        std::env::var("BIND_ADDR").ok().as_deref(),
+        std::env::var("PORT").ok().as_deref(),
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
     println!("Listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     serve(
@@ -55,6 +71,32 @@ async fn main() {
     .unwrap();
 }
 
+const DEFAULT_PORT: u16 = 35275;
+
+/// Picks the listen address: `BIND_ADDR` (e.g. `127.0.0.1:35275`) wins when set,
+/// otherwise `0.0.0.0:PORT`. A malformed `BIND_ADDR` is an error rather than a
+/// silent fallback, so a typo can't expose the server on every interface.
+pub(crate) fn resolve_bind_addr(
+    bind_addr: Option<&str>,
+    port: Option<&str>,
+) -> Result<SocketAddr, String> {
+    if let Some(raw) = bind_addr.map(str::trim).filter(|v| !v.is_empty()) {
+        return raw.parse::<SocketAddr>().map_err(|e| {
+            format!(
+                "Invalid BIND_ADDR {:?}: {} (expected host:port, e.g. 127.0.0.1:35275 or [::1]:35275)",
+                raw, e
+            )
+        });
+    }
+
+    // 监听 0.0.0.0 以允许外部访问 (部署时的常见坑)
+    // 端口已从 8080 改为 35275 (用户要求)
+    let port = port
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_PORT);
+    Ok(SocketAddr::from(([0, 0, 0, 0], port)))
+}
+
 async fn shutdown_signal() {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
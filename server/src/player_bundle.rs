@@ -0,0 +1,114 @@
+use crate::images::escape_xml;
+use crate::types::ExportedTemplate;
+
+// The player is plain HTML/CSS/JS with no dependencies, so the downloaded
+// file works offline. The data is injected between these parts rather than
+// through `format!`, which would clash with the braces in CSS and JS.
+const PLAYER_HEAD: &str = r#"<!DOCTYPE html>
+<html lang="zh-CN">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>"#;
+
+const PLAYER_BODY: &str = r#"</title>
+<style>
+  body { margin: 0; min-height: 100vh; font-family: system-ui, sans-serif; color: #f5f5f5;
+         background: #111 center / cover no-repeat fixed; display: flex; align-items: flex-end; }
+  main { width: 100%; max-width: 760px; margin: 0 auto 6vh; padding: 24px;
+         background: rgba(0, 0, 0, 0.72); border-radius: 12px; box-sizing: border-box; }
+  h1 { font-size: 1.1rem; opacity: 0.7; margin: 0 0 12px; }
+  #text { font-size: 1.15rem; line-height: 1.8; white-space: pre-wrap; }
+  #choices { display: flex; flex-direction: column; gap: 10px; margin-top: 20px; }
+  button { padding: 12px 16px; font-size: 1rem; color: inherit; background: rgba(255, 255, 255, 0.12);
+           border: 1px solid rgba(255, 255, 255, 0.3); border-radius: 8px; cursor: pointer; text-align: left; }
+  button:hover { background: rgba(255, 255, 255, 0.24); }
+</style>
+</head>
+<body>
+<main>
+  <h1 id="title"></h1>
+  <div id="text"></div>
+  <div id="choices"></div>
+</main>
+<script type="application/json" id="game-data">"#;
+
+const PLAYER_TAIL: &str = r#"</script>
+<script>
+(function () {
+  var data = JSON.parse(document.getElementById('game-data').textContent);
+  var nodes = data.nodes || {};
+  var endings = data.endings || {};
+  var titleEl = document.getElementById('title');
+  var textEl = document.getElementById('text');
+  var choicesEl = document.getElementById('choices');
+  titleEl.textContent = data.title || '';
+
+  function setBackground(img) {
+    document.body.style.backgroundImage = img ? 'url("' + img + '")' : '';
+  }
+
+  function button(label, onClick) {
+    var b = document.createElement('button');
+    b.textContent = label;
+    b.onclick = onClick;
+    choicesEl.appendChild(b);
+  }
+
+  function showEnding(key) {
+    var ending = endings[key] || {};
+    setBackground(data.backgroundImageBase64);
+    textEl.textContent = ending.description || '故事结束';
+    choicesEl.textContent = '';
+    button('重新开始', function () { go(startKey()); });
+  }
+
+  function startKey() {
+    if (nodes.start) return 'start';
+    if (nodes.n_start) return 'n_start';
+    return Object.keys(nodes)[0];
+  }
+
+  function go(key) {
+    var node = nodes[key];
+    if (!node) { showEnding(key); return; }
+    setBackground(node.backgroundImageBase64 || data.backgroundImageBase64);
+    textEl.textContent = node.content || '';
+    choicesEl.textContent = '';
+    var choices = node.choices || [];
+    if (choices.length === 0) {
+      button('继续', function () { showEnding(node.endingKey); });
+      return;
+    }
+    choices.forEach(function (c) {
+      button(c.text, function () { go(c.nextNodeId); });
+    });
+  }
+
+  go(startKey());
+})();
+</script>
+</body>
+</html>
+"#;
+
+/// A single HTML document that plays `template` offline: the player shell
+/// with the template JSON (images already inlined as data URIs) embedded.
+pub(crate) fn build_player_html(template: &ExportedTemplate) -> String {
+    // `<` only occurs inside JSON strings, where `\u003c` decodes back to
+    // it, so story text can never close the data `<script>` early.
+    let data = serde_json::to_string(template)
+        .unwrap_or_else(|_| "{}".to_string())
+        .replace('<', "\\u003c");
+    let title = escape_xml(&template.title);
+
+    let mut html = String::with_capacity(
+        PLAYER_HEAD.len() + title.len() + PLAYER_BODY.len() + data.len() + PLAYER_TAIL.len(),
+    );
+    html.push_str(PLAYER_HEAD);
+    html.push_str(&title);
+    html.push_str(PLAYER_BODY);
+    html.push_str(&data);
+    html.push_str(PLAYER_TAIL);
+    html
+}
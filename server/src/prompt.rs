@@ -1,4 +1,10 @@
-use crate::api_types::{ExpandCharacterRequest, ExpandWorldviewRequest, GenerateRequest};
+use crate::api_types::{
+    CharacterInput, ExpandCharacterRequest, ExpandWorldviewRequest, GenerateRequest, SequelRequest,
+};
+use crate::template::{pick_protagonist_name, DEFAULT_MIN_PATH_DEPTH};
+use crate::types::MovieTemplate;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 pub(crate) fn clean_json(s: &str) -> String {
     let s = s.trim();
@@ -47,30 +53,316 @@ pub(crate) fn clean_json(s: &str) -> String {
     output
 }
 
-pub(crate) fn construct_prompt(req: &GenerateRequest) -> String {
-    let topic = req
+/// Characters of context shown on each side of a JSON parse error.
+const PARSE_SNIPPET_CHARS: usize = 40;
+
+/// Where model output failed to parse, for `error_text` and the server log.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ParseDiagnostic {
+    pub(crate) message: String,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    /// Byte offset into the cleaned text.
+    pub(crate) offset: usize,
+    /// About 80 characters around `offset`.
+    pub(crate) snippet: String,
+    /// Whether `clean_json` changed the raw model output.
+    pub(crate) cleaned: bool,
+}
+
+impl ParseDiagnostic {
+    /// `err` must come from parsing `cleaned`, the `clean_json` output of `raw`.
+    pub(crate) fn new(raw: &str, cleaned: &str, err: &serde_json::Error) -> Self {
+        // serde_json counts columns in bytes from 1 within the line.
+        let line_start: usize = cleaned
+            .split_inclusive('\n')
+            .take(err.line().saturating_sub(1))
+            .map(str::len)
+            .sum();
+        let mut offset = (line_start + err.column().saturating_sub(1)).min(cleaned.len());
+        while !cleaned.is_char_boundary(offset) {
+            offset -= 1;
+        }
+
+        let before: String = {
+            let mut chars: Vec<char> = cleaned[..offset]
+                .chars()
+                .rev()
+                .take(PARSE_SNIPPET_CHARS)
+                .collect();
+            chars.reverse();
+            chars.into_iter().collect()
+        };
+        let after: String = cleaned[offset..]
+            .chars()
+            .take(PARSE_SNIPPET_CHARS)
+            .collect();
+
+        Self {
+            message: err.to_string(),
+            line: err.line(),
+            column: err.column(),
+            offset,
+            snippet: format!("{}{}", before, after),
+            cleaned: raw != cleaned,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "JSON Parse Error: {} (line {}, column {}, offset {}; clean_json modified input: {}); near: {:?}",
+            self.message,
+            self.line,
+            self.column,
+            self.offset,
+            if self.cleaned { "yes" } else { "no" },
+            self.snippet
+        )
+    }
+}
+
+/// Genres the UI offers (home page chips plus random-theme presets); request
+/// genres outside this list are dropped.
+pub(crate) const KNOWN_GENRES: &[&str] = &[
+    "科幻",
+    "剧情",
+    "爱情",
+    "悬疑",
+    "喜剧",
+    "青春",
+    "历史",
+    "冒险",
+    "武侠",
+    "伦理",
+    "悲剧",
+    "职场",
+    "爽文",
+    "动作",
+    "奇幻",
+    "家庭",
+    "惊悚",
+    "赛博朋克",
+    "都市",
+];
+
+/// Length as the prompt's word-count rules mean it ("45 到 85 字"): one per
+/// Unicode scalar. `str::len` counts UTF-8 bytes, so a CJK character would
+/// count three times and a 50-字 line would read as 150.
+pub(crate) fn count_display_chars(s: &str) -> usize {
+    s.chars().count()
+}
+
+/// Rough token count of a prompt before it is sent: one token per non-ASCII
+/// character (CJK text tokenizes at roughly one per character or less) plus
+/// one per four ASCII characters. Cheap enough to run on every request.
+pub(crate) fn estimate_prompt_tokens(prompt: &str) -> i32 {
+    let (ascii, other) = prompt.chars().fold((0usize, 0usize), |(a, o), c| {
+        if c.is_ascii() {
+            (a + 1, o)
+        } else {
+            (a, o + 1)
+        }
+    });
+    (other + ascii.div_ceil(4)).min(i32::MAX as usize) as i32
+}
+
+/// Longest slice of upstream bodies written to the server log.
+pub(crate) const LOG_PREVIEW_CHARS: usize = 500;
+
+/// Cuts `s` to at most `max` characters, the last being `…` when anything
+/// was dropped. Byte slicing (`&s[..n]`) would panic inside a CJK character.
+pub(crate) fn truncate_chars(s: &str, max: usize) -> String {
+    if count_display_chars(s) <= max {
+        return s.to_string();
+    }
+    let mut out: String = s.chars().take(max.saturating_sub(1)).collect();
+    if max > 0 {
+        out.push('…');
+    }
+    out
+}
+
+/// Trims, de-duplicates and allowlists request genres, keeping input order.
+pub(crate) fn sanitize_genre_tags(genres: Option<&[String]>) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for g in genres.unwrap_or_default() {
+        let g = g.trim();
+        if KNOWN_GENRES.contains(&g) && !out.iter().any(|x| x == g) {
+            out.push(g.to_string());
+        }
+    }
+    out
+}
+
+pub(crate) const DEFAULT_PROMPT_CHARACTER_CAP: usize = 12;
+
+/// Upper bound on characters embedded in the generation prompt, from
+/// `MOVIE_GAMES_PROMPT_CHARACTER_CAP`. Defaults to 12.
+pub(crate) fn prompt_character_cap() -> usize {
+    std::env::var("MOVIE_GAMES_PROMPT_CHARACTER_CAP")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_PROMPT_CHARACTER_CAP)
+}
+
+/// Keeps every main character, then supporting ones in input order until
+/// `cap` is reached. Returns the kept characters and how many were omitted.
+pub(crate) fn cap_prompt_characters(
+    chars: &[CharacterInput],
+    cap: usize,
+) -> (Vec<CharacterInput>, usize) {
+    let mains = chars.iter().filter(|c| c.is_main).count();
+    let mut supporting_budget = cap.saturating_sub(mains);
+
+    let kept: Vec<CharacterInput> = chars
+        .iter()
+        .filter(|c| {
+            if c.is_main {
+                return true;
+            }
+            if supporting_budget > 0 {
+                supporting_budget -= 1;
+                return true;
+            }
+            false
+        })
+        // Supplied avatars are image data and voices are for narration only;
+        // neither is something the model should see.
+        .map(|c| CharacterInput {
+            avatar: None,
+            voice: None,
+            voice_description: None,
+            ..c.clone()
+        })
+        .collect();
+
+    let omitted = chars.len() - kept.len();
+    (kept, omitted)
+}
+
+/// When the request carries no character with a usable name, injects a
+/// default protagonist so the prompt, consistency pass and avatar step all
+/// work with a concrete name instead of the "主角" placeholder. The name is
+/// picked from a small language-specific pool, keyed by the theme so the same
+/// request always gets the same protagonist. Gender is left unspecified.
+pub(crate) fn ensure_default_protagonist(req: &mut GenerateRequest) {
+    let has_named = req
+        .characters
+        .as_ref()
+        .is_some_and(|cs| cs.iter().any(|c| !c.name.trim().is_empty()));
+    if has_named {
+        return;
+    }
+
+    let language = req.language.as_deref().unwrap_or("zh-CN").to_lowercase();
+    let (pool, description): (&[&str], &str) = if language.starts_with("zh") {
+        (&["林然", "沈言", "顾遥", "许安"], "故事主角")
+    } else {
+        (&["Alex", "Jordan", "Riley", "Sam"], "The protagonist")
+    };
+    let seed = req
         .theme
         .as_deref()
         .or(req.free_input.as_deref())
-        .unwrap_or("Unknown Theme");
+        .unwrap_or("")
+        .chars()
+        .fold(0usize, |acc, c| acc.wrapping_add(c as usize));
 
-    let synopsis = req.synopsis.as_deref().unwrap_or("");
-    let full_topic = if !synopsis.is_empty() {
-        format!("Theme/Genre: {}\nSynopsis: {}", topic, synopsis)
-    } else {
-        format!("Theme/Genre: {}", topic)
-    };
+    req.characters = Some(vec![CharacterInput {
+        name: pool[seed % pool.len()].to_string(),
+        description: description.to_string(),
+        gender: String::new(),
+        is_main: true,
+        avatar: None,
+        voice: None,
+        voice_description: None,
+    }]);
+}
 
-    let language_tag = req.language.as_deref().unwrap_or("zh-CN");
-    let language_label = if language_tag.to_lowercase().starts_with("zh") {
-        "简体中文".to_string()
-    } else if language_tag.to_lowercase().starts_with("en") {
-        "English".to_string()
-    } else {
-        language_tag.to_string()
-    };
+/// The parent game's cast as request characters, ordered by character id,
+/// keeping avatars and voices so the sequel's characters match. The
+/// protagonist is the one `pick_protagonist_name` would keep.
+pub(crate) fn sequel_cast(parent: &MovieTemplate) -> Vec<CharacterInput> {
+    let protagonist = pick_protagonist_name(&parent.characters);
+    let mut cast: Vec<&crate::types::Character> = parent
+        .characters
+        .values()
+        .filter(|c| !c.name.trim().is_empty())
+        .collect();
+    cast.sort_by(|a, b| a.id.cmp(&b.id));
+    cast.into_iter()
+        .map(|c| CharacterInput {
+            name: c.name.trim().to_string(),
+            description: if c.role.trim().is_empty() {
+                c.background.clone()
+            } else {
+                c.role.clone()
+            },
+            gender: c.gender.clone(),
+            is_main: protagonist.as_deref() == Some(c.name.trim()),
+            avatar: c.avatar_path.clone(),
+            voice: c.voice.clone(),
+            voice_description: c.voice_description.clone(),
+        })
+        .collect()
+}
+
+/// Turns a stored game and one of its endings into a `/generate` request for
+/// a sequel: the ending and the parent synopsis become the authoritative
+/// worldview, the cast and genre carry over. `None` when the ending is unknown.
+pub(crate) fn build_sequel_request(
+    parent: &MovieTemplate,
+    req: &SequelRequest,
+) -> Option<GenerateRequest> {
+    let ending = parent.endings.get(req.ending_key.trim())?;
+
+    let mut setup = format!(
+        "本作是《{}》的续集，故事从前作的一个结局之后开始。",
+        parent.title
+    );
+    if !parent.meta.synopsis.trim().is_empty() {
+        setup.push_str(&format!("\n前作梗概：{}", parent.meta.synopsis.trim()));
+    }
+    setup.push_str(&format!(
+        "\n前作结局（{}）：{}",
+        ending.r#type,
+        ending.description.trim()
+    ));
+    setup.push_str("\n续集要求：开场直接承接上述结局之后的处境，沿用前作角色的身份与关系，展开新的冲突，不要复述前作剧情。");
+
+    let genre: Vec<String> = parent
+        .meta
+        .genre
+        .split([',', '，', '/'])
+        .map(str::trim)
+        .filter(|g| !g.is_empty())
+        .map(str::to_string)
+        .collect();
+    let language = parent.meta.language.trim();
 
-    let types_def = r#"interface MovieTemplate {
+    Some(GenerateRequest {
+        mode: "sequel".to_string(),
+        theme: Some(parent.title.clone()),
+        worldview: Some(setup),
+        genre: (!genre.is_empty()).then_some(genre),
+        characters: Some(sequel_cast(parent)),
+        language: (!language.is_empty()).then(|| language.to_string()),
+        api_key: req.api_key.clone(),
+        base_url: req.base_url.clone(),
+        model: req.model.clone(),
+        quality: req.quality.clone(),
+        max_tokens: req.max_tokens,
+        ..GenerateRequest::default()
+    })
+}
+
+/// Schema section of the `/generate` prompt. It never varies per request, so
+/// it lives here rather than being rebuilt inside `construct_prompt`.
+const GENERATE_TYPES_DEF: &str = r#"interface MovieTemplate {
   title: string
   backgroundImageBase64?: string
   nodes: Record<string, StoryNode>
@@ -97,11 +389,117 @@ interface Ending {
 }
 "#;
 
-    let characters_json = req
-        .characters
-        .as_ref()
-        .and_then(|cs| serde_json::to_string_pretty(cs).ok())
-        .unwrap_or_else(|| "[]".to_string());
+/// The story setting for generation: an already expanded `worldview` wins
+/// over `synopsis`; blank values count as missing.
+pub(crate) fn effective_synopsis(req: &GenerateRequest) -> Option<&str> {
+    [req.worldview.as_deref(), req.synopsis.as_deref()]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .find(|s| !s.is_empty())
+}
+
+/// Node count the `/generate` prompt asks for, inclusive.
+pub(crate) const PROMPT_NODE_RANGE: (u32, u32) = (35, 45);
+
+/// Ending count the `/generate` prompt asks for, inclusive: exactly
+/// `exact_endings` when set, otherwise 4 to 6.
+pub(crate) fn prompt_endings_range(req: &GenerateRequest) -> (u32, u32) {
+    match req.exact_endings {
+        Some(n) => (n, n),
+        None => (4, 6),
+    }
+}
+
+/// Graph rules of the default prompt: a DAG whose choices only point at
+/// larger node keys.
+const DAG_GRAPH_RULES: &str = r#"- DAG 无环结构：剧情必须构成 **有向无环图 (DAG)**。严禁任何形式的死循环。
+- ID 递增原则：所有节点 key (除了 "start") 必须是严格递增的数字。
+    - `choices.nextNodeId` 只能指向 **数字更大** 的节点或 `endings`。
+    - "start" 节点被视为 0，可以指向任何数字节点。
+    - **严禁回退，严禁指向自身**。"#;
+
+/// Replaces the DAG rules when the request sets `allowCycles`, for
+/// time-loop style stories that deliberately return to earlier scenes.
+const LOOP_GRAPH_RULES: &str = r#"- 允许循环：本剧情允许回到先前节点的循环结构 (如时间循环)，`choices.nextNodeId` 可以指向任意节点 key (包括更小编号的节点) 或 `endings`。
+- 循环出口：每个循环中必须至少有一个选项能够离开循环并最终到达结局，严禁让玩家被永久困住。
+- 节点编号：所有节点 key (除了 "start") 仍使用纯数字。"#;
+
+pub(crate) fn construct_prompt(req: &GenerateRequest) -> String {
+    let topic = req
+        .theme
+        .as_deref()
+        .or(req.free_input.as_deref())
+        .unwrap_or("Unknown Theme");
+
+    let has_worldview = req
+        .worldview
+        .as_deref()
+        .is_some_and(|w| !w.trim().is_empty());
+    let mut full_topic = match effective_synopsis(req) {
+        Some(worldview) if has_worldview => format!(
+            "Theme/Genre: {}\nSynopsis: {}\n（以上世界观已扩写完成，是本剧本的权威设定：直接基于它创作，不要改写、重新扩写或另起设定。）",
+            topic, worldview
+        ),
+        Some(synopsis) => format!("Theme/Genre: {}\nSynopsis: {}", topic, synopsis),
+        None => format!("Theme/Genre: {}", topic),
+    };
+    let genre_tags = sanitize_genre_tags(req.genre.as_deref());
+    if !genre_tags.is_empty() {
+        full_topic.push_str(&format!(
+            "\n类型标签: {}（剧情基调、冲突设置与结局走向必须符合这些类型）",
+            genre_tags.join(" / ")
+        ));
+    }
+
+    let language_tag = req.language.as_deref().unwrap_or("zh-CN");
+    let language_label = if language_tag.to_lowercase().starts_with("zh") {
+        "简体中文"
+    } else if language_tag.to_lowercase().starts_with("en") {
+        "English"
+    } else {
+        language_tag
+    };
+
+    let (prompt_characters, omitted) = cap_prompt_characters(
+        req.characters.as_deref().unwrap_or(&[]),
+        prompt_character_cap(),
+    );
+    let mut characters_json =
+        serde_json::to_string_pretty(&prompt_characters).unwrap_or_else(|_| "[]".to_string());
+    if omitted > 0 {
+        characters_json.push_str(&format!(
+            "\n（另有 {} 个配角因篇幅限制未列出，请只使用上面列出的角色。）",
+            omitted
+        ));
+    }
+
+    let (min_endings, max_endings) = prompt_endings_range(req);
+    let endings_rule = if min_endings == max_endings {
+        format!("必须恰好为 **{}** 个", min_endings)
+    } else {
+        format!("必须在 **{} 到 {}** 之间", min_endings, max_endings)
+    };
+    let endings_count = if min_endings == max_endings {
+        min_endings.to_string()
+    } else {
+        format!("{}~{}", min_endings, max_endings)
+    };
+
+    let quick_ending_rule: Cow<'static, str> = match req.quick_ending_level {
+        Some(n) => format!(
+            "也就是说，最迟在 **Level {}** (含) 之前，就必须存在通过特定选项直接进入结局的路径。",
+            n
+        )
+        .into(),
+        None => "也就是说，在较早的层级 (如 Level 3-5) 就允许通过特定选项直接进入结局。".into(),
+    };
+
+    let graph_rules = if req.allow_cycles.unwrap_or(false) {
+        LOOP_GRAPH_RULES
+    } else {
+        DAG_GRAPH_RULES
+    };
 
     let protagonist_name = req
         .characters
@@ -138,19 +536,15 @@ interface Ending {
     - 结局引用：`StoryNode` 中的 `choices` 若指向结局，必须引用 `endings` 中的 key。
 
 # 三、数值硬性约束 (校验失败将视为错误)
-- 节点总数：`nodes` 的数量必须在 **35 到 45** 之间 (含 35/45)。
-- 结局数量：`endings` 的数量必须在 **4 到 6** 之间。
+- 节点总数：`nodes` 的数量必须在 **{min_nodes} 到 {max_nodes}** 之间 (含 {min_nodes}/{max_nodes})。
+- 结局数量：`endings` 的数量{}。
 - 单节点字数：每个节点的 `content` (AI 智能扩写) 字数必须严格控制在 **45 到 85 字** 之间。
-- 路径深度：必须保证所有的故事线都经过 **至少 12 个节点**。
+- 路径深度：必须保证所有的故事线都经过 **至少 {} 个节点**。
 
 # 四、Nodes 结构与逻辑约束 (重点)
 
 ## 1. 图结构与流程
-- DAG 无环结构：剧情必须构成 **有向无环图 (DAG)**。严禁任何形式的死循环。
-- ID 递增原则：所有节点 key (除了 "start") 必须是严格递增的数字。
-    - `choices.nextNodeId` 只能指向 **数字更大** 的节点或 `endings`。
-    - "start" 节点被视为 0，可以指向任何数字节点。
-    - **严禁回退，严禁指向自身**。
+{}
 
 ## 2. Level (层级) 控制
 每层的节点指的是 level 值相同的节点
@@ -199,7 +593,7 @@ interface Ending {
 - 结局描述：每个结局的 `description` 长度不能超过 **40 个字**。
 - 快速通道：**必须包含一个可以快速到达的结局路径**。
     - 例如：从 Start -> 节点 3 -> 节点 5 -> (选择某选项) -> 直接到达结局。
-    - 也就是说，在较早的层级 (如 Level 3-5) 就允许通过特定选项直接进入结局。
+    - {}
 - 互斥规则：
     - `nodes` 中的节点 **不允许** 包含 `endingKey` 属性。
     - 结局只能通过 `choices.nextNodeId` 指向 `endings` 的 Key 来触发。
@@ -213,12 +607,23 @@ interface Ending {
 # 输出规则
 - 输出必须是 **纯 JSON** 文本。
 - **不要** 包含 markdown 代码块标记。
-- `nodes` 数量：**35~45**。
-- `endings` 数量：**4~6**。
+- `nodes` 数量：**{min_nodes}~{max_nodes}**。
+- `endings` 数量：**{}**。
 - 必须包含 `start` 节点。
 开始创作！
 "#,
-        full_topic, language_label, protagonist_name, characters_json, types_def
+        full_topic,
+        language_label,
+        endings_rule,
+        req.min_path_depth.unwrap_or(DEFAULT_MIN_PATH_DEPTH),
+        graph_rules,
+        protagonist_name,
+        quick_ending_rule,
+        characters_json,
+        GENERATE_TYPES_DEF,
+        endings_count,
+        min_nodes = PROMPT_NODE_RANGE.0,
+        max_nodes = PROMPT_NODE_RANGE.1,
     )
 }
 
@@ -264,6 +669,125 @@ pub(crate) fn construct_expand_worldview_prompt(req: &ExpandWorldviewRequest) ->
     }
 }
 
+/// Synopsis length the worldview prompt asks for, in `count_display_chars`.
+pub(crate) const WORLDVIEW_MIN_CHARS: usize = 600;
+pub(crate) const WORLDVIEW_MAX_CHARS: usize = 800;
+
+/// How far `len` falls outside the worldview range; 0 when inside it.
+pub(crate) fn worldview_length_gap(len: usize) -> usize {
+    if len < WORLDVIEW_MIN_CHARS {
+        WORLDVIEW_MIN_CHARS - len
+    } else {
+        len.saturating_sub(WORLDVIEW_MAX_CHARS)
+    }
+}
+
+/// Follow-up prompt asking the model to expand or condense an out-of-range
+/// synopsis, or `None` when `content` already fits.
+pub(crate) fn construct_worldview_length_retry_prompt(
+    prompt: &str,
+    content: &str,
+) -> Option<String> {
+    let len = count_display_chars(content.trim());
+    if worldview_length_gap(len) == 0 {
+        return None;
+    }
+    let target = (WORLDVIEW_MIN_CHARS + WORLDVIEW_MAX_CHARS) / 2;
+    let instruction = if len < WORLDVIEW_MIN_CHARS {
+        format!(
+            "只有 {} 字，篇幅不足。请在保持情节与人物不变的前提下扩写",
+            len
+        )
+    } else {
+        format!("有 {} 字，篇幅过长。请在保留核心情节的前提下精简", len)
+    };
+    Some(format!(
+        "{}
+
+# 字数修正
+你上一次的输出如下：
+{}
+
+上一次输出{}到 {} 字左右（必须在 {}-{} 字之间），直接输出修正后的完整文本，不要包含任何前言后语。",
+        prompt,
+        content.trim(),
+        instruction,
+        target,
+        WORLDVIEW_MIN_CHARS,
+        WORLDVIEW_MAX_CHARS
+    ))
+}
+
+/// Fields a JSON-wrapped worldview reply has been seen to carry its text in.
+const WORLDVIEW_TEXT_FIELDS: [&str; 4] = ["synopsis", "worldview", "text", "content"];
+
+/// The worldview prompt asks for prose, but the model sometimes wraps it in
+/// JSON anyway: a bare JSON string, or an object with the text under one of
+/// `WORLDVIEW_TEXT_FIELDS`. Those are unwrapped; anything else is returned as is.
+pub(crate) fn unwrap_worldview_text(raw: &str) -> String {
+    let unwrapped = match serde_json::from_str::<serde_json::Value>(&clean_json(raw)) {
+        Ok(serde_json::Value::String(text)) => Some(text),
+        Ok(serde_json::Value::Object(map)) => WORLDVIEW_TEXT_FIELDS.iter().find_map(|key| {
+            map.get(*key)
+                .and_then(|v| v.as_str())
+                .filter(|text| !text.trim().is_empty())
+                .map(str::to_string)
+        }),
+        _ => None,
+    };
+    unwrapped.unwrap_or_else(|| raw.to_string())
+}
+
+/// The first JSON array or object embedded in `raw`, for replies that put
+/// prose around the JSON despite `response_format: json_object`.
+fn embedded_json(raw: &str) -> Option<&str> {
+    let start = raw.find(['[', '{'])?;
+    let close = if raw[start..].starts_with('[') {
+        ']'
+    } else {
+        '}'
+    };
+    let end = raw.rfind(close)?;
+    (end > start).then(|| &raw[start..=end])
+}
+
+/// Parses the `/expand/character` reply. Besides the bare array the prompt
+/// asks for, accepts a fenced block, prose around the JSON, an object
+/// wrapping the array (`{"characters": [...]}` or any single array field)
+/// and a lone character object.
+pub(crate) fn parse_expanded_characters(raw: &str) -> Result<Vec<CharacterInput>, String> {
+    let value = serde_json::from_str::<serde_json::Value>(&clean_json(raw))
+        .or_else(|e| {
+            embedded_json(raw)
+                .and_then(|slice| serde_json::from_str(&clean_json(slice)).ok())
+                .ok_or(e)
+        })
+        .map_err(|e| e.to_string())?;
+
+    let list = match value {
+        serde_json::Value::Object(mut map) => {
+            let arrays: Vec<String> = map
+                .iter()
+                .filter(|(_, v)| v.is_array())
+                .map(|(k, _)| k.clone())
+                .collect();
+            let key = if map.get("characters").is_some_and(|v| v.is_array()) {
+                Some("characters".to_string())
+            } else if arrays.len() == 1 {
+                arrays.into_iter().next()
+            } else {
+                None
+            };
+            match key.and_then(|k| map.remove(&k)) {
+                Some(list) => list,
+                None => serde_json::Value::Array(vec![serde_json::Value::Object(map)]),
+            }
+        }
+        other => other,
+    };
+    serde_json::from_value(list).map_err(|e| e.to_string())
+}
+
 pub(crate) fn construct_expand_character_prompt(req: &ExpandCharacterRequest) -> String {
     let language = req.language.as_deref().unwrap_or("zh-CN");
     // Use worldview as the synopsis source since frontend sends it in 'worldview' field
@@ -352,3 +876,183 @@ pub(crate) fn construct_expand_character_prompt(req: &ExpandCharacterRequest) ->
         )
     }
 }
+
+pub(crate) fn construct_split_node_prompt(
+    template: &MovieTemplate,
+    node_id: &str,
+    parts: usize,
+) -> String {
+    let language = if template.meta.language.trim().is_empty() {
+        "zh-CN"
+    } else {
+        template.meta.language.as_str()
+    };
+    let (content, choices) = template
+        .nodes
+        .get(node_id)
+        .map(|n| {
+            let choices: Vec<String> = n.choices.iter().map(|c| format!("- {}", c.text)).collect();
+            (n.content.as_str(), choices.join("\n"))
+        })
+        .unwrap_or(("", String::new()));
+    let choices = if choices.is_empty() {
+        "（无，该节点为结局前的最后一幕）".to_string()
+    } else {
+        choices
+    };
+
+    format!(
+        "你是一名资深互动电影编剧。
+请把《{}》中的一个剧情节点拆分为 {} 个按时间顺序依次发生的连续片段（beat），让节奏更舒缓、画面更具体。
+
+故事梗概：
+{}
+
+原节点内容：
+{}
+
+原节点之后玩家可做的选择（拆分后由最后一个片段承接，不要改写）：
+{}
+
+要求：
+1. 必须恰好输出 {} 个片段，按发生顺序排列，合起来完整覆盖原节点的剧情，不得新增分支或改变结局走向。
+2. 每个片段的 `content` 字数控制在 **45 到 85 字** 之间，使用第一人称，保持原有人物与语气。
+3. 除最后一个片段外，每个片段提供一个 `choice`：推进到下一片段的唯一选项文本（10 字以内）；最后一个片段的 `choice` 留空。
+
+# 语言要求
+输出语言：{}。
+
+# 输出格式
+{{
+  \"beats\": [
+    {{ \"content\": \"片段内容\", \"choice\": \"推进到下一片段的选项\" }}
+  ]
+}}
+注意：必须严格遵守 JSON 格式，不要包含 Markdown 代码块标记。",
+        template.title, parts, template.meta.synopsis, content, choices, parts, language
+    )
+}
+
+/// Up to `limit` nodes on a shortest path from `start` to `node_id`, each with
+/// the choice taken to leave it, ending at `node_id` itself.
+fn story_path_to(template: &MovieTemplate, node_id: &str, limit: usize) -> Vec<(String, String)> {
+    let mut parent: HashMap<String, (String, String)> = HashMap::new();
+    let mut queue: VecDeque<String> = VecDeque::from(vec!["start".to_string()]);
+    let mut seen: HashSet<String> = HashSet::from(["start".to_string()]);
+    while let Some(key) = queue.pop_front() {
+        if key == node_id {
+            break;
+        }
+        let Some(node) = template.nodes.get(&key) else {
+            continue;
+        };
+        for c in node.choices.iter() {
+            if template.nodes.contains_key(&c.next_node_id) && seen.insert(c.next_node_id.clone()) {
+                parent.insert(c.next_node_id.clone(), (key.clone(), c.text.clone()));
+                queue.push_back(c.next_node_id.clone());
+            }
+        }
+    }
+
+    let mut path: Vec<(String, String)> = vec![(node_id.to_string(), String::new())];
+    let mut cur = node_id.to_string();
+    while let Some((prev, choice)) = parent.get(&cur) {
+        if path.len() >= limit {
+            break;
+        }
+        path.push((prev.clone(), choice.clone()));
+        cur = prev.clone();
+    }
+    path.reverse();
+    path.into_iter()
+        .filter_map(|(k, choice)| template.nodes.get(&k).map(|n| (n.content.clone(), choice)))
+        .collect()
+}
+
+pub(crate) fn construct_continue_prompt(
+    template: &MovieTemplate,
+    from_node_id: &str,
+    instruction: &str,
+    max_nodes: usize,
+) -> String {
+    let language = if template.meta.language.trim().is_empty() {
+        "zh-CN"
+    } else {
+        template.meta.language.as_str()
+    };
+
+    let mut names: Vec<&str> = template
+        .characters
+        .values()
+        .map(|c| c.name.as_str())
+        .collect();
+    names.sort();
+
+    let story_so_far = story_path_to(template, from_node_id, 4)
+        .into_iter()
+        .map(|(content, choice)| {
+            if choice.is_empty() {
+                format!("- {}", content)
+            } else {
+                format!("- {}\n  （玩家选择：{}）", content, choice)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let existing_choices = template
+        .nodes
+        .get(from_node_id)
+        .map(|n| {
+            n.choices
+                .iter()
+                .map(|c| format!("- {}", c.text))
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "（无）".to_string());
+
+    format!(
+        "你是一名资深互动电影编剧，正在与作者逐节点共同创作《{}》。
+
+故事梗概：
+{}
+
+主要角色：{}
+
+此前的剧情（按时间顺序，最后一条为当前节点）：
+{}
+
+当前节点已有的选项（新选项不得与之重复）：
+{}
+
+作者对接下来剧情的要求：
+{}
+
+要求：
+1. 为当前节点新增一个选项 `choiceText`（15 字以内），并续写该选项之后的 1 到 {} 个连续节点，按发生顺序排列。
+2. 每个节点的 `content` 字数控制在 **45 到 85 字** 之间，使用第一人称，人物与语气与前文保持一致。
+3. 除最后一个节点外，每个节点提供一个 `choice`：推进到下一节点的选项文本（10 字以内）；最后一个节点的 `choice` 留空，留给作者继续创作。
+
+# 语言要求
+输出语言：{}。
+
+# 输出格式
+{{
+  \"choiceText\": \"当前节点的新选项\",
+  \"nodes\": [
+    {{ \"content\": \"节点内容\", \"choice\": \"推进到下一节点的选项\" }}
+  ]
+}}
+注意：必须严格遵守 JSON 格式，不要包含 Markdown 代码块标记。",
+        template.title,
+        template.meta.synopsis,
+        names.join("、"),
+        story_so_far,
+        existing_choices,
+        instruction.trim(),
+        max_nodes,
+        language
+    )
+}
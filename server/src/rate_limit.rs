@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// 超过该数量的 IP 记录时，清理已回满的桶，避免内存无限增长
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// In-memory token bucket keyed by client IP. Rejects obvious bursts before
+/// the DB-backed quota check runs; the DB stays the source of truth for quotas.
+pub(crate) struct BurstLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl BurstLimiter {
+    pub(crate) fn new(capacity: u32, refill_per_minute: u32) -> Self {
+        Self {
+            capacity: capacity.max(1) as f64,
+            refill_per_sec: refill_per_minute as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn from_env() -> Self {
+        let read = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.trim().parse::<u32>().ok())
+                .unwrap_or(default)
+        };
+        Self::new(
+            read("MOVIE_GAMES_BURST_CAPACITY", 5),
+            read("MOVIE_GAMES_BURST_REFILL_PER_MINUTE", 10),
+        )
+    }
+
+    pub(crate) fn check(&self, key: &str) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    pub(crate) fn check_at(&self, key: &str, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(key) {
+            let (capacity, rate) = (self.capacity, self.refill_per_sec);
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.updated_at).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+pub(crate) fn sha256_hex(s: &str) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Clients exempt from the DB-backed daily/window quotas: `TRUSTED_IPS` lists
+/// client IPs, `TRUSTED_KEYS` lists SHA-256 hex digests of pass keys sent in
+/// the `apiKey` field (the plain key never needs to be configured).
+pub(crate) struct TrustedClients {
+    ips: Vec<String>,
+    key_hashes: Vec<String>,
+}
+
+impl TrustedClients {
+    pub(crate) fn new(ips: &str, key_hashes: &str) -> Self {
+        let split = |raw: &str| -> Vec<String> {
+            raw.split(',')
+                .map(|v| v.trim().to_lowercase())
+                .filter(|v| !v.is_empty())
+                .collect()
+        };
+        Self {
+            ips: split(ips),
+            key_hashes: split(key_hashes),
+        }
+    }
+
+    pub(crate) fn from_env() -> Self {
+        let read = |name: &str| std::env::var(name).unwrap_or_default();
+        Self::new(&read("TRUSTED_IPS"), &read("TRUSTED_KEYS"))
+    }
+
+    pub(crate) fn is_trusted_ip(&self, client_ip: &str) -> bool {
+        let ip = client_ip.trim().to_lowercase();
+        !ip.is_empty() && self.ips.contains(&ip)
+    }
+
+    pub(crate) fn is_trusted_key(&self, api_key: Option<&str>) -> bool {
+        let Some(key) = api_key.map(str::trim).filter(|k| !k.is_empty()) else {
+            return false;
+        };
+        !self.key_hashes.is_empty() && self.key_hashes.contains(&sha256_hex(key))
+    }
+}
@@ -0,0 +1,168 @@
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::rate_limit::sha256_hex;
+use crate::types::MovieTemplate;
+
+/// Hex chars of the client IP digest kept in the summary: enough to follow
+/// one client across lines without logging the address itself.
+const CLIENT_IP_HASH_CHARS: usize = 12;
+
+/// One request's lifecycle, filled in as the request progresses and printed
+/// as a single `request_summary {...}` JSON line when it ends, so a request
+/// can be followed with one grep instead of correlating scattered prints.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct RequestSummary {
+    pub(crate) route: String,
+    pub(crate) client_ip_hash: String,
+    pub(crate) model: Option<String>,
+    /// Prompt length in characters.
+    pub(crate) prompt_len: Option<usize>,
+    pub(crate) glm_latency_ms: Option<u64>,
+    /// `ok` or `invalid_json`; unset when the model output was never parsed.
+    pub(crate) parse_result: Option<&'static str>,
+    /// GLM calls repeated because the output was not valid JSON.
+    pub(crate) parse_retries: usize,
+    pub(crate) node_count: Option<usize>,
+    pub(crate) ending_count: Option<usize>,
+    /// CogView images in the returned template (SVG fallbacks not counted).
+    pub(crate) image_count: Option<usize>,
+    /// Time spent generating images, see `StageTimings`.
+    pub(crate) images_ms: Option<u64>,
+    /// Time spent parsing, repairing and saving the template.
+    pub(crate) process_ms: Option<u64>,
+    /// Sensitive-word replacements made in the request payload.
+    pub(crate) sensitive_hits: usize,
+    /// Served another in-flight identical request's result.
+    pub(crate) coalesced: bool,
+    /// Final HTTP status sent to the client.
+    pub(crate) status: Option<u16>,
+}
+
+/// Shared between a handler and the task it spawns.
+pub(crate) type SharedRequestSummary = Arc<Mutex<RequestSummary>>;
+
+impl RequestSummary {
+    pub(crate) fn new(route: &str, client_ip: &str) -> Self {
+        let mut client_ip_hash = sha256_hex(client_ip);
+        client_ip_hash.truncate(CLIENT_IP_HASH_CHARS);
+        Self {
+            route: route.to_string(),
+            client_ip_hash,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn shared(self) -> SharedRequestSummary {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub(crate) fn record_template(&mut self, template: &MovieTemplate) {
+        let is_generated = |img: &Option<String>| {
+            img.as_deref()
+                .is_some_and(|s| !s.is_empty() && !s.starts_with("data:image/svg+xml"))
+        };
+        let image_count = usize::from(is_generated(&template.background_image_base64))
+            + template
+                .characters
+                .values()
+                .filter(|c| is_generated(&c.avatar_path))
+                .count()
+            + template
+                .nodes
+                .values()
+                .filter(|n| is_generated(&n.background_image_base64))
+                .count();
+
+        self.node_count = Some(template.nodes.len());
+        self.ending_count = Some(template.endings.len());
+        self.image_count = Some(image_count);
+    }
+
+    pub(crate) fn to_log_line(&self) -> String {
+        format!(
+            "request_summary {}",
+            serde_json::to_string(self).unwrap_or_default()
+        )
+    }
+}
+
+pub(crate) fn update_summary(summary: &SharedRequestSummary, f: impl FnOnce(&mut RequestSummary)) {
+    f(&mut summary.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+/// Records the final status and prints the summary line.
+pub(crate) fn emit_request_summary(summary: &SharedRequestSummary, status: u16) {
+    let mut summary = summary.lock().unwrap_or_else(|e| e.into_inner());
+    summary.status = Some(status);
+    println!("{}", summary.to_log_line());
+}
+
+/// Wall-clock split of a `/generate` call. The stages cover the time from the
+/// start of the GLM call to the final log write, so they add up to the
+/// `response_time_ms` stored next to them.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StageTimings {
+    /// Sending the request and waiting for GLM to answer.
+    pub(crate) glm_ms: i64,
+    /// CogView background, avatar and node images.
+    pub(crate) images_ms: i64,
+    /// Reading and parsing the model output, repairing and saving the template.
+    pub(crate) process_ms: i64,
+}
+
+impl StageTimings {
+    pub(crate) fn total_ms(&self) -> i64 {
+        self.glm_ms + self.images_ms + self.process_ms
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    Glm,
+    Images,
+    Process,
+}
+
+/// Books wall-clock time to stages: each `lap` charges the time since the
+/// previous lap (or the start) to one stage, so nothing is counted twice.
+#[derive(Debug, Clone)]
+pub(crate) struct StageClock {
+    last: Instant,
+    glm: Duration,
+    images: Duration,
+    process: Duration,
+}
+
+impl StageClock {
+    pub(crate) fn starting_at(start: Instant) -> Self {
+        Self {
+            last: start,
+            glm: Duration::ZERO,
+            images: Duration::ZERO,
+            process: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn lap(&mut self, stage: Stage) {
+        let now = Instant::now();
+        let spent = now.saturating_duration_since(self.last);
+        self.last = now;
+        match stage {
+            Stage::Glm => self.glm += spent,
+            Stage::Images => self.images += spent,
+            Stage::Process => self.process += spent,
+        }
+    }
+
+    pub(crate) fn timings(&self) -> StageTimings {
+        let ms = |d: Duration| d.as_millis().min(i64::MAX as u128) as i64;
+        StageTimings {
+            glm_ms: ms(self.glm),
+            images_ms: ms(self.images),
+            process_ms: ms(self.process),
+        }
+    }
+}
@@ -4,12 +4,11 @@ use std::path::PathBuf;
 
 pub(crate) struct SensitiveFilter {
     filter: Filter,
+    active: bool,
 }
 
 impl SensitiveFilter {
     pub(crate) fn from_env() -> Self {
-        let mut filter = create_filter_with_default_dict();
-
         let mut words: Vec<String> = Vec::new();
 
         if let Ok(raw) = std::env::var("SENSITIVE_WORDS") {
@@ -39,9 +38,24 @@ impl SensitiveFilter {
             }
         }
 
+        Self::with_default_dict(create_filter_with_default_dict(), &words)
+    }
+
+    /// Builds on the default dictionary when there is one. Without it only
+    /// `words` are filtered, so a missing dict doesn't keep the server from
+    /// starting.
+    pub(crate) fn with_default_dict(default_dict: Option<Filter>, words: &[String]) -> Self {
+        let active = default_dict.is_some() || !words.is_empty();
+        let mut filter = default_dict.unwrap_or_else(|| {
+            eprintln!(
+                "Warning: sensitive-rs 默认词库不可用，仅使用 SENSITIVE_WORDS / SENSITIVE_WORDS_PATH 中的词（共 {} 个）",
+                words.len()
+            );
+            Filter::new()
+        });
         let refs: Vec<&str> = words.iter().map(|s| s.as_str()).collect();
         filter.add_words(&refs);
-        Self { filter }
+        Self { filter, active }
     }
 
     #[cfg(test)]
@@ -49,7 +63,16 @@ impl SensitiveFilter {
         let mut filter = Filter::new();
         let refs: Vec<&str> = words.iter().map(|s| s.as_str()).collect();
         filter.add_words(&refs);
-        Self { filter }
+        Self {
+            filter,
+            active: !words.is_empty(),
+        }
+    }
+
+    /// False when neither the default dictionary nor any configured word is
+    /// loaded, i.e. nothing would ever be masked.
+    pub(crate) fn is_active(&self) -> bool {
+        self.active
     }
 
     pub(crate) fn sanitize_json(&self, value: &mut Value) -> usize {
@@ -105,6 +128,8 @@ fn should_skip_key(key: &str) -> bool {
         "apiKey"
             | "baseUrl"
             | "model"
+            | "imageModel"
+            | "imageQuality"
             | "size"
             | "backgroundImageBase64"
             | "avatarPath"
@@ -113,7 +138,10 @@ fn should_skip_key(key: &str) -> bool {
     )
 }
 
-fn create_filter_with_default_dict() -> Filter {
+/// Loads the sensitive-rs default dictionary. An explicit
+/// `SENSITIVE_DEFAULT_DICT_PATH` that fails to load is fatal since the
+/// operator asked for it; otherwise a missing dict yields `None`.
+fn create_filter_with_default_dict() -> Option<Filter> {
     if let Ok(path) = std::env::var("SENSITIVE_DEFAULT_DICT_PATH") {
         let p = path.trim();
         if !p.is_empty() {
@@ -121,25 +149,23 @@ fn create_filter_with_default_dict() -> Filter {
             filter.load_word_dict(p).unwrap_or_else(|e| {
                 panic!("无法加载 SENSITIVE_DEFAULT_DICT_PATH 指定的词库: {}", e)
             });
-            return filter;
+            return Some(filter);
         }
     }
 
     if let Ok(filter) = Filter::with_default_dict() {
-        return filter;
+        return Some(filter);
     }
 
-    if let Some(p) = find_sensitive_rs_default_dict_in_cargo_registry() {
-        let mut filter = Filter::new();
-        filter
-            .load_word_dict(&p)
-            .unwrap_or_else(|e| panic!("无法加载 sensitive-rs 默认词库文件 {:?}: {}", p, e));
-        return filter;
+    let p = find_sensitive_rs_default_dict_in_cargo_registry()?;
+    let mut filter = Filter::new();
+    match filter.load_word_dict(&p) {
+        Ok(_) => Some(filter),
+        Err(e) => {
+            eprintln!("无法加载 sensitive-rs 默认词库文件 {:?}: {}", p, e);
+            None
+        }
     }
-
-    panic!(
-        "无法加载 sensitive-rs 默认词库。请提供 SENSITIVE_DEFAULT_DICT_PATH 或确保运行目录存在 dict/dict.txt"
-    );
 }
 
 fn find_sensitive_rs_default_dict_in_cargo_registry() -> Option<PathBuf> {
@@ -0,0 +1,78 @@
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+type SharedResult<T> = Shared<BoxFuture<'static, Option<T>>>;
+
+/// Coalesces identical work that is already in flight: the first caller for
+/// a key leads and does the work, later callers wait for its result instead
+/// of repeating it. Nothing is cached once the leader finishes.
+pub(crate) struct SingleFlight<T: Clone> {
+    calls: Arc<Mutex<HashMap<String, SharedResult<T>>>>,
+}
+
+pub(crate) enum Flight<T: Clone> {
+    /// Do the work, then hand the result to `FlightLeader::finish`.
+    Leader(FlightLeader<T>),
+    /// Resolves to the leader's result, or `None` when the leader failed or
+    /// gave up, in which case the caller should do the work itself.
+    Follower(SharedResult<T>),
+}
+
+/// Dropping the leader without `finish` releases the key and tells the
+/// followers to fall back to their own work.
+pub(crate) struct FlightLeader<T: Clone> {
+    key: String,
+    calls: Arc<Mutex<HashMap<String, SharedResult<T>>>>,
+    tx: Option<oneshot::Sender<T>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            calls: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn join(&self, key: &str) -> Flight<T> {
+        let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(shared) = calls.get(key) {
+            return Flight::Follower(shared.clone());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        calls.insert(key.to_string(), rx.map(Result::ok).boxed().shared());
+        Flight::Leader(FlightLeader {
+            key: key.to_string(),
+            calls: self.calls.clone(),
+            tx: Some(tx),
+        })
+    }
+}
+
+impl<T: Clone> FlightLeader<T> {
+    pub(crate) fn finish(mut self, value: T) {
+        self.release();
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(value);
+        }
+    }
+
+    // Unregister before answering so a request arriving afterwards starts a
+    // fresh call rather than receiving this result.
+    fn release(&self) {
+        self.calls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+    }
+}
+
+impl<T: Clone> Drop for FlightLeader<T> {
+    fn drop(&mut self) {
+        if self.tx.is_some() {
+            self.release();
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
 
-use crate::api_types::CharacterInput;
-use crate::types::{self, MovieTemplate};
+use crate::api_types::{
+    CastMember, CharacterInput, GraphMetrics, NodeLayout, RelationshipEdge, RelationshipGraph,
+};
+use crate::types::{self, map_key_order, MovieTemplate};
 
+/// Model-output `meta.genre`: array items become "Sci-Fi, Drama", matching
+/// `MetaInfo::genre`.
 fn deserialize_option_string_or_vec<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
 where
     D: Deserializer<'de>,
@@ -19,7 +24,7 @@ where
     let opt: Option<OptionStringOrVec> = Option::deserialize(deserializer)?;
     match opt {
         Some(OptionStringOrVec::String(s)) => Ok(Some(s)),
-        Some(OptionStringOrVec::Vec(v)) => Ok(Some(v.join("\n"))),
+        Some(OptionStringOrVec::Vec(v)) => Ok(Some(v.join(", "))),
         None => Ok(None),
     }
 }
@@ -54,6 +59,8 @@ struct CharacterLite {
     background: Option<String>,
     avatar_path: Option<String>,
     description: Option<String>,
+    voice: Option<String>,
+    voice_description: Option<String>,
 }
 
 impl From<CharacterLite> for types::Character {
@@ -69,6 +76,8 @@ impl From<CharacterLite> for types::Character {
             role: lite.role.unwrap_or_default(),
             background: lite.background.or(lite.description).unwrap_or_default(),
             avatar_path: lite.avatar_path,
+            voice: lite.voice,
+            voice_description: lite.voice_description,
         }
     }
 }
@@ -107,23 +116,45 @@ fn convert_node_lite(key: String, lite: StoryNodeLite) -> types::StoryNode {
             .choices
             .map(|choices| choices.into_iter().map(|c| c.into()).collect())
             .unwrap_or_default(),
+        kind: None,
+        background_image_base64: None,
+        background_alt: None,
     }
 }
 
+// Keys seen in model output for a choice's label and target, canonical first.
+// Read from a flattened map rather than serde aliases so a choice carrying
+// more than one of them keeps the first instead of failing as a duplicate.
+const CHOICE_TEXT_KEYS: [&str; 3] = ["text", "label", "option"];
+const CHOICE_TARGET_KEYS: [&str; 6] =
+    ["nextNodeId", "next_node_id", "next", "target", "to", "goto"];
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ChoiceLite {
-    text: Option<String>,
-    next_node_id: Option<String>,
     #[serde(default)]
     affinity_effect: Option<types::AffinityEffect>,
+    #[serde(flatten)]
+    fields: HashMap<String, Value>,
+}
+
+impl ChoiceLite {
+    fn first_string(&self, keys: &[&str]) -> Option<String> {
+        keys.iter()
+            .find_map(|key| self.fields.get(*key).and_then(Value::as_str))
+            .map(str::to_string)
+    }
 }
 
 impl From<ChoiceLite> for types::Choice {
     fn from(lite: ChoiceLite) -> Self {
         types::Choice {
-            text: lite.text.unwrap_or_else(|| "Continue".to_string()),
-            next_node_id: lite.next_node_id.unwrap_or_else(|| "END".to_string()),
+            text: lite
+                .first_string(&CHOICE_TEXT_KEYS)
+                .unwrap_or_else(|| "Continue".to_string()),
+            next_node_id: lite
+                .first_string(&CHOICE_TARGET_KEYS)
+                .unwrap_or_else(|| "END".to_string()),
             affinity_effect: lite.affinity_effect,
         }
     }
@@ -154,6 +185,7 @@ pub(crate) fn convert_lite_to_full(lite: MovieTemplateLite, language: &str) -> M
                 .unwrap_or_default(),
             language: language.to_string(),
         },
+        genre_tags: Vec::new(),
         background_image_base64: None,
         nodes: lite
             .nodes
@@ -174,6 +206,9 @@ pub(crate) fn convert_lite_to_full(lite: MovieTemplateLite, language: &str) -> M
                                 level: None,
                                 characters: None,
                                 choices: Vec::new(),
+                                kind: None,
+                                background_image_base64: None,
+                                background_alt: None,
                             },
                         ))
                     }
@@ -194,11 +229,15 @@ pub(crate) fn convert_lite_to_full(lite: MovieTemplateLite, language: &str) -> M
 
 pub(crate) fn normalize_character_ids(template: &mut MovieTemplate) {
     // Rebuild characters map with name as key (as per user requirement)
+    // Same-name characters collapse into one entry; iterate in key order so the merge is stable
     let mut new_characters: HashMap<String, types::Character> = HashMap::new();
+    let mut keys: Vec<&String> = template.characters.keys().collect();
+    keys.sort();
 
-    for (k, c) in template.characters.iter() {
-        let key = if !c.name.is_empty() {
-            c.name.clone()
+    for k in keys {
+        let c = &template.characters[k];
+        let key = if !c.name.trim().is_empty() {
+            c.name.trim().to_string()
         } else if !c.id.is_empty() {
             c.id.clone()
         } else {
@@ -207,12 +246,22 @@ pub(crate) fn normalize_character_ids(template: &mut MovieTemplate) {
 
         let mut char = c.clone();
         char.id = key.clone();
-        new_characters.insert(key, char);
+        char.name = char.name.trim().to_string();
+        match new_characters.get_mut(&key) {
+            Some(existing) => types::Character::merge_duplicate(existing, char),
+            None => {
+                new_characters.insert(key, char);
+            }
+        }
     }
 
     template.characters = new_characters;
 }
 
+fn has_legacy_node_prefix(key: &str) -> bool {
+    key.starts_with("n_") || key.starts_with("node_")
+}
+
 pub(crate) fn normalize_template_nodes(template: &mut MovieTemplate) {
     if template.nodes.is_empty() {
         return;
@@ -226,9 +275,12 @@ pub(crate) fn normalize_template_nodes(template: &mut MovieTemplate) {
     let mut mapping: HashMap<String, String> = HashMap::new();
     let mut used: HashMap<String, usize> = HashMap::new();
 
-    // Sort keys to ensure deterministic order (optional but good for consistency)
+    // Keys that are already canonical claim their names first, so a legacy
+    // `n_x` / `node_x` sitting next to an existing `x` is the one that gets
+    // suffixed. Otherwise sorted order could rename `x` and a second pass
+    // over the output would not be a no-op.
     let mut keys: Vec<String> = template.nodes.keys().cloned().collect();
-    keys.sort();
+    keys.sort_by_key(|k| (has_legacy_node_prefix(k), k.clone()));
 
     for old_key in keys {
         // Ensure "start" is "start", and other keys are kept as is (or sanitized if needed)
@@ -303,7 +355,23 @@ pub(crate) fn normalize_template_nodes(template: &mut MovieTemplate) {
     template.nodes = new_nodes;
 }
 
+const DEFAULT_MAX_ENDINGS: usize = 6;
+
+/// Most endings a template keeps after normalization, from `MAX_ENDINGS`.
+/// Defaults to 6, the top of the 4–6 range the prompt asks for.
+pub(crate) fn max_endings_cap() -> usize {
+    std::env::var("MAX_ENDINGS")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_ENDINGS)
+}
+
 pub(crate) fn normalize_template_endings(template: &mut MovieTemplate) {
+    normalize_template_endings_with_cap(template, max_endings_cap());
+}
+
+pub(crate) fn normalize_template_endings_with_cap(template: &mut MovieTemplate, cap: usize) {
     if template.endings.is_empty() {
         return;
     }
@@ -346,44 +414,244 @@ pub(crate) fn normalize_template_endings(template: &mut MovieTemplate) {
         }
     }
 
-    if template.endings.len() > 5 {
-        let mut keep: HashMap<String, types::Ending> = HashMap::new();
-        for k in ["ending_good", "ending_neutral", "ending_bad"] {
-            if let Some(v) = template.endings.get(k).cloned() {
-                keep.insert(k.to_string(), v);
+    trim_endings(template, cap);
+}
+
+/// Keeps at most `cap` endings, retaining the canonical good/neutral/bad trio first.
+fn trim_endings(template: &mut MovieTemplate, cap: usize) {
+    if template.endings.len() <= cap {
+        return;
+    }
+
+    let mut keep: HashMap<String, types::Ending> = HashMap::new();
+    for k in ["ending_good", "ending_neutral", "ending_bad"] {
+        if keep.len() >= cap {
+            break;
+        }
+        if let Some(v) = template.endings.get(k).cloned() {
+            keep.insert(k.to_string(), v);
+        }
+    }
+
+    let mut rest: Vec<String> = template.endings.keys().cloned().collect();
+    rest.sort();
+    for k in rest {
+        if keep.len() >= cap {
+            break;
+        }
+        if keep.contains_key(&k) {
+            continue;
+        }
+        if let Some(v) = template.endings.get(&k).cloned() {
+            keep.insert(k, v);
+        }
+    }
+
+    template.endings = keep;
+}
+
+pub(crate) const MAX_EXACT_ENDINGS: u32 = 12;
+
+const NEUTRAL_ENDING_ZH: &str = "一切暂时归于平静。";
+const NEUTRAL_ENDING_EN: &str = "Things settled, for now.";
+
+/// Ending that repaired choices and dead-end nodes are sent to: the neutral
+/// ending, else the bad or the good one, else the first existing ending by
+/// key. `None` when the template has no endings at all.
+fn fallback_ending_key(template: &MovieTemplate) -> Option<String> {
+    ["ending_neutral", "ending_bad", "ending_good"]
+        .into_iter()
+        .find(|key| template.endings.contains_key(*key))
+        .map(str::to_string)
+        .or_else(|| template.endings.keys().min().cloned())
+}
+
+/// Adds the generic `ending_neutral` that `sanitize_template_graph_with` falls
+/// back to when a template has no endings.
+fn insert_neutral_ending(template: &mut MovieTemplate) {
+    let is_zh = template.meta.language.is_empty()
+        || template.meta.language.to_lowercase().starts_with("zh");
+    template.endings.insert(
+        "ending_neutral".to_string(),
+        types::Ending {
+            r#type: "neutral".to_string(),
+            description: if is_zh {
+                NEUTRAL_ENDING_ZH
+            } else {
+                NEUTRAL_ENDING_EN
             }
+            .to_string(),
+        },
+    );
+}
+
+/// Forces the template to carry exactly `count` endings: extras are trimmed
+/// (canonical trio first), missing ones are synthesized with generic descriptions.
+/// Choices pointing at trimmed endings are repaired later by `sanitize_template_graph`.
+pub(crate) fn enforce_exact_endings(template: &mut MovieTemplate, count: usize) {
+    if count == 0 {
+        return;
+    }
+
+    trim_endings(template, count);
+
+    let is_zh = template.meta.language.is_empty()
+        || template.meta.language.to_lowercase().starts_with("zh");
+
+    let canonical = [
+        (
+            "ending_good",
+            "good",
+            "我守住了最重要的东西。",
+            "I held on to what mattered most.",
+        ),
+        (
+            "ending_neutral",
+            "neutral",
+            NEUTRAL_ENDING_ZH,
+            NEUTRAL_ENDING_EN,
+        ),
+        (
+            "ending_bad",
+            "bad",
+            "我终究没能挽回局面。",
+            "In the end, I couldn't turn it around.",
+        ),
+    ];
+
+    for (key, kind, zh, en) in canonical {
+        if template.endings.len() >= count {
+            return;
         }
+        if template.endings.contains_key(key) {
+            continue;
+        }
+        template.endings.insert(
+            key.to_string(),
+            types::Ending {
+                r#type: kind.to_string(),
+                description: if is_zh { zh } else { en }.to_string(),
+            },
+        );
+    }
 
-        if keep.len() < 5 {
-            for (k, v) in template.endings.iter() {
-                if keep.len() >= 5 {
-                    break;
-                }
-                if keep.contains_key(k) {
-                    continue;
+    let mut i = 1usize;
+    while template.endings.len() < count {
+        let key = format!("ending_extra_{}", i);
+        i += 1;
+        if template.endings.contains_key(&key) {
+            continue;
+        }
+        template.endings.insert(
+            key,
+            types::Ending {
+                r#type: "neutral".to_string(),
+                description: if is_zh {
+                    "故事在这里走向了另一种结局。"
+                } else {
+                    "The story reaches a different close here."
                 }
-                keep.insert(k.clone(), v.clone());
-            }
+                .to_string(),
+            },
+        );
+    }
+}
+
+const DEFAULT_MAX_NODES_HARD_LIMIT: usize = 500;
+
+/// Upper bound on nodes a template may carry before graph repair runs, from
+/// `MAX_NODES_HARD_LIMIT`. Defaults to 500.
+pub(crate) fn max_nodes_hard_limit() -> usize {
+    std::env::var("MAX_NODES_HARD_LIMIT")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_NODES_HARD_LIMIT)
+}
+
+/// Optional passes of `sanitize_template_graph_with`. Exact dedup and cycle
+/// breaking are on by default; near-duplicate merging is opt-in.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct GraphRepairOptions {
+    /// Merge nodes with identical content and choices.
+    pub(crate) dedup_nodes: bool,
+    /// Redirect self-references and back edges found by DFS to an ending.
+    pub(crate) break_cycles: bool,
+    /// Also merge nodes whose content (character-bigram Jaccard, whitespace
+    /// ignored) is at least this similar and whose choices reach the same targets.
+    pub(crate) near_duplicate_threshold: Option<f64>,
+    /// Also collapse choices of one node that lead to the same target, keeping
+    /// the first. Identical `(text, target)` choices are always collapsed.
+    pub(crate) merge_same_target_choices: bool,
+}
+
+impl Default for GraphRepairOptions {
+    fn default() -> Self {
+        Self {
+            dedup_nodes: true,
+            break_cycles: true,
+            near_duplicate_threshold: None,
+            merge_same_target_choices: false,
         }
+    }
+}
+
+/// Drops repeated choices within a node, keeping the first occurrence. Two
+/// choices repeat when text (trimmed) and target match, or, with
+/// `merge_same_target`, whenever the target matches.
+fn dedup_node_choices(choices: &mut Vec<types::Choice>, merge_same_target: bool) {
+    let mut seen: HashSet<(String, String)> = HashSet::new();
+    choices.retain(|c| {
+        let text = if merge_same_target {
+            String::new()
+        } else {
+            c.text.trim().to_string()
+        };
+        seen.insert((text, c.next_node_id.clone()))
+    });
+}
+
+fn char_bigrams(text: &str) -> HashSet<(char, char)> {
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if chars.len() == 1 {
+        return HashSet::from([(chars[0], chars[0])]);
+    }
+    chars.windows(2).map(|w| (w[0], w[1])).collect()
+}
 
-        template.endings = keep;
+/// Jaccard similarity of two character-bigram sets, in `0.0..=1.0`; cheap
+/// enough to compare every node pair.
+fn jaccard(a: &HashSet<(char, char)>, b: &HashSet<(char, char)>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
     }
+    let shared = a.intersection(b).count();
+    shared as f64 / (a.len() + b.len() - shared) as f64
 }
 
+/// Default graph repair, except that a game generated with `allowCycles`
+/// keeps its loops.
 pub(crate) fn sanitize_template_graph(template: &mut MovieTemplate) {
+    let options = GraphRepairOptions {
+        break_cycles: !template.provenance.allow_cycles,
+        ..GraphRepairOptions::default()
+    };
+    sanitize_template_graph_with(template, options);
+}
+
+pub(crate) fn sanitize_template_graph_with(
+    template: &mut MovieTemplate,
+    options: GraphRepairOptions,
+) {
     if template.nodes.is_empty() {
         return;
     }
 
-    let ending_neutral_key = if template.endings.contains_key("ending_neutral") {
-        "ending_neutral".to_string()
-    } else if template.endings.contains_key("ending_bad") {
-        "ending_bad".to_string()
-    } else if template.endings.contains_key("ending_good") {
-        "ending_good".to_string()
-    } else {
-        "END".to_string()
-    };
+    // Without any ending, repairs point at an `ending_neutral` that is only
+    // added at the end if something actually uses it.
+    let existing_fallback = fallback_ending_key(template);
+    let synthesize_fallback = existing_fallback.is_none();
+    let ending_neutral_key = existing_fallback.unwrap_or_else(|| "ending_neutral".to_string());
 
     let mut signature_owner: HashMap<String, String> = HashMap::new();
     let mut redirect: HashMap<String, String> = HashMap::new();
@@ -399,31 +667,61 @@ pub(crate) fn sanitize_template_graph(template: &mut MovieTemplate) {
         keys.insert(0, "n_start".to_string());
     }
 
-    for node_id in keys.iter() {
-        if node_id == "start" || node_id == "n_start" {
-            signature_owner.insert(node_id.clone(), "".to_string());
-            continue;
+    if options.dedup_nodes {
+        for node_id in keys.iter() {
+            if node_id == "start" || node_id == "n_start" {
+                signature_owner.insert(node_id.clone(), "".to_string());
+                continue;
+            }
+
+            let Some(node) = template.nodes.get(node_id) else {
+                continue;
+            };
+
+            let text = node.content.trim().to_string();
+            let mut cparts: Vec<String> = node
+                .choices
+                .iter()
+                .map(|c| format!("{}→{}", c.text.trim(), c.next_node_id.trim()))
+                .collect();
+            cparts.sort();
+            let signature = format!("{}||{}", text, cparts.join("|"));
+
+            if let Some(owner) = signature_owner.get(&signature) {
+                if owner != node_id {
+                    redirect.insert(node_id.clone(), owner.clone());
+                }
+            } else {
+                signature_owner.insert(signature, node_id.clone());
+            }
         }
+    }
 
-        let Some(node) = template.nodes.get(node_id) else {
-            continue;
-        };
+    if let Some(threshold) = options.near_duplicate_threshold {
+        // Compare each node with the kept ones before it (start never merges either way).
+        let mut kept: Vec<(&String, HashSet<(char, char)>, Vec<&str>)> = Vec::new();
+        for node_id in keys.iter() {
+            if node_id == "start" || node_id == "n_start" || redirect.contains_key(node_id) {
+                continue;
+            }
+            let Some(node) = template.nodes.get(node_id) else {
+                continue;
+            };
 
-        let text = node.content.trim().to_string();
-        let mut cparts: Vec<String> = node
-            .choices
-            .iter()
-            .map(|c| format!("{}→{}", c.text.trim(), c.next_node_id.trim()))
-            .collect();
-        cparts.sort();
-        let signature = format!("{}||{}", text, cparts.join("|"));
+            let bigrams = char_bigrams(&node.content);
+            let mut targets: Vec<&str> =
+                node.choices.iter().map(|c| c.next_node_id.trim()).collect();
+            targets.sort();
 
-        if let Some(owner) = signature_owner.get(&signature) {
-            if owner != node_id {
-                redirect.insert(node_id.clone(), owner.clone());
+            let owner = kept.iter().find(|(_, other, other_targets)| {
+                *other_targets == targets && jaccard(&bigrams, other) >= threshold
+            });
+            match owner {
+                Some((owner_id, _, _)) => {
+                    redirect.insert(node_id.clone(), (*owner_id).clone());
+                }
+                None => kept.push((node_id, bigrams, targets)),
             }
-        } else {
-            signature_owner.insert(signature, node_id.clone());
         }
     }
 
@@ -514,9 +812,11 @@ pub(crate) fn sanitize_template_graph(template: &mut MovieTemplate) {
         state.insert(cur.to_string(), 2);
     }
 
-    for id in node_ids {
-        if *state.get(&id).unwrap_or(&0) == 0 {
-            dfs(&id, template, &mut state, &ending_neutral_key);
+    if options.break_cycles {
+        for id in node_ids {
+            if *state.get(&id).unwrap_or(&0) == 0 {
+                dfs(&id, template, &mut state, &ending_neutral_key);
+            }
         }
     }
 
@@ -524,34 +824,28 @@ pub(crate) fn sanitize_template_graph(template: &mut MovieTemplate) {
         template.endings.keys().map(|k| (k.clone(), ())).collect();
     let node_keys: HashMap<String, ()> = template.nodes.keys().map(|k| (k.clone(), ())).collect();
 
-    let ending_fallback = if ending_keys.contains_key(&ending_neutral_key) {
-        ending_neutral_key.clone()
-    } else {
-        template
-            .endings
-            .keys()
-            .next()
-            .cloned()
-            .unwrap_or_else(|| "END".to_string())
-    };
+    let ending_fallback = ending_neutral_key.clone();
 
     for node in template.nodes.values_mut() {
         for choice in node.choices.iter_mut() {
             let to = choice.next_node_id.trim();
-            if to.is_empty() {
+            // `ChoiceLite` defaults a missing target to "END", which is not a real
+            // ending key; route it to the fallback ending so playback can finish.
+            if to.is_empty() || to == "END" {
                 choice.next_node_id = ending_fallback.clone();
                 continue;
             }
 
-            if to == "END" {
+            if node_keys.contains_key(to) {
                 continue;
             }
 
-            if node_keys.contains_key(to) {
+            if ending_keys.contains_key(to) {
                 continue;
             }
 
-            if ending_keys.contains_key(to) {
+            if let Some(key) = ending_key_by_label(&template.endings, to) {
+                choice.next_node_id = key;
                 continue;
             }
 
@@ -559,6 +853,12 @@ pub(crate) fn sanitize_template_graph(template: &mut MovieTemplate) {
         }
     }
 
+    // Cycle breaking and target repair can send several choices to the same
+    // fallback ending, so repeats are only visible at this point.
+    for node in template.nodes.values_mut() {
+        dedup_node_choices(&mut node.choices, options.merge_same_target_choices);
+    }
+
     for node in template.nodes.values_mut() {
         if let Some(ending_key) = node.ending_key.as_ref() {
             if ending_keys.contains_key(ending_key) {
@@ -577,14 +877,1401 @@ pub(crate) fn sanitize_template_graph(template: &mut MovieTemplate) {
             .as_ref()
             .is_some_and(|k| ending_keys.contains_key(k));
 
-        if valid {
+        if !valid {
+            node.ending_key = Some(ending_neutral_key.clone());
+        }
+    }
+
+    if synthesize_fallback {
+        let used = template.nodes.values().any(|n| {
+            n.choices
+                .iter()
+                .any(|c| c.next_node_id == ending_neutral_key)
+                || (n.choices.is_empty() && n.ending_key.as_ref() == Some(&ending_neutral_key))
+        });
+        if used {
+            insert_neutral_ending(template);
+        }
+    }
+
+    classify_node_kinds(template);
+}
+
+/// Resolves a choice target that names an ending by its description (e.g.
+/// "悲惨结局") or its type (e.g. "bad") instead of its key. Descriptions win
+/// over types; ties go to the smallest key so the result is stable.
+fn ending_key_by_label(endings: &HashMap<String, types::Ending>, label: &str) -> Option<String> {
+    let pick = |matches: &dyn Fn(&types::Ending) -> bool| {
+        endings
+            .iter()
+            .filter(|(_, e)| matches(e))
+            .map(|(k, _)| k)
+            .min()
+            .cloned()
+    };
+    pick(&|e| e.description.trim() == label)
+        .or_else(|| pick(&|e| e.r#type.trim().eq_ignore_ascii_case(label)))
+}
+
+/// Sets `kind` on every node from its in-degree (distinct parent nodes), its
+/// distinct choice targets and its ending links. Precedence: start, terminal, convergence, branch, linear.
+/// Graph repair calls it, and so does every later pass that rewires choices
+/// (backward redirects, level cap, quick ending, orphan endings, padding)
+/// whenever it changes something, so callers never need to.
+pub(crate) fn classify_node_kinds(template: &mut MovieTemplate) {
+    let mut parents: HashMap<String, HashSet<String>> = HashMap::new();
+    for (key, node) in &template.nodes {
+        for choice in &node.choices {
+            if template.nodes.contains_key(&choice.next_node_id) {
+                parents
+                    .entry(choice.next_node_id.clone())
+                    .or_default()
+                    .insert(key.clone());
+            }
+        }
+    }
+
+    let node_keys: HashSet<String> = template.nodes.keys().cloned().collect();
+    for (key, node) in template.nodes.iter_mut() {
+        let targets: HashSet<&str> = node
+            .choices
+            .iter()
+            .map(|c| c.next_node_id.as_str())
+            .collect();
+        let leads_to_node = targets.iter().any(|to| node_keys.contains(*to));
+        let kind = if key == "start" || key == "n_start" {
+            types::NodeKind::Start
+        } else if node.ending_key.is_some() || !leads_to_node {
+            types::NodeKind::Terminal
+        } else if parents.get(key).map_or(0, HashSet::len) >= 2 {
+            types::NodeKind::Convergence
+        } else if targets.len() >= 2 {
+            types::NodeKind::Branch
+        } else {
+            types::NodeKind::Linear
+        };
+        node.kind = Some(kind);
+    }
+}
+
+/// Redirects choices that break the "only point at a larger number" rule
+/// between integer-keyed nodes (target <= source) to the neutral ending.
+/// Returns the rewritten `(source, original target)` pairs in key order.
+pub(crate) fn redirect_backward_choices(template: &mut MovieTemplate) -> Vec<(String, String)> {
+    let fallback = if template.endings.contains_key("ending_neutral") {
+        "ending_neutral".to_string()
+    } else {
+        match template.endings.keys().min() {
+            Some(k) => k.clone(),
+            None => return Vec::new(),
+        }
+    };
+
+    let mut keys: Vec<String> = template.nodes.keys().cloned().collect();
+    keys.sort_by(|a, b| map_key_order(a).cmp(&map_key_order(b)));
+
+    let mut rewritten = Vec::new();
+    for key in keys {
+        let Ok(from) = key.parse::<u64>() else {
+            continue;
+        };
+        let Some(node) = template.nodes.get_mut(&key) else {
+            continue;
+        };
+        for choice in node.choices.iter_mut() {
+            let backward = choice
+                .next_node_id
+                .trim()
+                .parse::<u64>()
+                .is_ok_and(|to| to <= from);
+            if backward {
+                rewritten.push((key.clone(), choice.next_node_id.clone()));
+                choice.next_node_id = fallback.clone();
+            }
+        }
+    }
+    if !rewritten.is_empty() {
+        classify_node_kinds(template);
+    }
+    rewritten
+}
+
+fn start_node_key(template: &MovieTemplate) -> Option<String> {
+    ["start", "n_start"]
+        .iter()
+        .find(|k| template.nodes.contains_key(**k))
+        .map(|k| k.to_string())
+}
+
+/// Counts structural problems without changing the template, so callers can
+/// compare a graph before and after repair.
+pub(crate) fn analyze_graph(template: &MovieTemplate) -> GraphMetrics {
+    let is_target = |to: &str| template.nodes.contains_key(to) || template.endings.contains_key(to);
+
+    let dangling_targets = template
+        .nodes
+        .values()
+        .flat_map(|n| n.choices.iter())
+        .filter(|c| !is_target(c.next_node_id.trim()))
+        .count();
+
+    let dead_ends = template
+        .nodes
+        .values()
+        .filter(|n| {
+            n.choices.is_empty()
+                && !n
+                    .ending_key
+                    .as_ref()
+                    .is_some_and(|k| template.endings.contains_key(k))
+        })
+        .count();
+
+    let mut reached: HashSet<&str> = HashSet::new();
+    if let Some(start) = ["start", "n_start"]
+        .into_iter()
+        .find(|k| template.nodes.contains_key(*k))
+    {
+        let mut queue = VecDeque::from([start]);
+        reached.insert(start);
+        while let Some(cur) = queue.pop_front() {
+            for choice in template.nodes[cur].choices.iter() {
+                let next = choice.next_node_id.trim();
+                if let Some((key, _)) = template.nodes.get_key_value(next) {
+                    if reached.insert(key.as_str()) {
+                        queue.push_back(key.as_str());
+                    }
+                }
+            }
+        }
+    }
+
+    // Iterative DFS in key order; 1 = on the stack, 2 = finished.
+    let mut keys: Vec<&String> = template.nodes.keys().collect();
+    keys.sort_by(|a, b| map_key_order(a).cmp(&map_key_order(b)));
+    let mut state: HashMap<&str, u8> = HashMap::new();
+    let mut cycles = 0;
+    for root in keys {
+        if state.contains_key(root.as_str()) {
             continue;
         }
+        state.insert(root.as_str(), 1);
+        let mut stack: Vec<(&str, usize)> = vec![(root.as_str(), 0)];
+        while let Some((cur, idx)) = stack.pop() {
+            let choices = &template.nodes[cur].choices;
+            let Some(choice) = choices.get(idx) else {
+                state.insert(cur, 2);
+                continue;
+            };
+            stack.push((cur, idx + 1));
+            let Some((next, _)) = template.nodes.get_key_value(choice.next_node_id.trim()) else {
+                continue;
+            };
+            match state.get(next.as_str()) {
+                Some(1) => cycles += 1,
+                Some(_) => {}
+                None => {
+                    state.insert(next.as_str(), 1);
+                    stack.push((next.as_str(), 0));
+                }
+            }
+        }
+    }
 
-        if ending_keys.contains_key(&ending_neutral_key) {
-            node.ending_key = Some(ending_neutral_key.clone());
+    GraphMetrics {
+        node_count: template.nodes.len(),
+        unreachable_nodes: template.nodes.len() - reached.len(),
+        dangling_targets,
+        cycles,
+        dead_ends,
+    }
+}
+
+/// Computes a 1-based depth for every node by BFS from the start node.
+/// Nodes that cannot be reached keep their stored `level` or are placed
+/// one row below the deepest reachable node.
+pub(crate) fn assign_levels(template: &MovieTemplate) -> HashMap<String, u32> {
+    let mut levels: HashMap<String, u32> = HashMap::new();
+
+    if let Some(start) = start_node_key(template) {
+        let mut queue = VecDeque::new();
+        levels.insert(start.clone(), 1);
+        queue.push_back(start);
+
+        while let Some(cur) = queue.pop_front() {
+            let level = levels[&cur];
+            let Some(node) = template.nodes.get(&cur) else {
+                continue;
+            };
+            for choice in node.choices.iter() {
+                let next = &choice.next_node_id;
+                if template.nodes.contains_key(next) && !levels.contains_key(next) {
+                    levels.insert(next.clone(), level + 1);
+                    queue.push_back(next.clone());
+                }
+            }
+        }
+    }
+
+    let fallback = levels.values().copied().max().unwrap_or(0) + 1;
+    for (key, node) in template.nodes.iter() {
+        if !levels.contains_key(key) {
+            levels.insert(key.clone(), node.level.unwrap_or(fallback));
+        }
+    }
+
+    levels
+}
+
+/// Groups nodes by level (row) and orders them within each level (column),
+/// so graph views can place nodes without re-deriving the structure.
+pub(crate) fn build_layout_hints(template: &MovieTemplate) -> Vec<NodeLayout> {
+    let levels = assign_levels(template);
+
+    let mut rows: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for (key, level) in levels.iter() {
+        rows.entry(*level).or_default().push(key.clone());
+    }
+
+    let mut out = Vec::with_capacity(levels.len());
+    for (level, mut keys) in rows {
+        keys.sort_by(|a, b| map_key_order(a).cmp(&map_key_order(b)));
+        for (column, key) in keys.iter().enumerate() {
+            out.push(NodeLayout {
+                id: key.clone(),
+                level,
+                column: column as u32,
+                siblings: keys.iter().filter(|k| *k != key).cloned().collect(),
+                x: column as u32,
+                y: level,
+            });
+        }
+    }
+
+    out
+}
+
+/// Relation of two characters linked only by sharing story nodes; templates
+/// carry no declared relationships.
+pub(crate) const CO_OCCURRENCE_RELATION: &str = "appears together";
+
+/// Cast graph of a template: every character, plus an edge for each pair
+/// listed together in at least one node, weighted by how many. Node lists
+/// may name a character by id or by name; names outside the cast still get
+/// a vertex so nothing the story mentions is dropped.
+pub(crate) fn build_relationship_graph(template: &MovieTemplate) -> RelationshipGraph {
+    let mut ids: HashMap<&str, &str> = HashMap::new();
+    for c in template.characters.values() {
+        ids.insert(c.name.trim(), c.id.as_str());
+        ids.insert(c.id.trim(), c.id.as_str());
+    }
+
+    let mut appearances: BTreeMap<String, usize> = BTreeMap::new();
+    let mut pairs: BTreeMap<(String, String), usize> = BTreeMap::new();
+    for node in template.nodes.values() {
+        let present: BTreeSet<String> = node
+            .characters
+            .iter()
+            .flatten()
+            .map(|raw| raw.trim())
+            .filter(|raw| !raw.is_empty())
+            .map(|raw| ids.get(raw).copied().unwrap_or(raw).to_string())
+            .collect();
+        let present: Vec<String> = present.into_iter().collect();
+        for (i, a) in present.iter().enumerate() {
+            *appearances.entry(a.clone()).or_default() += 1;
+            for b in &present[i + 1..] {
+                *pairs.entry((a.clone(), b.clone())).or_default() += 1;
+            }
+        }
+    }
+
+    let mut characters: Vec<CastMember> = template
+        .characters
+        .values()
+        .map(|c| CastMember {
+            id: c.id.clone(),
+            name: c.name.clone(),
+            role: c.role.clone(),
+            appearances: appearances.get(&c.id).copied().unwrap_or(0),
+        })
+        .collect();
+    for (id, count) in &appearances {
+        if !template.characters.values().any(|c| &c.id == id) {
+            characters.push(CastMember {
+                id: id.clone(),
+                name: id.clone(),
+                role: String::new(),
+                appearances: *count,
+            });
+        }
+    }
+    characters.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut edges: Vec<RelationshipEdge> = pairs
+        .into_iter()
+        .map(|((source, target), count)| RelationshipEdge {
+            source,
+            target,
+            relation: CO_OCCURRENCE_RELATION.to_string(),
+            count,
+        })
+        .collect();
+    edges.sort_by_key(|e| Reverse(e.count));
+
+    RelationshipGraph { characters, edges }
+}
+
+/// Renders a relationship graph as a Mermaid flowchart. Vertices get
+/// positional ids since character ids may hold characters Mermaid rejects.
+pub(crate) fn relationship_graph_mermaid(graph: &RelationshipGraph) -> String {
+    let label = |text: &str| text.replace('"', "#quot;");
+    let index: HashMap<&str, usize> = graph
+        .characters
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.id.as_str(), i))
+        .collect();
+
+    let mut out = String::from("graph LR\n");
+    for (i, c) in graph.characters.iter().enumerate() {
+        out.push_str(&format!("    c{}[\"{}\"]\n", i, label(&c.name)));
+    }
+    for edge in &graph.edges {
+        let (Some(source), Some(target)) = (
+            index.get(edge.source.as_str()),
+            index.get(edge.target.as_str()),
+        ) else {
+            continue;
+        };
+        out.push_str(&format!(
+            "    c{} ---|\"{} ×{}\"| c{}\n",
+            source,
+            label(&edge.relation),
+            edge.count,
+            target
+        ));
+    }
+    out
+}
+
+const DEFAULT_MAX_NODES_PER_LEVEL: usize = 5;
+
+/// Most nodes a level may hold, from `MAX_NODES_PER_LEVEL`. Defaults to 5,
+/// the width the prompt asks for.
+pub(crate) fn max_nodes_per_level() -> usize {
+    std::env::var("MAX_NODES_PER_LEVEL")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_NODES_PER_LEVEL)
+}
+
+fn node_reaches(template: &MovieTemplate, from: &str, to: &str) -> bool {
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut stack = vec![from];
+    while let Some(cur) = stack.pop() {
+        if cur == to {
+            return true;
+        }
+        if !seen.insert(cur) {
+            continue;
+        }
+        if let Some(node) = template.nodes.get(cur) {
+            stack.extend(node.choices.iter().map(|c| c.next_node_id.as_str()));
+        }
+    }
+    false
+}
+
+/// Folds node `from` into `into`: incoming choices are redirected (a parent
+/// left with several choices to `into` keeps the first), `from`'s content is
+/// appended and its choices to targets `into` doesn't already have are added.
+fn merge_node_into(template: &mut MovieTemplate, from: &str, into: &str) {
+    let Some(removed) = template.nodes.remove(from) else {
+        return;
+    };
+    for node in template.nodes.values_mut() {
+        let mut redirected = false;
+        for choice in node.choices.iter_mut() {
+            if choice.next_node_id == from {
+                choice.next_node_id = into.to_string();
+                redirected = true;
+            }
         }
+        if redirected {
+            dedup_node_choices(&mut node.choices, true);
+        }
+    }
+    if let Some(node) = template.nodes.get_mut(into) {
+        let content = removed.content.trim();
+        if !content.is_empty() && node.content.trim() != content {
+            node.content = format!("{}\n\n{}", node.content.trim_end(), content);
+        }
+        node.choices.retain(|c| c.next_node_id != into);
+        for choice in removed.choices {
+            if choice.next_node_id != into
+                && !node
+                    .choices
+                    .iter()
+                    .any(|c| c.next_node_id == choice.next_node_id)
+            {
+                node.choices.push(choice);
+            }
+        }
+    }
+}
+
+/// Keeps every level (see `assign_levels`) at or below `cap` nodes by
+/// merging the excess, in node order, into the first kept sibling that
+/// neither reaches nor is reached by it, so the graph stays acyclic and the
+/// merged node's successors stay reachable. Nodes carrying an `ending_key`
+/// are never merged or merged into, so a level of endings may stay over the
+/// cap. Runs after graph repair and leaves no repeated targets behind.
+/// Returns `(merged, into)` pairs.
+pub(crate) fn enforce_level_cap(template: &mut MovieTemplate, cap: usize) -> Vec<(String, String)> {
+    let mut merged = Vec::new();
+    if cap == 0 {
+        return merged;
     }
+    loop {
+        let mut rows: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+        for (key, level) in assign_levels(template) {
+            rows.entry(level).or_default().push(key);
+        }
+
+        let mut pair = None;
+        for (_, mut keys) in rows {
+            if keys.len() <= cap {
+                continue;
+            }
+            keys.sort_by(|a, b| map_key_order(a).cmp(&map_key_order(b)));
+            let (kept, excess) = keys.split_at(cap);
+            let story = |key: &String| {
+                template
+                    .nodes
+                    .get(key)
+                    .is_some_and(|n| n.ending_key.is_none())
+            };
+            pair = excess.iter().filter(|x| story(x)).find_map(|x| {
+                kept.iter()
+                    .filter(|k| story(k))
+                    .find(|k| !node_reaches(template, k, x) && !node_reaches(template, x, k))
+                    .map(|k| (x.clone(), k.clone()))
+            });
+            if pair.is_some() {
+                break;
+            }
+        }
+
+        // Levels shift after every merge, so recompute before the next one.
+        let Some((from, into)) = pair else {
+            break;
+        };
+        merge_node_into(template, &from, &into);
+        merged.push((from, into));
+    }
+    if !merged.is_empty() {
+        classify_node_kinds(template);
+    }
+    merged
+}
+
+pub(crate) const DEFAULT_QUICK_ENDING_LEVEL: u32 = 5;
+pub(crate) const MAX_QUICK_ENDING_LEVEL: u32 = 12;
+
+/// Guarantees at least one ending is reachable by a choice on a node at or
+/// above `max_level`. When none is, the deepest eligible node gets an extra
+/// choice leading to the neutral (or first) ending. Returns whether it changed.
+pub(crate) fn enforce_quick_ending(template: &mut MovieTemplate, max_level: u32) -> bool {
+    let target = if template.endings.contains_key("ending_neutral") {
+        "ending_neutral".to_string()
+    } else {
+        let mut keys: Vec<&String> = template.endings.keys().collect();
+        keys.sort();
+        match keys.first() {
+            Some(k) => (*k).clone(),
+            None => return false,
+        }
+    };
+
+    let levels = assign_levels(template);
+    let mut candidates: Vec<(u32, String)> = Vec::new();
+    for (key, node) in template.nodes.iter() {
+        let level = levels.get(key).copied().unwrap_or(u32::MAX);
+        if level > max_level || node.choices.is_empty() {
+            continue;
+        }
+        if node
+            .choices
+            .iter()
+            .any(|c| template.endings.contains_key(&c.next_node_id))
+        {
+            return false;
+        }
+        candidates.push((level, key.clone()));
+    }
+
+    // Deepest level first, then the stable node order.
+    candidates.sort_by(|a, b| {
+        b.0.cmp(&a.0)
+            .then_with(|| map_key_order(&a.1).cmp(&map_key_order(&b.1)))
+    });
+    let Some((_, key)) = candidates.into_iter().next() else {
+        return false;
+    };
+
+    let text = if template.meta.language.to_lowercase().starts_with("zh") {
+        "就此放手，结束这段故事"
+    } else {
+        "Let it go and end the story here"
+    };
+    if let Some(node) = template.nodes.get_mut(&key) {
+        node.choices.push(types::Choice {
+            text: text.to_string(),
+            next_node_id: target,
+            affinity_effect: None,
+        });
+    }
+    classify_node_kinds(template);
+    true
+}
+
+pub(crate) const DEFAULT_MIN_PATH_DEPTH: u32 = 12;
+pub(crate) const MAX_MIN_PATH_DEPTH: u32 = 30;
+
+/// An ending that a player can reach through fewer story nodes than the
+/// minimum path depth; `depth` counts nodes on the shortest route, `start`
+/// included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ShortPath {
+    pub(crate) ending: String,
+    pub(crate) depth: u32,
+}
+
+/// Shortest depth of every reachable node (`start` is 1), plus the shortest
+/// depth at which each ending is entered. Exits taken on nodes at or above
+/// `exempt_level` are the sanctioned quick endings and are not recorded.
+fn shortest_ending_depths(
+    template: &MovieTemplate,
+    exempt_level: u32,
+) -> (HashMap<String, u32>, BTreeMap<String, u32>) {
+    let mut depths: HashMap<String, u32> = HashMap::new();
+    let mut endings: BTreeMap<String, u32> = BTreeMap::new();
+    let Some(start) = start_node_key(template) else {
+        return (depths, endings);
+    };
+
+    let mut queue = VecDeque::new();
+    depths.insert(start.clone(), 1);
+    queue.push_back(start);
+    while let Some(cur) = queue.pop_front() {
+        let depth = depths[&cur];
+        let Some(node) = template.nodes.get(&cur) else {
+            continue;
+        };
+        let mut exits: Vec<&String> = Vec::new();
+        for choice in node.choices.iter() {
+            let next = &choice.next_node_id;
+            if template.nodes.contains_key(next) {
+                if !depths.contains_key(next) {
+                    depths.insert(next.clone(), depth + 1);
+                    queue.push_back(next.clone());
+                }
+            } else if template.endings.contains_key(next) {
+                exits.push(next);
+            }
+        }
+        if node.choices.is_empty() {
+            exits.extend(
+                node.ending_key
+                    .as_ref()
+                    .filter(|k| template.endings.contains_key(*k)),
+            );
+        }
+        if depth > exempt_level {
+            for ending in exits {
+                let best = endings.entry(ending.clone()).or_insert(depth);
+                *best = (*best).min(depth);
+            }
+        }
+    }
+    (depths, endings)
+}
+
+/// Endings reachable through fewer than `min_depth` nodes, ordered by key.
+/// Endings entered from nodes at or above `exempt_level` (the quick ending)
+/// only count through their deeper entries.
+pub(crate) fn find_short_paths(
+    template: &MovieTemplate,
+    min_depth: u32,
+    exempt_level: u32,
+) -> Vec<ShortPath> {
+    shortest_ending_depths(template, exempt_level)
+        .1
+        .into_iter()
+        .filter(|(_, depth)| *depth < min_depth)
+        .map(|(ending, depth)| ShortPath { ending, depth })
+        .collect()
+}
+
+/// Lengthens every route found by `find_short_paths` to `min_depth` nodes:
+/// each short ending gets one chain of connector nodes in front of it, and
+/// each too-shallow exit into that ending is moved onto the chain link that
+/// leaves exactly enough nodes before the ending. Returns the padded paths.
+pub(crate) fn pad_short_paths(
+    template: &mut MovieTemplate,
+    min_depth: u32,
+    exempt_level: u32,
+) -> Vec<ShortPath> {
+    let short = find_short_paths(template, min_depth, exempt_level);
+    if short.is_empty() {
+        return short;
+    }
+    let (depths, _) = shortest_ending_depths(template, exempt_level);
+
+    let is_zh = template.meta.language.is_empty()
+        || template.meta.language.to_lowercase().starts_with("zh");
+    let (content, text) = if is_zh {
+        ("时间一点点过去，我隐约感到一切正走向终点。", "继续")
+    } else {
+        (
+            "Time slips by, and I sense it is all heading toward an end.",
+            "Continue",
+        )
+    };
+
+    for path in short.iter() {
+        let links = (min_depth - path.depth) as usize;
+        let mut chain: Vec<String> = Vec::with_capacity(links);
+        for _ in 0..links {
+            let key = next_free_node_key(template);
+            template.nodes.insert(
+                key.clone(),
+                types::StoryNode {
+                    id: key.clone(),
+                    content: content.to_string(),
+                    ending_key: None,
+                    level: None,
+                    characters: None,
+                    choices: Vec::new(),
+                    kind: None,
+                    background_image_base64: None,
+                    background_alt: None,
+                },
+            );
+            chain.push(key);
+        }
+        for (i, key) in chain.iter().enumerate() {
+            let next = chain.get(i + 1).unwrap_or(&path.ending).clone();
+            if let Some(node) = template.nodes.get_mut(key) {
+                node.choices.push(types::Choice {
+                    text: text.to_string(),
+                    next_node_id: next,
+                    affinity_effect: None,
+                });
+            }
+        }
+
+        // An exit at depth d enters the link that still has `min_depth - d`
+        // connector nodes ahead of the ending.
+        for (key, &depth) in depths.iter() {
+            if depth <= exempt_level || depth >= min_depth {
+                continue;
+            }
+            let Some(node) = template.nodes.get_mut(key) else {
+                continue;
+            };
+            let ends_here =
+                node.choices.is_empty() && node.ending_key.as_deref() == Some(path.ending.as_str());
+            if !ends_here && !node.choices.iter().any(|c| c.next_node_id == path.ending) {
+                continue;
+            }
+            let entry = chain[(depth - path.depth) as usize].clone();
+            if ends_here {
+                node.ending_key = None;
+                node.choices.push(types::Choice {
+                    text: text.to_string(),
+                    next_node_id: entry,
+                    affinity_effect: None,
+                });
+                continue;
+            }
+            for choice in node.choices.iter_mut() {
+                if choice.next_node_id == path.ending {
+                    choice.next_node_id = entry.clone();
+                }
+            }
+        }
+    }
+    classify_node_kinds(template);
+    short
+}
+
+/// What `reconcile_orphan_endings` does with an ending that no route from
+/// `start` reaches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OrphanEndingPolicy {
+    /// Point a choice of the shallowest suitable node at it.
+    Link,
+    /// Remove it from `endings`.
+    Prune,
+    /// Leave the template as it is and only report it.
+    Report,
+}
+
+impl OrphanEndingPolicy {
+    /// `link` (the default when omitted), `prune` or `report`, case-insensitive.
+    pub(crate) fn parse(value: Option<&str>) -> Option<Self> {
+        match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("link") => Some(Self::Link),
+            Some("prune") => Some(Self::Prune),
+            Some("report") => Some(Self::Report),
+            Some(_) => None,
+        }
+    }
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Link => "link",
+            Self::Prune => "prune",
+            Self::Report => "report",
+        }
+    }
+}
+
+/// What happened to an orphaned ending.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum OrphanAction {
+    /// A choice of this node now leads to it.
+    Linked(String),
+    Pruned,
+    Reported,
+}
+
+/// An ending that was unreachable from `start`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OrphanEnding {
+    pub(crate) ending: String,
+    pub(crate) action: OrphanAction,
+}
+
+/// Makes `endings` match what play can actually reach. With `Link`, each
+/// orphaned ending takes over a spare choice (one leading straight to an
+/// ending that stays reachable through another entry) of the shallowest node
+/// that has one; failing that, a node gets an extra choice (see
+/// `link_orphan_ending`). The start node is never rewired, so linking never
+/// adds a one-step route to an ending. Orphans that cannot be linked, and
+/// every orphan under `Prune`, are removed, and leftover references to them
+/// (only on unreachable nodes) are moved to a reachable ending. `Report`
+/// changes nothing. Endings in `synthesized` were added by the pipeline
+/// itself and are skipped. Templates where no ending is reachable are left
+/// alone. Returns the orphans by key.
+pub(crate) fn reconcile_orphan_endings(
+    template: &mut MovieTemplate,
+    policy: OrphanEndingPolicy,
+    synthesized: &HashSet<String>,
+) -> Vec<OrphanEnding> {
+    let (depths, reachable) = shortest_ending_depths(template, 0);
+    let mut orphans: Vec<String> = template
+        .endings
+        .keys()
+        .filter(|k| !reachable.contains_key(*k) && !synthesized.contains(*k))
+        .cloned()
+        .collect();
+    if reachable.is_empty() || orphans.is_empty() {
+        return Vec::new();
+    }
+    orphans.sort();
+    if policy == OrphanEndingPolicy::Report {
+        return orphans
+            .into_iter()
+            .map(|ending| OrphanEnding {
+                ending,
+                action: OrphanAction::Reported,
+            })
+            .collect();
+    }
+
+    let mut nodes: Vec<&String> = depths.keys().collect();
+    nodes.sort_by(|a, b| (depths[*a], map_key_order(a)).cmp(&(depths[*b], map_key_order(b))));
+    let nodes: Vec<String> = nodes.into_iter().cloned().collect();
+
+    // Entries into each reachable ending: direct choices plus terminal nodes.
+    let mut entries: HashMap<String, usize> = HashMap::new();
+    for key in nodes.iter() {
+        let node = &template.nodes[key];
+        for choice in node.choices.iter() {
+            if template.endings.contains_key(&choice.next_node_id) {
+                *entries.entry(choice.next_node_id.clone()).or_default() += 1;
+            }
+        }
+        if node.choices.is_empty() {
+            if let Some(k) = node.ending_key.as_ref() {
+                *entries.entry(k.clone()).or_default() += 1;
+            }
+        }
+    }
+
+    let is_zh = template.meta.language.is_empty()
+        || template.meta.language.to_lowercase().starts_with("zh");
+    let mut result = Vec::new();
+    for ending in orphans {
+        let linked_from = match policy {
+            OrphanEndingPolicy::Link => {
+                link_orphan_ending(template, &nodes, &mut entries, &ending, is_zh)
+            }
+            OrphanEndingPolicy::Prune | OrphanEndingPolicy::Report => None,
+        };
+        let action = match linked_from {
+            Some(node) => OrphanAction::Linked(node),
+            None => {
+                template.endings.remove(&ending);
+                OrphanAction::Pruned
+            }
+        };
+        result.push(OrphanEnding { ending, action });
+    }
+
+    let pruned: HashSet<&String> = result
+        .iter()
+        .filter(|o| o.action == OrphanAction::Pruned)
+        .map(|o| &o.ending)
+        .collect();
+    if !pruned.is_empty() {
+        let fallback = ["ending_neutral", "ending_bad", "ending_good"]
+            .iter()
+            .map(|k| k.to_string())
+            .find(|k| reachable.contains_key(k))
+            .or_else(|| reachable.keys().next().cloned())
+            .unwrap_or_default();
+        for node in template.nodes.values_mut() {
+            for choice in node.choices.iter_mut() {
+                if pruned.contains(&choice.next_node_id) {
+                    choice.next_node_id = fallback.clone();
+                }
+            }
+            if let Some(k) = node.ending_key.as_ref() {
+                if pruned.contains(k) {
+                    node.ending_key = Some(fallback.clone());
+                }
+            }
+        }
+    }
+    classify_node_kinds(template);
+    result
+}
+
+/// Wires `ending` into the graph for `reconcile_orphan_endings`; `nodes` are
+/// the reachable nodes, shallowest first. Without a spare choice, the extra
+/// choice goes on the shallowest node that already exits straight to an
+/// ending, so no exit gets shallower, else on the deepest node with choices.
+/// Returns the node that now leads to it.
+fn link_orphan_ending(
+    template: &mut MovieTemplate,
+    nodes: &[String],
+    entries: &mut HashMap<String, usize>,
+    ending: &str,
+    is_zh: bool,
+) -> Option<String> {
+    let start = start_node_key(template);
+    let nodes: Vec<&String> = nodes
+        .iter()
+        .filter(|k| Some(*k) != start.as_ref())
+        .collect();
+    for key in nodes.iter() {
+        let Some(node) = template.nodes.get_mut(*key) else {
+            continue;
+        };
+        let spare = node
+            .choices
+            .iter_mut()
+            .find(|c| entries.get(&c.next_node_id).is_some_and(|n| *n > 1));
+        if let Some(choice) = spare {
+            if let Some(n) = entries.get_mut(&choice.next_node_id) {
+                *n -= 1;
+            }
+            choice.next_node_id = ending.to_string();
+            entries.insert(ending.to_string(), 1);
+            return Some((*key).clone());
+        }
+    }
+
+    let exits = |key: &String| {
+        template.nodes[key]
+            .choices
+            .iter()
+            .any(|c| template.endings.contains_key(&c.next_node_id))
+    };
+    let key = nodes
+        .iter()
+        .find(|k| exits(k))
+        .or_else(|| {
+            nodes
+                .iter()
+                .rev()
+                .find(|k| !template.nodes[**k].choices.is_empty())
+        })
+        .map(|k| (*k).clone())?;
+    let text = if is_zh {
+        "另作打算"
+    } else {
+        "Try another way"
+    };
+    let node = template.nodes.get_mut(&key)?;
+    node.choices.push(types::Choice {
+        text: text.to_string(),
+        next_node_id: ending.to_string(),
+        affinity_effect: None,
+    });
+    entries.insert(ending.to_string(), 1);
+    Some(key)
+}
+
+/// Rewrites node keys to ascending integers in topological order (`start`
+/// keeps its key), so every choice points at a larger number as the prompt
+/// contract requires. Returns the old-to-new key mapping for every node.
+pub(crate) fn renumber_nodes_topologically(
+    template: &mut MovieTemplate,
+) -> HashMap<String, String> {
+    let mut indegree: HashMap<String, usize> =
+        template.nodes.keys().map(|k| (k.clone(), 0)).collect();
+    for (key, node) in template.nodes.iter() {
+        for choice in node.choices.iter() {
+            if choice.next_node_id != *key {
+                if let Some(d) = indegree.get_mut(&choice.next_node_id) {
+                    *d += 1;
+                }
+            }
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<(u8, u64, &str)>> = template
+        .nodes
+        .keys()
+        .filter(|k| indegree[*k] == 0)
+        .map(|k| Reverse(map_key_order(k)))
+        .collect();
+    let mut order: Vec<String> = Vec::with_capacity(template.nodes.len());
+
+    while let Some(Reverse((_, _, key))) = ready.pop() {
+        if let Some(node) = template.nodes.get(key) {
+            for choice in node.choices.iter() {
+                if choice.next_node_id == key {
+                    continue;
+                }
+                if let Some(d) = indegree.get_mut(&choice.next_node_id) {
+                    *d -= 1;
+                    if *d == 0 {
+                        ready.push(Reverse(map_key_order(&choice.next_node_id)));
+                    }
+                }
+            }
+        }
+        order.push(key.to_string());
+    }
+
+    // Nodes left on a cycle keep a deterministic position after the rest.
+    if order.len() < template.nodes.len() {
+        let mut rest: Vec<String> = template
+            .nodes
+            .keys()
+            .filter(|k| !order.contains(k))
+            .cloned()
+            .collect();
+        rest.sort_by(|a, b| map_key_order(a).cmp(&map_key_order(b)));
+        order.extend(rest);
+    }
+
+    let start = start_node_key(template);
+    if let Some(start) = start.as_ref() {
+        order.retain(|k| k != start);
+    }
+
+    let mut mapping: HashMap<String, String> = HashMap::new();
+    if let Some(start) = start {
+        mapping.insert(start, "start".to_string());
+    }
+    for (i, key) in order.into_iter().enumerate() {
+        mapping.insert(key, (i + 1).to_string());
+    }
+
+    let old_nodes = std::mem::take(&mut template.nodes);
+    for (old_key, mut node) in old_nodes {
+        let new_key = mapping[&old_key].clone();
+        node.id = new_key.clone();
+        for c in node.choices.iter_mut() {
+            if let Some(mapped) = mapping.get(&c.next_node_id) {
+                c.next_node_id = mapped.clone();
+            }
+        }
+        template.nodes.insert(new_key, node);
+    }
+
+    mapping
+}
+
+/// Stores sanitized request genres on the template, overwriting the joined
+/// `meta.genre` so both forms agree. No-op when `tags` is empty.
+pub(crate) fn apply_genre_tags(template: &mut MovieTemplate, tags: Vec<String>) {
+    if tags.is_empty() {
+        return;
+    }
+    template.meta.genre = tags.join(", ");
+    template.genre_tags = tags;
+}
+
+pub(crate) const MIN_SPLIT_PARTS: usize = 2;
+pub(crate) const MAX_SPLIT_PARTS: usize = 5;
+
+/// One beat of a split node as returned by GLM. `choice` is the text of the
+/// single "continue" choice leading to the next beat; the last beat's is unused.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SplitBeat {
+    pub(crate) content: String,
+    #[serde(default)]
+    pub(crate) choice: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SplitBeatsLite {
+    beats: Vec<SplitBeat>,
+}
+
+/// Parses the `{ "beats": [...] }` payload GLM returns for a node split and
+/// checks it has exactly `parts` non-empty beats.
+pub(crate) fn parse_split_beats(raw: &str, parts: usize) -> Result<Vec<SplitBeat>, String> {
+    let cleaned = crate::prompt::clean_json(raw);
+    let parsed: SplitBeatsLite = serde_json::from_str(&cleaned)
+        .or_else(|_| {
+            serde_json::from_str::<Vec<SplitBeat>>(&cleaned).map(|beats| SplitBeatsLite { beats })
+        })
+        .map_err(|e| format!("Failed to parse split beats: {}", e))?;
+
+    if parsed.beats.len() != parts {
+        return Err(format!(
+            "Expected {} beats, got {}",
+            parts,
+            parsed.beats.len()
+        ));
+    }
+    if parsed.beats.iter().any(|b| b.content.trim().is_empty()) {
+        return Err("Split beat content is empty".to_string());
+    }
+    Ok(parsed.beats)
+}
+
+/// Next unused numeric node key (one past the largest numeric key, skipping
+/// anything already taken).
+fn next_free_node_key(template: &MovieTemplate) -> String {
+    let mut n = template
+        .nodes
+        .keys()
+        .filter_map(|k| k.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0)
+        + 1;
+    while template.nodes.contains_key(&n.to_string())
+        || template.endings.contains_key(&n.to_string())
+    {
+        n += 1;
+    }
+    n.to_string()
+}
+
+pub(crate) const MAX_CONTINUE_NODES: usize = 3;
+
+/// GLM payload for `/generate/continue`: the text of the new choice on the
+/// source node plus the nodes it leads to, in order.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Continuation {
+    pub(crate) choice_text: String,
+    pub(crate) nodes: Vec<SplitBeat>,
+}
+
+pub(crate) fn parse_continuation(raw: &str, max_nodes: usize) -> Result<Continuation, String> {
+    let cleaned = crate::prompt::clean_json(raw);
+    let mut parsed: Continuation = serde_json::from_str(&cleaned)
+        .map_err(|e| format!("Failed to parse continuation: {}", e))?;
+
+    parsed.nodes.retain(|n| !n.content.trim().is_empty());
+    if parsed.nodes.is_empty() {
+        return Err("Continuation has no nodes".to_string());
+    }
+    parsed.nodes.truncate(max_nodes);
+    Ok(parsed)
+}
+
+/// Appends the continuation after `from_node_id`: a new choice on the source
+/// node leads to the first new node, and the new nodes are chained linearly.
+/// The last node is left open (no choices) for the author to continue from.
+/// Returns the new keys in order.
+pub(crate) fn splice_continuation(
+    template: &mut MovieTemplate,
+    from_node_id: &str,
+    continuation: Continuation,
+) -> Result<Vec<String>, String> {
+    if continuation.nodes.is_empty() {
+        return Err("Continuation has no nodes".to_string());
+    }
+    let Some(from_level) = template.nodes.get(from_node_id).map(|n| n.level) else {
+        return Err(format!("Node {} not found", from_node_id));
+    };
+
+    let mut keys: Vec<String> = Vec::new();
+    for _ in 0..continuation.nodes.len() {
+        let key = next_free_node_key(template);
+        // Reserve the key so the next lookup skips it; filled in below.
+        template.nodes.insert(
+            key.clone(),
+            types::StoryNode {
+                id: key.clone(),
+                content: String::new(),
+                ending_key: None,
+                level: None,
+                characters: None,
+                choices: Vec::new(),
+                kind: None,
+                background_image_base64: None,
+                background_alt: None,
+            },
+        );
+        keys.push(key);
+    }
+
+    for (i, beat) in continuation.nodes.iter().enumerate() {
+        let choices = match keys.get(i + 1) {
+            Some(next) => vec![types::Choice {
+                text: beat
+                    .choice
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .unwrap_or("继续")
+                    .to_string(),
+                next_node_id: next.clone(),
+                affinity_effect: None,
+            }],
+            None => Vec::new(),
+        };
+        if let Some(node) = template.nodes.get_mut(&keys[i]) {
+            node.content = beat.content.trim().to_string();
+            node.level = from_level.map(|l| l + 1 + i as u32);
+            node.choices = choices;
+        }
+    }
+
+    let text = continuation.choice_text.trim();
+    if let Some(from) = template.nodes.get_mut(from_node_id) {
+        // The source node is no longer terminal once it has a way forward.
+        from.ending_key = None;
+        from.choices.push(types::Choice {
+            text: if text.is_empty() {
+                "继续".to_string()
+            } else {
+                text.to_string()
+            },
+            next_node_id: keys[0].clone(),
+            affinity_effect: None,
+        });
+    }
+
+    Ok(keys)
+}
+
+/// Replaces `node_id` with a linear chain of `beats`. The first beat keeps the
+/// original key, so incoming edges (and `start`) already point at it; the
+/// remaining beats get fresh keys and the last one inherits the original
+/// choices and `ending_key`. Returns the chain's keys in order.
+pub(crate) fn split_node(
+    template: &mut MovieTemplate,
+    node_id: &str,
+    beats: Vec<SplitBeat>,
+) -> Result<Vec<String>, String> {
+    if beats.is_empty() {
+        return Err("No beats to split into".to_string());
+    }
+    let Some(original) = template.nodes.get(node_id).cloned() else {
+        return Err(format!("Node {} not found", node_id));
+    };
+
+    let mut keys = vec![node_id.to_string()];
+    for _ in 1..beats.len() {
+        let key = next_free_node_key(template);
+        // Reserve the key so the next lookup skips it.
+        template.nodes.insert(key.clone(), original.clone());
+        keys.push(key);
+    }
+
+    let last = beats.len() - 1;
+    for (i, beat) in beats.into_iter().enumerate() {
+        let key = &keys[i];
+        let (choices, ending_key) = if i == last {
+            (original.choices.clone(), original.ending_key.clone())
+        } else {
+            let text = beat
+                .choice
+                .as_deref()
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .unwrap_or("继续")
+                .to_string();
+            (
+                vec![types::Choice {
+                    text,
+                    next_node_id: keys[i + 1].clone(),
+                    affinity_effect: None,
+                }],
+                None,
+            )
+        };
+
+        template.nodes.insert(
+            key.clone(),
+            types::StoryNode {
+                id: key.clone(),
+                content: beat.content.trim().to_string(),
+                ending_key,
+                level: original.level.map(|l| l + i as u32),
+                characters: original.characters.clone(),
+                choices,
+                kind: None,
+                background_image_base64: None,
+                background_alt: None,
+            },
+        );
+    }
+
+    Ok(keys)
+}
+
+fn delimiter_run(chars: &[char], at: usize, c: char) -> usize {
+    chars[at..].iter().take_while(|&&x| x == c).count()
+}
+
+/// True at a line edge or next to anything that is not part of a word. The
+/// masker swaps sensitive words for `*` inside running text, so a delimiter
+/// glued to a word on its outer side is treated as a mask, not emphasis.
+fn is_word_boundary(c: Option<&char>) -> bool {
+    c.is_none_or(|x| !x.is_alphanumeric() && *x != '*' && *x != '_')
+}
+
+fn find_closing_delimiter(chars: &[char], from: usize, c: char, run: usize) -> Option<usize> {
+    match chars.get(from) {
+        Some(x) if !x.is_whitespace() => {}
+        _ => return None,
+    }
+    let mut j = from;
+    while j < chars.len() {
+        if chars[j] == c {
+            let r = delimiter_run(chars, j, c);
+            if r == run && !chars[j - 1].is_whitespace() && is_word_boundary(chars.get(j + r)) {
+                return Some(j);
+            }
+            j += r;
+            continue;
+        }
+        j += 1;
+    }
+    None
+}
+
+fn strip_emphasis(line: &str) -> String {
+    let chars: Vec<char> = line.chars().collect();
+    let mut out = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c != '*' && c != '_' {
+            out.push(c);
+            i += 1;
+            continue;
+        }
+
+        // Only `*x*`, `**x**` and `__x__` count as emphasis, and only when the
+        // pair sits between word boundaries. Longer runs such as the masker's
+        // `***`, mid-word `*` runs and single underscores are kept verbatim.
+        let run = delimiter_run(&chars, i, c);
+        let is_emphasis = (if c == '*' { run <= 2 } else { run == 2 })
+            && is_word_boundary(i.checked_sub(1).and_then(|k| chars.get(k)));
+        if is_emphasis {
+            if let Some(end) = find_closing_delimiter(&chars, i + run, c, run) {
+                out.extend(&chars[i + run..end]);
+                i = end + run;
+                continue;
+            }
+        }
+        out.extend(std::iter::repeat_n(c, run));
+        i += run;
+    }
+    out
+}
+
+/// Removes Markdown headings and emphasis markers while keeping the text.
+pub(crate) fn strip_markdown(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            let trimmed = line.trim_start();
+            let hashes = trimmed.chars().take_while(|&c| c == '#').count();
+            let line = if (1..=6).contains(&hashes) && trimmed[hashes..].starts_with(' ') {
+                trimmed[hashes..].trim_start()
+            } else {
+                line
+            };
+            strip_emphasis(line)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Applies [`strip_markdown`] to node contents and ending descriptions. The
+/// text may already carry sensitive-word masks (e.g. when rebuilding from
+/// logged responses), so mask-like `*` runs are left as they are.
+pub(crate) fn strip_template_markdown(template: &mut MovieTemplate) {
+    for node in template.nodes.values_mut() {
+        node.content = strip_markdown(&node.content);
+    }
+    for ending in template.endings.values_mut() {
+        ending.description = strip_markdown(&ending.description);
+    }
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+pub(crate) fn format_rfc3339(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+
+    // Civil-from-days (Howard Hinnant), valid for any date after 1970.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+/// Stamps `provenance.updated_at` with the current time after an edit.
+pub(crate) fn touch_provenance(template: &mut MovieTemplate) {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    template.provenance.updated_at = format_rfc3339(secs);
 }
 
 pub(crate) fn sanitize_affinity_effects(template: &mut MovieTemplate) {
@@ -649,14 +2336,18 @@ pub(crate) fn sanitize_affinity_effects(template: &mut MovieTemplate) {
     }
 }
 
-fn pick_protagonist_name(chars: &HashMap<String, types::Character>) -> Option<String> {
+/// Best guess at the protagonist's name from keys, roles and names; ties go
+/// to the smallest key so the pick is stable.
+pub(crate) fn pick_protagonist_name(chars: &HashMap<String, types::Character>) -> Option<String> {
     if chars.is_empty() {
         return None;
     }
 
     let mut best: Option<(i32, String)> = None;
 
-    for (k, c) in chars.iter() {
+    let mut entries: Vec<(&String, &types::Character)> = chars.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    for (k, c) in entries {
         let key = k.to_lowercase();
         let name = c.name.trim();
         if name.is_empty() {
@@ -670,7 +2361,11 @@ fn pick_protagonist_name(chars: &HashMap<String, types::Character>) -> Option<St
         if key.contains("player") || key.contains("protagonist") || key.contains("main") {
             score += 5;
         }
-        if role.contains("protagonist") || role.contains("player") || role.contains("main") {
+        if role.contains("protagonist")
+            || role.contains("player")
+            || role.contains("main")
+            || role.contains("主角")
+        {
             score += 6;
         }
         if name == "我" || name.contains("主角") {
@@ -720,6 +2415,8 @@ pub(crate) fn enforce_character_consistency(
                 role: input_char.description,
                 background: String::new(),
                 avatar_path: None,
+                voice: input_char.voice,
+                voice_description: input_char.voice_description,
             },
         );
     }
@@ -812,6 +2509,8 @@ pub(crate) fn ensure_minimum_game_graph(
                 role: "员工".to_string(),
                 background: "下班时被突然的消息绊住。".to_string(),
                 avatar_path: None,
+                voice: None,
+                voice_description: None,
             });
 
         // Use "start" as user requested, not "n_start"
@@ -835,6 +2534,9 @@ pub(crate) fn ensure_minimum_game_graph(
                         affinity_effect: None,
                     },
                 ],
+                kind: None,
+                background_image_base64: None,
+                background_alt: None,
             },
         );
 
@@ -858,6 +2560,9 @@ pub(crate) fn ensure_minimum_game_graph(
                         affinity_effect: None,
                     },
                 ],
+                kind: None,
+                background_image_base64: None,
+                background_alt: None,
             },
         );
 
@@ -876,6 +2581,9 @@ pub(crate) fn ensure_minimum_game_graph(
                         affinity_effect: None,
                     },
                 ],
+                kind: None,
+                background_image_base64: None,
+                background_alt: None,
             },
         );
     }
@@ -35,4 +35,27 @@ mod tests {
         assert!(!cleaned.contains("坏蛋"));
         println!("Cleaned: {}", cleaned);
     }
+
+    #[test]
+    fn test_missing_default_dict_falls_back_to_configured_words() {
+        let filter = SensitiveFilter::with_default_dict(None, &["坏蛋".to_string()]);
+        let (cleaned, count) = filter.sanitize_str("你是个坏蛋吗");
+        assert_eq!(count, 1);
+        assert!(!cleaned.contains("坏蛋"));
+
+        let empty = SensitiveFilter::with_default_dict(None, &[]);
+        assert_eq!(empty.sanitize_str("你好").1, 0);
+    }
+
+    #[test]
+    fn test_masking_preserves_char_length() {
+        use crate::prompt::count_display_chars;
+
+        let filter = SensitiveFilter::from_words(&["大坏蛋".to_string()]);
+        let text = "他是个大坏蛋，真的";
+        let (cleaned, count) = filter.sanitize_str(text);
+        assert_eq!(count, 1);
+        assert_eq!(cleaned, "他是个***，真的");
+        assert_eq!(count_display_chars(&cleaned), count_display_chars(text));
+    }
 }
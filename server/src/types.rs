@@ -1,7 +1,8 @@
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
 
-fn deserialize_string_or_vec<'de, D>(deserializer: D) -> Result<String, D::Error>
+fn deserialize_string_or_vec_joined<'de, D>(deserializer: D, sep: &str) -> Result<String, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -14,15 +15,24 @@ where
 
     match StringOrVec::deserialize(deserializer)? {
         StringOrVec::String(s) => Ok(s),
-        StringOrVec::Vec(v) => Ok(v.join("\n")),
+        StringOrVec::Vec(v) => Ok(v.join(sep)),
     }
 }
 
+/// Prose fields (logline, synopsis): array items become lines.
+fn deserialize_string_or_vec<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_string_or_vec_joined(deserializer, "\n")
+}
+
+/// `genre`: array items become a comma-separated list, e.g. "Sci-Fi, Drama".
 fn deserialize_string_or_vec_to_string<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
     D: Deserializer<'de>,
 {
-    deserialize_string_or_vec(deserializer)
+    deserialize_string_or_vec_joined(deserializer, ", ")
 }
 
 fn deserialize_option_vec_or_string<'de, D>(
@@ -46,6 +56,16 @@ where
     }
 }
 
+/// `kind` is computed server-side, so an unrecognized value sent back by a
+/// client is dropped rather than failing the whole template.
+fn deserialize_node_kind_lenient<'de, D>(deserializer: D) -> Result<Option<NodeKind>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let v = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(v.and_then(|v| serde_json::from_value(v).ok()))
+}
+
 fn deserialize_characters<'de, D>(deserializer: D) -> Result<HashMap<String, Character>, D::Error>
 where
     D: Deserializer<'de>,
@@ -69,13 +89,51 @@ where
                 } else {
                     format!("char_{}", m.len())
                 };
-                m.insert(key, c);
+                match m.get_mut(&key) {
+                    Some(existing) => Character::merge_duplicate(existing, c),
+                    None => {
+                        m.insert(key, c);
+                    }
+                }
             }
             Ok(m)
         }
     }
 }
 
+pub(crate) fn map_key_order(key: &str) -> (u8, u64, &str) {
+    if key == "start" || key == "n_start" {
+        return (0, 0, key);
+    }
+    match key.parse::<u64>() {
+        Ok(n) => (1, n, key),
+        Err(_) => (2, 0, key),
+    }
+}
+
+/// Drains `map` into entries in the same order `serialize_sorted_map` uses.
+pub(crate) fn sorted_entries<V>(map: HashMap<String, V>) -> Vec<(String, V)> {
+    let mut entries: Vec<(String, V)> = map.into_iter().collect();
+    entries.sort_by(|a, b| map_key_order(&a.0).cmp(&map_key_order(&b.0)));
+    entries
+}
+
+// HashMap 序列化顺序不稳定：按 start → 数字 key（数值序）→ 其他 key（字典序）输出
+fn serialize_sorted_map<S, V>(map: &HashMap<String, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize,
+{
+    let mut entries: Vec<(&String, &V)> = map.iter().collect();
+    entries.sort_by(|a, b| map_key_order(a.0).cmp(&map_key_order(b.0)));
+
+    let mut out = serializer.serialize_map(Some(entries.len()))?;
+    for (k, v) in entries {
+        out.serialize_entry(k, v)?;
+    }
+    out.end()
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct MovieTemplate {
@@ -84,18 +142,62 @@ pub struct MovieTemplate {
     pub version: String,
     pub owner: String,
     pub meta: MetaInfo,
+    /// Genre tags from the request, kept as an array; `meta.genre` is their
+    /// `, `-joined form for older clients.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub genre_tags: Vec<String>,
     #[serde(default)]
     pub background_image_base64: Option<String>,
-    #[serde(default)]
+    #[serde(default, serialize_with = "serialize_sorted_map")]
     pub nodes: HashMap<String, StoryNode>,
-    #[serde(default)]
+    #[serde(default, serialize_with = "serialize_sorted_map")]
     pub endings: HashMap<String, Ending>,
-    #[serde(default, deserialize_with = "deserialize_characters")]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_characters",
+        serialize_with = "serialize_sorted_map"
+    )]
     pub characters: HashMap<String, Character>,
     #[serde(default)]
     pub provenance: Provenance,
 }
 
+/// Player-facing shape served by `/export/json/:id`: the fields of the
+/// documented TypeScript interface plus the cast, without storage fields
+/// such as `projectId`, `version`, `owner` and `provenance`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedTemplate {
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background_image_base64: Option<String>,
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub nodes: HashMap<String, StoryNode>,
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub characters: HashMap<String, Character>,
+    #[serde(serialize_with = "serialize_sorted_map")]
+    pub endings: HashMap<String, Ending>,
+    /// `endings` in presentation order; only filled when requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ordered_endings: Option<Vec<KeyedEnding>>,
+}
+
+impl From<&MovieTemplate> for ExportedTemplate {
+    fn from(t: &MovieTemplate) -> Self {
+        Self {
+            title: t.title.clone(),
+            background_image_base64: t
+                .background_image_base64
+                .clone()
+                .filter(|s| !s.trim().is_empty()),
+            nodes: t.nodes.clone(),
+            characters: t.characters.clone(),
+            endings: t.endings.clone(),
+            ordered_endings: None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct MetaInfo {
@@ -121,6 +223,45 @@ pub struct Character {
     pub role: String,
     pub background: String,
     pub avatar_path: Option<String>,
+    /// TTS voice id or label for a downstream narration pipeline; the server
+    /// only carries it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    /// Free-text description of the voice, e.g. "low, calm, slightly hoarse".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice_description: Option<String>,
+}
+
+impl Character {
+    /// 合并同名角色：保留 background 更丰富的一方，另一方只用来补齐空字段。
+    pub(crate) fn merge_duplicate(existing: &mut Character, other: Character) {
+        let mut other = other;
+        if other.background.trim().chars().count() > existing.background.trim().chars().count() {
+            std::mem::swap(existing, &mut other);
+        }
+        if existing.gender.trim().is_empty() {
+            existing.gender = other.gender;
+        }
+        if existing.age == 0 {
+            existing.age = other.age;
+        }
+        if existing.role.trim().is_empty() {
+            existing.role = other.role;
+        }
+        if existing
+            .avatar_path
+            .as_deref()
+            .unwrap_or("")
+            .trim()
+            .is_empty()
+        {
+            existing.avatar_path = other.avatar_path;
+        }
+        if existing.voice.is_none() {
+            existing.voice = other.voice;
+            existing.voice_description = other.voice_description;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -137,6 +278,36 @@ pub struct StoryNode {
     pub characters: Option<Vec<String>>,
     #[serde(default)]
     pub choices: Vec<Choice>,
+    /// Structural role, filled in by `classify_node_kinds` after graph repair.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_node_kind_lenient"
+    )]
+    pub kind: Option<NodeKind>,
+    /// Scene-specific background; clients fall back to the template's
+    /// `backgroundImageBase64` when absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_image_base64: Option<String>,
+    /// Short description of the scene the node background shows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub background_alt: Option<String>,
+}
+
+/// Structural role of a node in the story graph, for frontend styling.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    /// The `start` node.
+    Start,
+    /// Leads to two or more different nodes.
+    Branch,
+    /// Leads to exactly one node.
+    Linear,
+    /// Reached from two or more different nodes.
+    Convergence,
+    /// Ends the story: has an `endingKey`, or every choice leads to an ending.
+    Terminal,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -162,9 +333,49 @@ pub struct Ending {
     pub description: String,
 }
 
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyedEnding {
+    pub key: String,
+    #[serde(flatten)]
+    pub ending: Ending,
+}
+
+fn ending_type_rank(t: &str) -> u8 {
+    match t.trim() {
+        "good" => 0,
+        "neutral" => 1,
+        "bad" => 2,
+        _ => 3,
+    }
+}
+
+/// Endings ordered good → neutral → bad → other types, by key within a type,
+/// so the trio never shows up shuffled.
+pub(crate) fn ordered_endings(endings: &HashMap<String, Ending>) -> Vec<KeyedEnding> {
+    let mut list: Vec<KeyedEnding> = endings
+        .iter()
+        .map(|(key, ending)| KeyedEnding {
+            key: key.clone(),
+            ending: ending.clone(),
+        })
+        .collect();
+    list.sort_by(|a, b| {
+        (ending_type_rank(&a.ending.r#type), &a.key)
+            .cmp(&(ending_type_rank(&b.ending.r#type), &b.key))
+    });
+    list
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Provenance {
     pub created_by: String,
     pub created_at: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub updated_at: String,
+    /// Generated with cycles kept (`allowCycles`); later graph repairs on
+    /// edit, split or continuation keep its loops too.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub allow_cycles: bool,
 }